@@ -0,0 +1,83 @@
+use std::{
+    borrow::Cow,
+    io::{self, Cursor, Read},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ssh_control::{server, MuxResponse, Packet};
+
+fn encoded_failure(size: usize) -> Vec<u8> {
+    let reason = "x".repeat(size);
+    let msg: MuxResponse = server::Failure {
+        client_request_id: 1,
+        reason: Cow::Owned(reason),
+    }
+    .into();
+    let mut packet: Packet = Vec::new().into();
+    packet.set(&msg).unwrap();
+    let mut raw = Vec::new();
+    ssh_control::Wire::serialize(&packet, &mut raw).unwrap();
+    raw
+}
+
+fn recv_next(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Packet::recv_next");
+    for size in [1024, 64 * 1024, 1024 * 1024, 16 * 1024 * 1024] {
+        let raw = encoded_failure(size);
+        let mut packet: Packet = Vec::with_capacity(1024).into();
+        packet.set_max_size(raw.len());
+
+        group.throughput(Throughput::Bytes(raw.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &raw, |b, raw| {
+            b.iter(|| {
+                let mut reader = Cursor::new(&raw[..]);
+                let msg: MuxResponse = packet.recv_next(&mut reader).unwrap();
+                criterion::black_box(msg.get_request_id());
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Read path of `Packet::recv` before it stopped handing uninitialized memory to the reader,
+/// kept as the baseline for the current one
+#[allow(clippy::uninit_vec)]
+fn recv_uninit<R: Read>(buffer: &mut Vec<u8>, reader: &mut R) -> io::Result<()> {
+    let mut raw_size = [0u8; 4];
+    reader.read_exact(&mut raw_size[..])?;
+    let size = u32::from_be_bytes(raw_size) as usize;
+    buffer.clear();
+    buffer.reserve(size);
+    unsafe { buffer.set_len(size) };
+    reader.read_exact(&mut buffer[..])
+}
+
+fn recv_raw(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Packet::recv_raw");
+    for size in [1024, 64 * 1024, 1024 * 1024, 16 * 1024 * 1024] {
+        let raw = encoded_failure(size);
+        group.throughput(Throughput::Bytes(raw.len() as u64));
+
+        let mut packet: Packet = Vec::with_capacity(1024).into();
+        packet.set_max_size(raw.len());
+        group.bench_with_input(BenchmarkId::new("take", size), &raw, |b, raw| {
+            b.iter(|| {
+                let mut reader = Cursor::new(&raw[..]);
+                criterion::black_box(packet.recv_raw(&mut reader).unwrap().len());
+            })
+        });
+
+        let mut buffer = Vec::with_capacity(1024);
+        group.bench_with_input(BenchmarkId::new("uninit", size), &raw, |b, raw| {
+            b.iter(|| {
+                let mut reader = Cursor::new(&raw[..]);
+                recv_uninit(&mut buffer, &mut reader).unwrap();
+                criterion::black_box(buffer.len());
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, recv_next, recv_raw);
+criterion_main!(benches);
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nom::error::VerboseError;
+use ssh_control::{MuxMessage, Wire};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((_, msg)) = MuxMessage::parse::<VerboseError<&[u8]>>(data) {
+        let mut buffer = Vec::new();
+        let _ = msg.serialize(&mut buffer);
+    }
+});
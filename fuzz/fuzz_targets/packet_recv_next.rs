@@ -0,0 +1,24 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use ssh_control::{Hello, MuxMessage, MuxResponse, Packet};
+
+// The first byte selects which side of the connection is replayed, the rest is
+// the raw stream as read from the control socket: a hello followed by packets.
+fuzz_target!(|data: &[u8]| {
+    let Some((&side, stream)) = data.split_first() else {
+        return;
+    };
+    let mut reader = Cursor::new(stream);
+    let mut packet: Packet = Vec::new().into();
+    if packet.recv_next::<Hello, _>(&mut reader).is_err() {
+        return;
+    }
+    if side & 1 == 0 {
+        while packet.recv_next::<MuxMessage, _>(&mut reader).is_ok() {}
+    } else {
+        while packet.recv_next::<MuxResponse, _>(&mut reader).is_ok() {}
+    }
+});
@@ -0,0 +1,136 @@
+//! [`tokio_util::codec`] framing of mux packets.
+//!
+//! Each packet is a 4-byte big-endian length followed by its body. File descriptors passed
+//! alongside `NewSession` or `NewStdioFwd` requests are out of band and must be sent on the
+//! underlying socket directly.
+
+use std::{marker::PhantomData, mem::size_of};
+
+use bytes::{Buf, BufMut, BytesMut};
+use nom::error::VerboseError;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{Error, Hello, MuxMessage, MuxResponse, Result, Wire, DEFAULT_MAX_PACKET_SIZE};
+
+/// Codec decoding packets of type `T` and encoding any [`Wire`] value.
+///
+/// A client uses `MuxCodec<Hello>` for the handshake, then `MuxCodec<MuxResponse>`. A server
+/// decodes `Hello` then [`MuxMessage`]s. Use [`MuxCodec::switch`] with
+/// [`Framed::map_codec`](tokio_util::codec::Framed::map_codec) to move from one to the other.
+#[derive(Debug)]
+pub struct MuxCodec<T> {
+    max_size: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for MuxCodec<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for MuxCodec<T> {
+    fn clone(&self) -> Self {
+        Self {
+            max_size: self.max_size,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> MuxCodec<T> {
+    pub fn new() -> Self {
+        Self::with_max_size(DEFAULT_MAX_PACKET_SIZE)
+    }
+
+    /// Creates a codec rejecting packets whose body is over `max_size` bytes
+    pub fn with_max_size(max_size: usize) -> Self {
+        Self {
+            max_size,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Returns a codec with the same settings decoding `U` instead
+    pub fn switch<U>(self) -> MuxCodec<U> {
+        MuxCodec::with_max_size(self.max_size)
+    }
+
+    /// Returns the body of the first packet of `src`, or reserves room for the rest of it
+    fn next_body<'a>(&self, src: &'a mut BytesMut) -> Result<Option<&'a [u8]>> {
+        const HEADER: usize = size_of::<u32>();
+        let Some(raw_size) = src.get(..HEADER) else {
+            src.reserve(HEADER - src.len());
+            return Ok(None);
+        };
+        let size = u32::from_be_bytes(raw_size.try_into().unwrap()) as usize;
+        if size > self.max_size {
+            return Err(Error::PacketTooLarge {
+                size,
+                max: self.max_size,
+            });
+        }
+        if src.len() < HEADER + size {
+            src.reserve(HEADER + size - src.len());
+            return Ok(None);
+        }
+        Ok(Some(&src[HEADER..HEADER + size]))
+    }
+}
+
+macro_rules! impl_decoder {
+    ($type:ident) => {
+        impl Decoder for MuxCodec<$type<'static>> {
+            type Item = $type<'static>;
+            type Error = Error;
+
+            fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+                let Some(body) = self.next_body(src)? else {
+                    return Ok(None);
+                };
+                let (rest, obj) = $type::parse::<VerboseError<&[u8]>>(body)?;
+                if !rest.is_empty() {
+                    return Err(Error::TrailingData { len: rest.len() });
+                }
+                log::debug!("Received {obj:?}");
+                let obj = obj.into_owned();
+                let len = size_of::<u32>() + body.len();
+                src.advance(len);
+                Ok(Some(obj))
+            }
+        }
+    };
+}
+impl_decoder!(Hello);
+impl_decoder!(MuxMessage);
+impl_decoder!(MuxResponse);
+
+impl<'a, T, U> Encoder<U> for MuxCodec<T>
+where
+    U: Wire<'a>,
+{
+    type Error = Error;
+
+    fn encode(&mut self, item: U, dst: &mut BytesMut) -> Result<()> {
+        let start = dst.len();
+        dst.put_u32(0);
+        if let Err(e) = item.serialize(&mut BufMut::writer(&mut *dst)) {
+            dst.truncate(start);
+            return Err(e.into());
+        }
+        let size = dst.len() - start - size_of::<u32>();
+        let Ok(raw_size) = u32::try_from(size) else {
+            dst.truncate(start);
+            return Err(Error::PacketTooLarge {
+                size,
+                max: u32::MAX as usize,
+            });
+        };
+        dst[start..start + size_of::<u32>()].copy_from_slice(&raw_size.to_be_bytes()[..]);
+        Ok(())
+    }
+}
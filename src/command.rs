@@ -1,13 +1,37 @@
-use std::{collections::HashMap, fmt};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt::{self, Write as _},
+    io::{self, Read, Write},
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{mpsc::Receiver, Mutex},
+    thread,
+};
 
+mod env;
+pub use env::EnvFilter;
+mod events;
+#[cfg(feature = "tokio")]
+pub use events::SessionEventStream;
+pub use events::{SessionEvent, SessionEvents};
 mod pipe;
 pub use pipe::{Pipe, PipeRead, PipeWrite};
 
+use crate::{client, ControlSocket, Error, Result, SshControl};
+
+/// Escape sequence making the master close a TTY session, `~` being the escape character requested
+/// with every session
+const ESCAPE_CLOSE: &[u8] = b"\r~.";
+
 pub struct Child {
     pub stdin: Option<PipeWrite>,
     pub stdout: Option<PipeRead>,
     pub stderr: Option<PipeRead>,
     pub(super) session: u32,
+    pub(super) tty: bool,
+    /// Events routed by the [`SshControlHandle`](crate::handle::SshControlHandle) that opened
+    /// the session, until they are taken
+    pub(crate) events: Mutex<Option<Receiver<SessionEvent>>>,
 }
 
 impl fmt::Debug for Child {
@@ -17,16 +41,141 @@ impl fmt::Debug for Child {
             .field("stdout", &self.stdout)
             .field("stderr", &self.stderr)
             .field("session", &self.session)
+            .field("tty", &self.tty)
             .finish_non_exhaustive()
     }
 }
 
+/// Signalling the remote command
+///
+/// The mux protocol has no way to deliver a signal, so what can be done depends on the session:
+/// * with a TTY, [`signal`](Child::signal) types the control character the remote terminal turns
+///   into the signal, and [`kill`](Child::kill) makes the master close the session with the `~.`
+///   escape sequence, the remote command then receiving `SIGHUP` as its terminal hangs up. The
+///   master also closes the control connection without sending the exit status, so waiting for
+///   the child fails afterwards;
+/// * without a TTY, no signal can be sent and [`kill`](Child::kill) only closes the pipes: the
+///   remote command reads the end of its input, and the session closes once it next writes output.
+///   [`SshControl::wait_timeout`](crate::SshControl::wait_timeout) gives it a deadline to exit.
+impl Child {
+    /// Identifier of the session in the master
+    pub fn session_id(&self) -> u32 {
+        self.session
+    }
+
+    /// Events of the session, as `ctrl`, which opened it, reads them
+    ///
+    /// This takes the place of [`SshControl::wait`]: iteration blocks until the next event, and
+    /// ends once the command exits or the connection is lost.
+    pub fn events<'a, S>(&self, ctrl: &'a mut SshControl<S>) -> SessionEvents<'a, S>
+    where
+        S: ControlSocket,
+    {
+        SessionEvents::new(ctrl, self.session)
+    }
+
+    /// Whether the session has a TTY, which is needed to send signals
+    pub fn is_tty(&self) -> bool {
+        self.tty
+    }
+
+    /// Sends `signal` to the remote command, which must run in a TTY
+    ///
+    /// `SIGINT`, `SIGQUIT` and `SIGTSTP` are typed as the terminal's default control characters,
+    /// and `SIGHUP` is the same as [`kill`](Self::kill).
+    pub fn signal(&mut self, signal: i32) -> Result<()> {
+        let bytes: &[u8] = match signal {
+            libc::SIGINT => b"\x03",
+            libc::SIGQUIT => b"\x1c",
+            libc::SIGTSTP => b"\x1a",
+            libc::SIGHUP => ESCAPE_CLOSE,
+            signal => {
+                return Err(Error::CannotSignal(format!(
+                    "signal {signal} has no terminal equivalent"
+                )))
+            }
+        };
+        if !self.tty {
+            return Err(Error::CannotSignal("session has no TTY".into()));
+        }
+        let stdin = self
+            .stdin
+            .as_mut()
+            .ok_or_else(|| Error::CannotSignal("stdin is not piped".into()))?;
+        stdin.write_all(bytes)?;
+        stdin.flush()?;
+        Ok(())
+    }
+
+    /// Ends the session, with `SIGHUP` in a TTY, or else by closing the pipes
+    pub fn kill(&mut self) -> Result<()> {
+        if self.tty {
+            self.signal(libc::SIGHUP)?;
+        }
+        self.stdin = None;
+        self.stdout = None;
+        self.stderr = None;
+        Ok(())
+    }
+}
+
+impl Child {
+    /// Closes stdin and reads piped stdout and stderr to their end
+    pub(crate) fn collect_output(&mut self) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        drop(self.stdin.take());
+        let stderr = self.stderr.take().map(|mut stderr| {
+            thread::spawn(move || {
+                let mut buffer = Vec::new();
+                stderr.read_to_end(&mut buffer).map(|_| buffer)
+            })
+        });
+        let mut stdout = Vec::new();
+        if let Some(ref mut pipe) = self.stdout {
+            pipe.read_to_end(&mut stdout)?;
+        }
+        let stderr = match stderr {
+            Some(reader) => reader.join().expect("stderr reader panicked")?,
+            None => Vec::new(),
+        };
+        Ok((stdout, stderr))
+    }
+}
+
+/// Quotes `s` so that a POSIX shell reads it as a single word
+pub fn quote(s: &str) -> Cow<'_, str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "%+,-./:=@_".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        Cow::Borrowed(s)
+    } else {
+        Cow::Owned(format!("'{}'", s.replace('\'', "'\\''")))
+    }
+}
+
+/// Output of a finished remote command, mimicks [`std::process::Output`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// Exit value reported by the master
+    pub status: u32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl Output {
+    pub fn success(&self) -> bool {
+        self.status == 0
+    }
+}
+
 /// SSH Command struct, mimicks [`std::process::Command`] interface
 #[derive(Debug)]
 pub struct SshCommand {
     pub(super) shell_command: String,
-    pub(super) environment: HashMap<String, String>,
+    pub(super) current_dir: Option<String>,
+    pub(super) environment: BTreeMap<String, String>,
+    pub(super) env_clear: bool,
+    pub(super) env_filter: Option<EnvFilter>,
     pub(super) want_tty: bool,
+    pub(super) subsystem: bool,
     pub(super) want_x11_forwarding: bool,
     pub(super) stdin: Option<Pipe>,
     pub(super) stdout: Option<Pipe>,
@@ -37,8 +186,12 @@ impl SshCommand {
     pub fn new(command: impl Into<String>) -> Self {
         Self {
             shell_command: command.into(),
-            environment: HashMap::default(),
+            current_dir: None,
+            environment: BTreeMap::default(),
+            env_clear: false,
+            env_filter: None,
             want_tty: false,
+            subsystem: false,
             want_x11_forwarding: false,
             stdin: None,
             stdout: None,
@@ -46,6 +199,64 @@ impl SshCommand {
         }
     }
 
+    /// Allocates a TTY for the command
+    pub fn want_tty(&mut self, want_tty: bool) -> &mut Self {
+        self.want_tty = want_tty;
+        self
+    }
+
+    /// Requests the subsystem named by the command, such as `sftp`, instead of running it
+    ///
+    /// The directory change and environment reset do not apply to subsystems.
+    pub fn subsystem(&mut self, subsystem: bool) -> &mut Self {
+        self.subsystem = subsystem;
+        self
+    }
+
+    /// Runs `program`, with the arguments given to [`arg`](Self::arg) and [`args`](Self::args)
+    ///
+    /// Unlike [`new`](Self::new), which takes a shell command line as is, the program and its
+    /// arguments are quoted so that the remote shell passes them unchanged.
+    pub fn program(program: impl AsRef<str>) -> Self {
+        Self::new(quote(program.as_ref()))
+    }
+
+    /// Appends a quoted argument to the command line
+    pub fn arg(&mut self, arg: impl AsRef<str>) -> &mut Self {
+        self.shell_command.push(' ');
+        self.shell_command.push_str(&quote(arg.as_ref()));
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Changes to the remote directory `dir` before running the command, which does not run if
+    /// that fails
+    pub fn current_dir(&mut self, dir: impl Into<String>) -> &mut Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Runs the command with an empty environment, except for variables added afterwards
+    ///
+    /// The remote shell is always started with the user's environment, so the command is run
+    /// through `env -i`, with the variables on its command line instead of being sent to the
+    /// master.
+    pub fn env_clear(&mut self) -> &mut Self {
+        self.environment.clear();
+        self.env_clear = true;
+        self
+    }
+
     pub fn env(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
         self.environment.insert(key.into(), value.into());
         self
@@ -58,6 +269,28 @@ impl SshCommand {
         self
     }
 
+    /// Copies the local variables whose names match `patterns`, given as to `SendEnv`
+    ///
+    /// For instance, `LANG LC_*` copies the variables OpenSSH sends by default on most systems.
+    pub fn copy_env_filtered(&mut self, patterns: &str) -> Result<&mut Self> {
+        let filter: EnvFilter = patterns.parse()?;
+        for (key, value) in std::env::vars_os() {
+            if let (Some(key), Some(value)) = (key.to_str(), value.to_str()) {
+                if filter.matches(key) {
+                    self.env(key, value);
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    /// Only sends the variables `filter` lets through, such as those the master's `AcceptEnv`
+    /// accepts, instead of letting the master drop the others
+    pub fn env_filter(&mut self, filter: EnvFilter) -> &mut Self {
+        self.env_filter = Some(filter);
+        self
+    }
+
     pub fn env_remove(&mut self, key: impl AsRef<str>) -> &mut Self {
         self.environment.remove(key.as_ref());
         self
@@ -77,4 +310,109 @@ impl SshCommand {
         self.stderr = Some(p);
         self
     }
+
+    /// Variables to send, sorted by name, after checking their names and values
+    fn checked_environment(&self) -> Result<Vec<String>> {
+        self.environment
+            .iter()
+            .filter(|(key, _)| self.env_filter.as_ref().is_none_or(|f| f.matches(key)))
+            .map(|(key, value)| {
+                env::validate(key, value)?;
+                Ok(format!("{key}={value}"))
+            })
+            .collect()
+    }
+
+    /// Command line and environment sent to the master, with the directory change and
+    /// environment reset
+    pub(super) fn request_parts(&self) -> Result<(String, Vec<String>)> {
+        let environment = self.checked_environment()?;
+        if self.subsystem {
+            return Ok((self.shell_command.clone(), environment));
+        }
+        let mut line = String::new();
+        if let Some(ref dir) = self.current_dir {
+            let _ = write!(line, "cd -- {} || exit; ", quote(dir));
+        }
+        if self.env_clear {
+            // Without an environment, variables are set on the command line instead
+            line.push_str("exec env -i");
+            for var in environment {
+                let _ = write!(line, " {}", quote(&var));
+            }
+            let _ = write!(line, " sh -c {}", quote(&self.shell_command));
+            Ok((line, Vec::new()))
+        } else {
+            line.push_str(&self.shell_command);
+            Ok((line, environment))
+        }
+    }
+}
+
+/// Session about to be opened for a command
+pub(crate) struct SessionSetup {
+    pub(crate) request: client::NewSession<'static>,
+    /// Pipe ends passed to the master, closed once passed
+    remote: (Option<PipeRead>, Option<PipeWrite>, Option<PipeWrite>),
+    /// Pipe ends kept by the child
+    local: (Option<PipeWrite>, Option<PipeRead>, Option<PipeRead>),
+}
+
+impl SessionSetup {
+    pub(crate) fn new(command: SshCommand) -> Result<Self> {
+        let (command_line, environment) = command.request_parts()?;
+        let request = client::NewSession {
+            request_id: 0,
+            want_tty: command.want_tty,
+            want_x11_forwarding: command.want_x11_forwarding,
+            want_agent: false,
+            subsystem: command.subsystem,
+            escape_char: b'~' as u32,
+            terminal_type: std::env::var("TERM")
+                .ok()
+                .unwrap_or_else(|| "xterm".into())
+                .into(),
+            command: command_line.into(),
+            environment: environment.into_iter().map(Into::into).collect(),
+        };
+        let split = |p: Option<Pipe>| p.map(|p| (p.read, p.write)).unzip();
+        let (stdin_remote, stdin_local) = split(command.stdin);
+        let (stdout_local, stdout_remote) = split(command.stdout);
+        let (stderr_local, stderr_remote) = split(command.stderr);
+        Ok(Self {
+            request,
+            remote: (stdin_remote, stdout_remote, stderr_remote),
+            local: (stdin_local, stdout_local, stderr_local),
+        })
+    }
+
+    /// Descriptors to pass to the master, the standard streams standing in for missing pipes
+    pub(crate) fn fds(&self) -> [RawFd; 3] {
+        [
+            self.remote
+                .0
+                .as_ref()
+                .map_or(libc::STDIN_FILENO, AsRawFd::as_raw_fd),
+            self.remote
+                .1
+                .as_ref()
+                .map_or(libc::STDOUT_FILENO, AsRawFd::as_raw_fd),
+            self.remote
+                .2
+                .as_ref()
+                .map_or(libc::STDERR_FILENO, AsRawFd::as_raw_fd),
+        ]
+    }
+
+    pub(crate) fn into_child(self, session: u32) -> Child {
+        let (stdin, stdout, stderr) = self.local;
+        Child {
+            stdin,
+            stdout,
+            stderr,
+            session,
+            tty: self.request.want_tty,
+            events: Mutex::default(),
+        }
+    }
 }
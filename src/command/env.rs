@@ -0,0 +1,106 @@
+use std::str::FromStr;
+
+use crate::{Error, Result};
+
+/// Whether `name` matches `pattern`, where `*` matches any characters and `?` a single one, like
+/// OpenSSH's `match_pattern`
+fn match_pattern(name: &str, pattern: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let (mut n, mut p) = (0, 0);
+    // Position after the last `*`, and the name position it currently matches up to
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Environment variable names to send with a session
+///
+/// Names are matched against patterns as in `SendEnv` and `AcceptEnv`: a variable is sent if its
+/// name matches one of the allowed patterns, and none of the denied ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl EnvFilter {
+    /// Filter letting no variable through
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(&mut self, pattern: impl Into<String>) -> &mut Self {
+        self.allow.push(pattern.into());
+        self
+    }
+
+    pub fn deny(&mut self, pattern: impl Into<String>) -> &mut Self {
+        self.deny.push(pattern.into());
+        self
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.allow.iter().any(|p| match_pattern(name, p))
+            && !self.deny.iter().any(|p| match_pattern(name, p))
+    }
+}
+
+/// Parses patterns separated by whitespace, as given to `SendEnv` or `AcceptEnv`
+///
+/// As with `SendEnv`, a pattern starting with `-` removes the previous patterns it matches, so
+/// `LC_* -LC_*` lets nothing through.
+impl FromStr for EnvFilter {
+    type Err = Error;
+
+    fn from_str(patterns: &str) -> Result<Self> {
+        let mut filter = Self::new();
+        for pattern in patterns.split_whitespace() {
+            match pattern.strip_prefix('-') {
+                Some("") => {
+                    return Err(Error::InvalidEnvironment(format!(
+                        "empty pattern in {patterns:?}"
+                    )))
+                }
+                Some(removed) => filter.allow.retain(|p| !match_pattern(p, removed)),
+                None => filter.allow.push(pattern.to_owned()),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// Checks that `key=value` is read back as the same variable by the remote side
+pub(super) fn validate(key: &str, value: &str) -> Result<()> {
+    let reason = if key.is_empty() {
+        "empty name"
+    } else if key.contains('=') {
+        "name contains '='"
+    } else if key.contains('\0') {
+        "name contains a NUL byte"
+    } else if value.contains('\0') {
+        "value contains a NUL byte"
+    } else {
+        return Ok(());
+    };
+    Err(Error::InvalidEnvironment(format!("{key:?}: {reason}")))
+}
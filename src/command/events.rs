@@ -0,0 +1,177 @@
+//! Lifecycle of a session, as reported by the master.
+
+use std::fmt;
+
+use crate::{server, ControlSocket, Error, MuxResponse, Result, SshControl};
+
+#[derive(Debug)]
+pub enum SessionEvent {
+    /// Session was opened, always the first event
+    Opened { session_id: u32 },
+    /// Session runs without the TTY it asked for
+    TtyAllocFailed,
+    /// Remote command exited with this value, always the last event unless the connection is lost
+    Exited(u32),
+    /// Connection closed before the command exited, or the master sent something unexpected
+    ConnectionLost(Error),
+}
+
+impl SessionEvent {
+    /// Whether no event can follow this one
+    pub fn is_last(&self) -> bool {
+        matches!(self, Self::Exited(_) | Self::ConnectionLost(_))
+    }
+
+    /// Event for `session_id` carried by a response read off the control connection
+    pub(crate) fn from_response(response: Result<MuxResponse<'_>>, session_id: u32) -> Self {
+        let event = match response {
+            Ok(MuxResponse::TtyAllocFail(server::TtyAllocFail { session_id: id }))
+                if id == session_id =>
+            {
+                return Self::TtyAllocFailed
+            }
+            Ok(MuxResponse::ExitMessage(server::ExitMessage {
+                session_id: id,
+                exit_value,
+            })) if id == session_id => return Self::Exited(exit_value),
+            Ok(response) => Error::InvalidPacket {
+                description: format!("Unexpected {response:?} for session {session_id}").into(),
+            },
+            Err(e) => e,
+        };
+        Self::ConnectionLost(event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Opened,
+    Running,
+    Done,
+}
+
+/// Events of a session, returned by [`Child::events`](super::Child::events)
+pub struct SessionEvents<'a, S>
+where
+    S: ControlSocket,
+{
+    ctrl: &'a mut SshControl<S>,
+    session_id: u32,
+    state: State,
+}
+
+impl<'a, S> SessionEvents<'a, S>
+where
+    S: ControlSocket,
+{
+    pub(super) fn new(ctrl: &'a mut SshControl<S>, session_id: u32) -> Self {
+        Self {
+            ctrl,
+            session_id,
+            state: State::Opened,
+        }
+    }
+}
+
+impl<S> fmt::Debug for SessionEvents<'_, S>
+where
+    S: ControlSocket,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionEvents")
+            .field("session_id", &self.session_id)
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Iterator for SessionEvents<'_, S>
+where
+    S: ControlSocket,
+{
+    type Item = SessionEvent;
+
+    fn next(&mut self) -> Option<SessionEvent> {
+        let event = match self.state {
+            State::Opened => SessionEvent::Opened {
+                session_id: self.session_id,
+            },
+            State::Running => SessionEvent::from_response(self.ctrl.recv_helper(), self.session_id),
+            State::Done => return None,
+        };
+        self.state = if event.is_last() {
+            State::Done
+        } else {
+            State::Running
+        };
+        Some(event)
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub use stream::SessionEventStream;
+
+#[cfg(feature = "tokio")]
+mod stream {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use futures_core::Stream;
+
+    use super::{SessionEvent, State};
+    use crate::{Error, MuxResponse, Result};
+
+    /// Events of a session, from the responses decoded by a
+    /// [`MuxCodec`](crate::codec::MuxCodec)
+    #[derive(Debug)]
+    pub struct SessionEventStream<R> {
+        responses: R,
+        session_id: u32,
+        state: State,
+    }
+
+    impl<R> SessionEventStream<R> {
+        /// Events of the session opened as `session_id`, whose later responses are `responses`
+        pub fn new(responses: R, session_id: u32) -> Self {
+            Self {
+                responses,
+                session_id,
+                state: State::Opened,
+            }
+        }
+    }
+
+    impl<R> Stream for SessionEventStream<R>
+    where
+        R: Stream<Item = Result<MuxResponse<'static>>> + Unpin,
+    {
+        type Item = SessionEvent;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<SessionEvent>> {
+            let event = match self.state {
+                State::Opened => SessionEvent::Opened {
+                    session_id: self.session_id,
+                },
+                State::Running => {
+                    let response = match Pin::new(&mut self.responses).poll_next(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(Some(response)) => response,
+                        Poll::Ready(None) => {
+                            Err(Error::IO(std::io::ErrorKind::UnexpectedEof.into()))
+                        }
+                    };
+                    SessionEvent::from_response(response, self.session_id)
+                }
+                State::Done => return Poll::Ready(None),
+            };
+            self.state = if event.is_last() {
+                State::Done
+            } else {
+                State::Running
+            };
+            Poll::Ready(Some(event))
+        }
+    }
+}
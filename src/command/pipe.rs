@@ -26,10 +26,17 @@ impl Pipe {
         let ret = unsafe { libc::pipe(fds.as_mut_ptr().cast()) };
         if ret == 0 {
             let fds = unsafe { fds.assume_init() };
-            Ok(Self {
+            let pipe = Self {
                 read: PipeRead(fds[0]),
                 write: PipeWrite(fds[1]),
-            })
+            };
+            // Keep the pipe out of processes spawned locally, which would delay EOF
+            for fd in fds {
+                if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(pipe)
         } else {
             Err(io::Error::last_os_error())
         }
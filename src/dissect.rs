@@ -0,0 +1,335 @@
+//! Field by field breakdown of captured mux traffic.
+//!
+//! Unlike the [`Wire`](crate::Wire) parsers, the dissector never gives up on a packet: fields are
+//! decoded until the data runs out or stops making sense, and what could not be decoded is
+//! reported along with the offset where it happened.
+
+use std::{borrow::Cow, fmt};
+
+use crate::{
+    error::RawBytes,
+    protocol::{client, server, MUX_HELLO},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    U32,
+    Bool,
+    String,
+    ForwardingType,
+    Port,
+    /// Strings up to the end of the packet
+    Strings,
+    /// Name and value pairs up to the end of the packet
+    Extensions,
+}
+
+type Schema = &'static [(&'static str, Kind)];
+
+const FORWARD: Schema = &[
+    ("request_id", Kind::U32),
+    ("forwarding_type", Kind::ForwardingType),
+    ("listen_host", Kind::String),
+    ("listen_port", Kind::Port),
+    ("connect_host", Kind::String),
+    ("connect_port", Kind::Port),
+];
+
+fn message(r#type: u32) -> Option<(&'static str, Schema)> {
+    use Kind::*;
+
+    Some(match r#type {
+        MUX_HELLO => (
+            "MUX_MSG_HELLO",
+            &[("version", U32), ("extensions", Extensions)],
+        ),
+        client::NEW_SESSION => (
+            "MUX_C_NEW_SESSION",
+            &[
+                ("request_id", U32),
+                ("reserved", String),
+                ("want_tty", Bool),
+                ("want_x11_forwarding", Bool),
+                ("want_agent", Bool),
+                ("subsystem", Bool),
+                ("escape_char", U32),
+                ("terminal_type", String),
+                ("command", String),
+                ("environment", Strings),
+            ],
+        ),
+        client::ALIVE_CHECK => ("MUX_C_ALIVE_CHECK", &[("request_id", U32)]),
+        client::TERMINATE => ("MUX_C_TERMINATE", &[("request_id", U32)]),
+        client::OPEN_FWD => ("MUX_C_OPEN_FWD", FORWARD),
+        client::CLOSE_FWD => ("MUX_C_CLOSE_FWD", FORWARD),
+        client::NEW_STDIO_FWD => (
+            "MUX_C_NEW_STDIO_FWD",
+            &[
+                ("request_id", U32),
+                ("reserved", String),
+                ("connect_host", String),
+                ("connect_port", Port),
+            ],
+        ),
+        client::STOP_LISTENING => ("MUX_C_STOP_LISTENING", &[("request_id", U32)]),
+        server::OK => ("MUX_S_OK", &[("client_request_id", U32)]),
+        server::PERMISSION_DENIED => (
+            "MUX_S_PERMISSION_DENIED",
+            &[("client_request_id", U32), ("reason", String)],
+        ),
+        server::FAILURE => (
+            "MUX_S_FAILURE",
+            &[("client_request_id", U32), ("reason", String)],
+        ),
+        server::EXIT_MESSAGE => (
+            "MUX_S_EXIT_MESSAGE",
+            &[("session_id", U32), ("exit_value", U32)],
+        ),
+        server::ALIVE => (
+            "MUX_S_ALIVE",
+            &[("client_request_id", U32), ("server_pid", U32)],
+        ),
+        server::SESSION_OPENED => (
+            "MUX_S_SESSION_OPENED",
+            &[("client_request_id", U32), ("session_id", U32)],
+        ),
+        server::REMOTE_PORT => (
+            "MUX_S_REMOTE_PORT",
+            &[
+                ("client_request_id", U32),
+                ("allocated_remote_listen_port", U32),
+            ],
+        ),
+        server::TTY_ALLOC_FAIL => ("MUX_S_TTY_ALLOC_FAIL", &[("session_id", U32)]),
+        _ => return None,
+    })
+}
+
+/// A decoded field, `offset` being relative to the start of the dissected data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub offset: usize,
+    pub len: usize,
+    pub name: Cow<'static, str>,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketDissection {
+    pub offset: usize,
+    /// Length announced by the packet header, if there was enough data for it
+    pub length: Option<u32>,
+    /// Protocol name of the message type, if known
+    pub name: Option<&'static str>,
+    pub fields: Vec<Field>,
+    /// Why decoding stopped early, if it did
+    pub error: Option<String>,
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    /// Offset of `data[0]` in the dissected buffer
+    base: usize,
+    pos: usize,
+    fields: Vec<Field>,
+}
+
+impl<'a> Cursor<'a> {
+    fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    fn take(&mut self, name: &str, len: usize) -> Result<&'a [u8], String> {
+        if self.remaining() < len {
+            return Err(format!(
+                "{name}: needs {len} bytes, only {} left",
+                self.remaining()
+            ));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self, name: &str) -> Result<u32, String> {
+        let bytes = self.take(name, 4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn push(&mut self, start: usize, name: impl Into<Cow<'static, str>>, value: String) {
+        self.fields.push(Field {
+            offset: self.base + start,
+            len: self.pos - start,
+            name: name.into(),
+            value,
+        });
+    }
+
+    fn field(&mut self, name: impl Into<Cow<'static, str>>, kind: Kind) -> Result<(), String> {
+        let name = name.into();
+        let start = self.pos;
+        let value = match kind {
+            Kind::U32 => {
+                let v = self.u32(&name)?;
+                format!("{v} (0x{v:08x})")
+            }
+            Kind::Bool => match self.u32(&name)? {
+                0 => "false".to_owned(),
+                1 => "true".to_owned(),
+                v => format!("true (non canonical value {v})"),
+            },
+            Kind::String => {
+                let len = self.u32(&name)?;
+                let bytes = self.take(&name, len as usize)?;
+                format!("{len} bytes \"{}\"", RawBytes(bytes))
+            }
+            Kind::ForwardingType => match self.u32(&name)? {
+                client::FWD_LOCAL => "local".to_owned(),
+                client::FWD_REMOTE => "remote".to_owned(),
+                client::FWD_DYNAMIC => "dynamic".to_owned(),
+                v => {
+                    self.push(start, name, format!("{v} (unknown)"));
+                    return Err(format!("unknown forwarding type {v}"));
+                }
+            },
+            Kind::Port => match self.u32(&name)? {
+                client::LISTEN_TYPE_UNIX => "unix socket".to_owned(),
+                v if v <= u16::MAX as u32 => v.to_string(),
+                v => {
+                    self.push(start, name, format!("{v} (out of range)"));
+                    return Err(format!("port {v} out of range"));
+                }
+            },
+            Kind::Strings => {
+                let mut i = 0;
+                while self.remaining() > 0 {
+                    self.field(format!("{name}[{i}]"), Kind::String)?;
+                    i += 1;
+                }
+                return Ok(());
+            }
+            Kind::Extensions => {
+                let mut i = 0;
+                while self.remaining() > 0 {
+                    self.field(format!("{name}[{i}].name"), Kind::String)?;
+                    self.field(format!("{name}[{i}].value"), Kind::String)?;
+                    i += 1;
+                }
+                return Ok(());
+            }
+        };
+        self.push(start, name, value);
+        Ok(())
+    }
+}
+
+fn dissect_body(body: &[u8], base: usize, packet: &mut PacketDissection) {
+    let mut cursor = Cursor {
+        data: body,
+        base,
+        pos: 0,
+        fields: Vec::new(),
+    };
+    let result = (|| {
+        let start = cursor.pos;
+        let r#type = cursor.u32("type")?;
+        let Some((name, schema)) = message(r#type) else {
+            cursor.push(start, "type", format!("0x{type:08x} (unknown)"));
+            return Err(format!("unknown message type 0x{type:08x}"));
+        };
+        packet.name = Some(name);
+        cursor.push(start, "type", format!("0x{type:08x} {name}"));
+        for (field, kind) in schema {
+            cursor.field(*field, *kind)?;
+        }
+        match cursor.remaining() {
+            0 => Ok(()),
+            n => Err(format!(
+                "{n} trailing bytes: {:?}",
+                RawBytes(&body[cursor.pos..])
+            )),
+        }
+    })();
+    packet.fields.append(&mut cursor.fields);
+    packet.error = result.err();
+}
+
+/// Splits `data` into packets and decodes their fields
+pub fn dissect(data: &[u8]) -> Vec<PacketDissection> {
+    let mut packets = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let rest = &data[offset..];
+        let mut packet = PacketDissection {
+            offset,
+            length: None,
+            name: None,
+            fields: Vec::new(),
+            error: None,
+        };
+        let Some(raw_length) = rest.get(..4) else {
+            packet.error = Some(format!("truncated length: {:?}", RawBytes(rest)));
+            packets.push(packet);
+            break;
+        };
+        let length = u32::from_be_bytes(raw_length.try_into().unwrap());
+        packet.length = Some(length);
+        packet.fields.push(Field {
+            offset,
+            len: 4,
+            name: "length".into(),
+            value: length.to_string(),
+        });
+        let available = rest.len() - 4;
+        let body = &rest[4..4 + available.min(length as usize)];
+        dissect_body(body, offset + 4, &mut packet);
+        if available < length as usize {
+            let truncated = format!("truncated packet: {available} of {length} bytes");
+            packet.error = Some(match packet.error {
+                Some(e) => format!("{truncated}, {e}"),
+                None => truncated,
+            });
+        }
+        packets.push(packet);
+        offset += 4 + body.len();
+    }
+    packets
+}
+
+/// Decodes hexadecimal text, ignoring whitespace and an optional `0x` prefix
+pub fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = text
+        .trim()
+        .trim_start_matches("0x")
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+impl fmt::Display for PacketDissection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "packet at 0x{:04x}", self.offset)?;
+        if let Some(name) = self.name {
+            write!(f, ": {name}")?;
+        }
+        writeln!(f)?;
+        for field in &self.fields {
+            writeln!(
+                f,
+                "  0x{:04x} {:>4}  {:<32} {}",
+                field.offset, field.len, field.name, field.value
+            )?;
+        }
+        if let Some(ref error) = self.error {
+            writeln!(f, "  error: {error}")?;
+        }
+        Ok(())
+    }
+}
@@ -78,6 +78,12 @@ pub enum Error {
     /// Unsupported version
     UnsupportedVersion(u32),
 
+    /// Packet length exceeds the allowed maximum
+    PacketTooLarge { size: usize, max: usize },
+
+    /// Data left over after parsing a packet
+    TrailingData { len: usize },
+
     /// Invalid Packet
     InvalidPacket { description: Cow<'static, str> },
 
@@ -87,6 +93,10 @@ pub enum Error {
         received: Option<u32>,
     },
 
+    /// Connection whose stream was left in the middle of a packet, such as by a timeout, and
+    /// cannot be used anymore
+    Desynchronized,
+
     /// Permission denied
     PermissionDenied(String),
 
@@ -95,6 +105,40 @@ pub enum Error {
 
     /// TTY allocation failed,
     TtyAllocFailed,
+
+    /// Invalid configuration
+    InvalidConfig(String),
+
+    /// Forwarding address that cannot be sent to the master
+    InvalidEndpoint(String),
+
+    /// Environment variable that cannot be sent
+    InvalidEnvironment(String),
+
+    /// Signal that cannot be delivered to a remote command
+    CannotSignal(String),
+
+    /// Value used with a connection it does not belong to, or used twice
+    InvalidUsage(String),
+
+    /// Remote command exited with a non zero status
+    CommandFailed { status: u32, stderr: String },
+
+    /// Transferred data does not match what was sent
+    VerificationFailed(String),
+
+    /// SFTP request failed
+    Sftp {
+        code: crate::sftp::StatusCode,
+        message: String,
+    },
+
+    /// Remote file system operation failed
+    RemoteFs {
+        kind: io::ErrorKind,
+        path: String,
+        message: String,
+    },
 }
 pub type Result<T> = ::std::result::Result<T, Error>;
 
@@ -122,6 +166,15 @@ impl fmt::Display for Error {
             Self::UnsupportedVersion(version) => {
                 write!(f, "Remote uses incompatible version {version}")
             }
+            Self::PacketTooLarge { size, max } => {
+                write!(
+                    f,
+                    "Packet of {size} bytes exceeds maximum size of {max} bytes"
+                )
+            }
+            Self::TrailingData { len } => {
+                write!(f, "Packet has {len} trailing bytes after parsing")
+            }
             Self::InvalidPacket { description } => {
                 write!(f, "Received an invalid packet: {description}")
             }
@@ -131,6 +184,9 @@ impl fmt::Display for Error {
                 (None, Some(rec)) => write!(f, "Expect no ID, received 0x{rec:x}"),
                 _ => unreachable!(),
             },
+            Self::Desynchronized => {
+                f.write_str("Connection left in the middle of a packet, cannot be used anymore")
+            }
             Self::PermissionDenied(ref reason) => {
                 write!(f, "Remote operation not permietted: {reason}")
             }
@@ -138,6 +194,32 @@ impl fmt::Display for Error {
                 write!(f, "Remote operation failed: {reason}")
             }
             Self::TtyAllocFailed => f.write_str("Remote TTY allocation failed"),
+            Self::InvalidConfig(ref reason) => write!(f, "Invalid configuration: {reason}"),
+            Self::InvalidEndpoint(ref reason) => write!(f, "Invalid forwarding endpoint: {reason}"),
+            Self::InvalidEnvironment(ref reason) => {
+                write!(f, "Invalid environment variable: {reason}")
+            }
+            Self::CannotSignal(ref reason) => write!(f, "Cannot signal remote command: {reason}"),
+            Self::InvalidUsage(ref reason) => write!(f, "Invalid usage: {reason}"),
+            Self::CommandFailed { status, ref stderr } => match stderr.trim() {
+                "" => write!(f, "Remote command exited with status {status}"),
+                stderr => write!(f, "Remote command exited with status {status}: {stderr}"),
+            },
+            Self::VerificationFailed(ref reason) => write!(f, "Verification failed: {reason}"),
+            Self::Sftp { code, ref message } if message.is_empty() => {
+                write!(f, "SFTP request failed: {code}")
+            }
+            Self::Sftp { code, ref message } => {
+                write!(f, "SFTP request failed: {code} ({message})")
+            }
+            Self::RemoteFs {
+                kind,
+                ref path,
+                ref message,
+            } => match message.as_str() {
+                "" => write!(f, "Remote operation on {path} failed: {kind}"),
+                message => write!(f, "Remote operation on {path} failed: {message}"),
+            },
         }
     }
 }
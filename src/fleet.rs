@@ -0,0 +1,138 @@
+//! Running one command through many masters at once.
+//!
+//! Each [`Target`] is a host with its own master. Commands are started on at most
+//! [`Fleet::concurrency`] targets at a time, each from a dedicated connection and thread.
+
+use std::{
+    path::PathBuf,
+    process,
+    sync::{mpsc, Mutex},
+    thread,
+};
+
+use crate::{
+    command::{Output, Pipe, SshCommand},
+    Error, Result, SshControl,
+};
+
+pub const DEFAULT_CONCURRENCY: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    /// Name used to report results, usually the host name
+    pub name: String,
+    pub control_path: PathBuf,
+}
+
+impl Target {
+    pub fn new(name: impl Into<String>, control_path: impl Into<PathBuf>) -> Self {
+        Self {
+            name: name.into(),
+            control_path: control_path.into(),
+        }
+    }
+
+    /// Target for `host`, using the `ControlPath` `ssh` would use to reach it
+    pub fn resolve(host: &str) -> Result<Self> {
+        let output = process::Command::new("ssh")
+            .args(["-G", "--", host])
+            .stdin(process::Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::InvalidConfig(format!(
+                "ssh -G {host} failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let config = String::from_utf8_lossy(&output.stdout);
+        let control_path = config
+            .lines()
+            .find_map(|line| line.strip_prefix("controlpath "))
+            .filter(|path| *path != "none")
+            .ok_or_else(|| Error::InvalidConfig(format!("No ControlPath configured for {host}")))?;
+        Ok(Self::new(host, control_path))
+    }
+}
+
+/// Result of a command on one target
+#[derive(Debug)]
+pub struct HostOutput {
+    pub target: Target,
+    pub output: Result<Output>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fleet {
+    targets: Vec<Target>,
+    concurrency: usize,
+}
+
+impl Fleet {
+    pub fn new(targets: impl IntoIterator<Item = Target>) -> Self {
+        Self {
+            targets: targets.into_iter().collect(),
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+
+    pub fn targets(&self) -> &[Target] {
+        &self.targets
+    }
+
+    /// Sets the maximum number of commands running at once, at least 1
+    pub fn concurrency(&mut self, concurrency: usize) -> &mut Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Runs `command` on every target, returning the results in the targets order
+    pub fn run(&self, command: &str) -> Vec<HostOutput> {
+        let mut results: Vec<Option<HostOutput>> = self.targets.iter().map(|_| None).collect();
+        self.run_indexed(command, |i, result| results[i] = Some(result));
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Runs `command` on every target, calling `f` with each result as soon as it is available
+    pub fn run_each(&self, command: &str, mut f: impl FnMut(HostOutput)) {
+        self.run_indexed(command, |_, result| f(result));
+    }
+
+    fn run_indexed(&self, command: &str, mut f: impl FnMut(usize, HostOutput)) {
+        let pending = Mutex::new(self.targets.iter().enumerate());
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            for _ in 0..self.concurrency.min(self.targets.len()) {
+                let sender = sender.clone();
+                let pending = &pending;
+                scope.spawn(move || loop {
+                    let Some((i, target)) = pending.lock().unwrap().next() else {
+                        break;
+                    };
+                    log::debug!("Running {command:?} on {}", target.name);
+                    let output = run_one(target, command);
+                    let result = HostOutput {
+                        target: target.clone(),
+                        output,
+                    };
+                    if sender.send((i, result)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(sender);
+            for (i, result) in receiver {
+                f(i, result);
+            }
+        });
+    }
+}
+
+fn run_one(target: &Target, command: &str) -> Result<Output> {
+    let mut ctrl = SshControl::new(&target.control_path)?;
+    let mut cmd = SshCommand::new(command);
+    cmd.stdin(Pipe::dev_null()?);
+    cmd.stdout(Pipe::new()?);
+    cmd.stderr(Pipe::new()?);
+    let child = ctrl.new_session(cmd)?;
+    ctrl.wait_with_output(child)
+}
@@ -0,0 +1,332 @@
+//! Port forwardings opened through the master
+
+use std::{
+    borrow::Cow,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+mod spec;
+
+use crate::{
+    client::{CloseFwd, ForwardingType, OpenFwd, Port},
+    server, Error, MuxResponse, Result,
+};
+
+/// Address a forward listens on or connects to
+///
+/// The mux protocol sends Unix socket paths in the host field, with [`Port::Unix`] as port.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// TCP address, an empty `host` meaning the default bind address when listening
+    Tcp {
+        host: String,
+        port: u16,
+    },
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    pub fn tcp(host: impl Into<String>, port: u16) -> Self {
+        Self::Tcp {
+            host: host.into(),
+            port,
+        }
+    }
+
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Self::Unix(path.into())
+    }
+
+    /// Builds an endpoint from the host and port fields of a message
+    pub fn from_wire(host: &str, port: Port) -> Result<Self> {
+        check_host(host)?;
+        match port {
+            Port::Inet(port) => Ok(Self::tcp(host, port)),
+            Port::Unix if host.is_empty() => Err(Error::InvalidEndpoint(
+                "Unix socket forward without a path".into(),
+            )),
+            Port::Unix => Ok(Self::unix(host)),
+        }
+    }
+
+    /// Host and port fields of a message for this endpoint
+    pub fn to_wire(&self) -> Result<(Cow<'_, str>, Port)> {
+        match self {
+            Self::Tcp { host, port } => {
+                check_host(host)?;
+                Ok((host.as_str().into(), Port::Inet(*port)))
+            }
+            Self::Unix(path) => {
+                let host = path.to_str().ok_or_else(|| {
+                    Error::InvalidEndpoint(format!("{} is not valid UTF-8", path.display()))
+                })?;
+                if host.is_empty() {
+                    return Err(Error::InvalidEndpoint("Empty Unix socket path".into()));
+                }
+                check_host(host)?;
+                Ok((host.into(), Port::Unix))
+            }
+        }
+    }
+}
+
+fn check_host(host: &str) -> Result<()> {
+    if host.contains('\0') {
+        Err(Error::InvalidEndpoint(format!(
+            "{host:?} contains a NUL byte"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp { host, port } if host.contains(':') => write!(f, "[{host}]:{port}"),
+            Self::Tcp { host, port } => write!(f, "{host}:{port}"),
+            Self::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl From<&Path> for Endpoint {
+    fn from(path: &Path) -> Self {
+        Self::unix(path)
+    }
+}
+
+impl From<PathBuf> for Endpoint {
+    fn from(path: PathBuf) -> Self {
+        Self::Unix(path)
+    }
+}
+
+impl<S: Into<String>> From<(S, u16)> for Endpoint {
+    fn from((host, port): (S, u16)) -> Self {
+        Self::tcp(host, port)
+    }
+}
+
+/// Forwards opened through one control connection
+#[derive(Debug, Default)]
+pub(crate) struct Registry {
+    next_id: u64,
+    open: Vec<(u64, OpenFwd<'static>)>,
+    /// Forwards dropped without being closed, waiting for the connection to be used again
+    dropped: Vec<CloseFwd<'static>>,
+}
+
+impl Registry {
+    /// Locks `registry`, which stays consistent even if a thread panicked holding it
+    pub(crate) fn lock(registry: &Mutex<Self>) -> MutexGuard<'_, Self> {
+        match registry.lock() {
+            Ok(registry) => registry,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, request: OpenFwd<'static>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.open.push((id, request));
+        id
+    }
+
+    pub(crate) fn remove(&mut self, id: u64) -> Option<OpenFwd<'static>> {
+        let pos = self.open.iter().position(|(i, _)| *i == id)?;
+        Some(self.open.remove(pos).1)
+    }
+
+    pub(crate) fn open(&self) -> impl Iterator<Item = &OpenFwd<'static>> {
+        self.open.iter().map(|(_, request)| request)
+    }
+
+    pub(crate) fn has_dropped(&self) -> bool {
+        !self.dropped.is_empty()
+    }
+
+    /// Takes the next dropped forward to close
+    pub(crate) fn pop_dropped(&mut self) -> Option<CloseFwd<'static>> {
+        (!self.dropped.is_empty()).then(|| self.dropped.remove(0))
+    }
+
+    /// Puts back a close that could not be sent, to be sent first next time
+    pub(crate) fn requeue_dropped(&mut self, request: CloseFwd<'static>) {
+        self.dropped.insert(0, request);
+    }
+}
+
+/// Whether the forward acts as a SOCKS proxy instead of connecting to a fixed address
+pub(crate) fn is_socks(request: &OpenFwd<'_>) -> bool {
+    match request.forwarding_type {
+        ForwardingType::Local => false,
+        ForwardingType::Remote => {
+            request.connect_host.is_empty() && request.connect_port == Port::Inet(0)
+        }
+        ForwardingType::Dynamic => true,
+    }
+}
+
+/// Request for a forward from `listen` to `connect`, see
+/// [`SshControl::open_forward`](crate::SshControl::open_forward)
+pub(crate) fn open_request(
+    forwarding_type: ForwardingType,
+    listen: Endpoint,
+    connect: Option<Endpoint>,
+) -> Result<OpenFwd<'static>> {
+    let (listen_host, listen_port) = listen.to_wire()?;
+    let (connect_host, connect_port) = match (forwarding_type, &connect) {
+        (ForwardingType::Dynamic | ForwardingType::Remote, None) => ("".into(), Port::Inet(0)),
+        (ForwardingType::Dynamic, Some(_)) => {
+            return Err(Error::InvalidEndpoint(
+                "Dynamic forwards have no connect endpoint".into(),
+            ))
+        }
+        (_, Some(connect)) => connect.to_wire()?,
+        (_, None) => {
+            return Err(Error::InvalidEndpoint(format!(
+                "{forwarding_type:?} forward without a connect endpoint"
+            )))
+        }
+    };
+    Ok(OpenFwd {
+        request_id: 0,
+        forwarding_type,
+        listen_host,
+        listen_port,
+        connect_host,
+        connect_port,
+    }
+    .into_owned())
+}
+
+/// Checks the endpoints of `request`, returning it ready to be sent
+pub(crate) fn checked_request(request: OpenFwd<'_>) -> Result<OpenFwd<'static>> {
+    Endpoint::from_wire(&request.listen_host, request.listen_port)?;
+    if !is_socks(&request) {
+        Endpoint::from_wire(&request.connect_host, request.connect_port)?;
+    }
+    Ok(OpenFwd {
+        request_id: 0,
+        ..request.into_owned()
+    })
+}
+
+/// Port the master allocated in its `response` to an open request, if any
+pub(crate) fn allocated_port(response: MuxResponse<'_>) -> Result<Option<u16>> {
+    match response {
+        MuxResponse::RemotePort(rp) => {
+            Some(u16::try_from(rp.allocated_remote_listen_port).map_err(|_| {
+                Error::InvalidPacket {
+                    description: format!(
+                        "Allocated port {} out of range",
+                        rp.allocated_remote_listen_port
+                    )
+                    .into(),
+                }
+            }))
+            .transpose()
+        }
+        response => {
+            Result::<server::Ok>::from(response)?;
+            Ok(None)
+        }
+    }
+}
+
+pub(crate) fn close_request(request: OpenFwd<'static>) -> CloseFwd<'static> {
+    CloseFwd {
+        request_id: 0,
+        forwarding_type: request.forwarding_type,
+        listen_host: request.listen_host,
+        listen_port: request.listen_port,
+        connect_host: request.connect_host,
+        connect_port: request.connect_port,
+    }
+}
+
+/// A forwarding opened with [`SshControl::open_forward`](crate::SshControl::open_forward)
+///
+/// Like [`Child`](crate::command::Child) for sessions, the forward lives as long as this value:
+/// it is closed by [`SshControl::close_forward`](crate::SshControl::close_forward), or when
+/// dropped, by the next request made on the control connection it was opened on, or when that
+/// connection is dropped, if its socket supports
+/// [`ControlSocket::set_read_timeout`](crate::ControlSocket::set_read_timeout). A forward still
+/// held when its connection is dropped stays open in the master.
+pub struct Forward {
+    pub(crate) request: OpenFwd<'static>,
+    pub(crate) allocated_port: Option<u16>,
+    pub(crate) id: u64,
+    pub(crate) registry: Arc<Mutex<Registry>>,
+}
+
+impl Forward {
+    /// Records the forward opened by `request` in `registry`
+    pub(crate) fn new(
+        request: OpenFwd<'static>,
+        allocated_port: Option<u16>,
+        registry: &Arc<Mutex<Registry>>,
+    ) -> Self {
+        let id = Registry::lock(registry).insert(request.clone());
+        Self {
+            request,
+            allocated_port,
+            id,
+            registry: Arc::clone(registry),
+        }
+    }
+
+    pub fn forwarding_type(&self) -> ForwardingType {
+        self.request.forwarding_type
+    }
+
+    pub fn listen(&self) -> Endpoint {
+        Endpoint::from_wire(&self.request.listen_host, self.request.listen_port)
+            .expect("validated when opened")
+    }
+
+    /// Destination of the forward, `None` for forwards acting as a SOCKS proxy
+    pub fn connect(&self) -> Option<Endpoint> {
+        if is_socks(&self.request) {
+            return None;
+        }
+        Some(
+            Endpoint::from_wire(&self.request.connect_host, self.request.connect_port)
+                .expect("validated when opened"),
+        )
+    }
+
+    /// Port picked by the server for remote forwards requested on port 0
+    pub fn allocated_port(&self) -> Option<u16> {
+        self.allocated_port
+    }
+
+    /// Request the forward was opened with
+    pub fn request(&self) -> &OpenFwd<'static> {
+        &self.request
+    }
+}
+
+impl fmt::Debug for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Forward")
+            .field("forwarding_type", &self.request.forwarding_type)
+            .field("listen", &self.listen())
+            .field("connect", &self.connect())
+            .field("allocated_port", &self.allocated_port)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Forward {
+    fn drop(&mut self) {
+        let mut registry = Registry::lock(&self.registry);
+        if let Some(request) = registry.remove(self.id) {
+            log::debug!("Forward {request:?} dropped, will be closed");
+            registry.dropped.push(close_request(request));
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! `ssh -L`, `-R` and `-D` forwarding specifications
+//!
+//! Parsing follows OpenSSH's `parse_forward`: fields are separated by `:`, may be enclosed in
+//! square brackets (IPv6 addresses) or have characters escaped with a backslash, and a field
+//! containing a `/` is a Unix socket path.
+
+use std::{
+    fmt::{self, Write},
+    str::FromStr,
+};
+
+use crate::{
+    client::{ForwardingType, OpenFwd, Port},
+    Error, Result,
+};
+
+struct Field {
+    value: String,
+    is_path: bool,
+}
+
+fn invalid(spec: &str, reason: &str) -> Error {
+    Error::InvalidConfig(format!("Invalid forward {spec:?}: {reason}"))
+}
+
+fn split_fields(spec: &str) -> Result<Vec<Field>> {
+    let mut fields = Vec::new();
+    let mut rest = spec;
+    while !rest.is_empty() {
+        if fields.len() == 4 {
+            return Err(invalid(spec, "too many fields"));
+        }
+        if let Some(bracketed) = rest.strip_prefix('[') {
+            let end = bracketed
+                .find(']')
+                .ok_or_else(|| invalid(spec, "unmatched '['"))?;
+            let value = &bracketed[..end];
+            rest = match &bracketed[end + 1..] {
+                "" => "",
+                after => after
+                    .strip_prefix(':')
+                    .ok_or_else(|| invalid(spec, "']' must end a field"))?,
+            };
+            fields.push(Field {
+                value: value.to_owned(),
+                is_path: value.contains('/'),
+            });
+            continue;
+        }
+
+        let mut value = String::new();
+        let mut is_path = false;
+        let mut chars = rest.chars();
+        rest = "";
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    let escaped = chars
+                        .next()
+                        .ok_or_else(|| invalid(spec, "trailing backslash"))?;
+                    value.push(escaped);
+                }
+                ':' => {
+                    rest = chars.as_str();
+                    break;
+                }
+                '/' => {
+                    is_path = true;
+                    value.push(c);
+                }
+                c => value.push(c),
+            }
+        }
+        fields.push(Field { value, is_path });
+    }
+    Ok(fields)
+}
+
+fn port(spec: &str, field: &Field) -> Result<Port> {
+    field
+        .value
+        .parse()
+        .map(Port::Inet)
+        .map_err(|_| invalid(spec, &format!("bad port {:?}", field.value)))
+}
+
+impl FromStr for OpenFwd<'static> {
+    type Err = Error;
+
+    /// Parses a forward given as `-L [bind:]port:host:hostport`, `-R [bind:]port:host:hostport`,
+    /// `-R [bind:]port` (remote SOCKS proxy) or `-D [bind:]port`, where any address can be a Unix
+    /// socket path
+    fn from_str(spec: &str) -> Result<Self> {
+        let (forwarding_type, args) = match spec.trim().split_at_checked(2) {
+            Some(("-L", args)) => (ForwardingType::Local, args),
+            Some(("-R", args)) => (ForwardingType::Remote, args),
+            Some(("-D", args)) => (ForwardingType::Dynamic, args),
+            _ => return Err(invalid(spec, "expected -L, -R or -D")),
+        };
+        let fields = split_fields(args.trim_start())?;
+
+        let unix = |field: &Field| (field.value.clone(), Port::Unix);
+        let tcp =
+            |host: &Field, p: &Field| -> Result<_> { Ok((host.value.clone(), port(spec, p)?)) };
+        let no_host = |p: &Field| -> Result<_> { Ok((String::new(), port(spec, p)?)) };
+        let none = (String::new(), Port::Inet(0));
+
+        let ((listen_host, listen_port), (connect_host, connect_port)) =
+            match (forwarding_type, &fields[..]) {
+                (ForwardingType::Dynamic | ForwardingType::Remote, [listen]) if listen.is_path => {
+                    (unix(listen), none)
+                }
+                (ForwardingType::Dynamic | ForwardingType::Remote, [listen]) => {
+                    (no_host(listen)?, none)
+                }
+                (ForwardingType::Dynamic | ForwardingType::Remote, [bind, listen])
+                    if !bind.is_path && !listen.is_path =>
+                {
+                    (tcp(bind, listen)?, none)
+                }
+                (ForwardingType::Dynamic, _) => {
+                    return Err(invalid(spec, "expected [bind:]port"));
+                }
+                (_, [listen, connect]) if listen.is_path && connect.is_path => {
+                    (unix(listen), unix(connect))
+                }
+                (_, [listen, connect]) if connect.is_path => (no_host(listen)?, unix(connect)),
+                (_, [listen, host, p]) if listen.is_path => (unix(listen), tcp(host, p)?),
+                (_, [bind, listen, connect]) if connect.is_path => {
+                    (tcp(bind, listen)?, unix(connect))
+                }
+                (_, [listen, host, p]) => (no_host(listen)?, tcp(host, p)?),
+                (_, [bind, listen, host, p]) => (tcp(bind, listen)?, tcp(host, p)?),
+                _ => return Err(invalid(spec, "expected [bind:]port:host:hostport")),
+            };
+
+        if forwarding_type != ForwardingType::Remote && listen_port == Port::Inet(0) {
+            return Err(invalid(spec, "listen port 0 is only allowed for -R"));
+        }
+        if connect_port == Port::Inet(0) && !connect_host.is_empty() {
+            return Err(invalid(spec, "connect port 0"));
+        }
+
+        Ok(OpenFwd {
+            request_id: 0,
+            forwarding_type,
+            listen_host: listen_host.into(),
+            listen_port,
+            connect_host: connect_host.into(),
+            connect_port,
+        })
+    }
+}
+
+/// Field written so that [`split_fields`] reads it back unchanged
+fn write_field(f: &mut fmt::Formatter<'_>, value: &str, is_path: bool) -> fmt::Result {
+    if !is_path && value.contains(':') && !value.contains(['/', '\\', ']']) {
+        return write!(f, "[{value}]");
+    }
+    let special: &[char] = if is_path {
+        &['\\', ':', '[']
+    } else {
+        &['\\', ':', '[', '/']
+    };
+    for c in value.chars() {
+        if special.contains(&c) {
+            f.write_char('\\')?;
+        }
+        f.write_char(c)?;
+    }
+    Ok(())
+}
+
+fn write_address(
+    f: &mut fmt::Formatter<'_>,
+    host: &str,
+    port: Port,
+    host_optional: bool,
+) -> fmt::Result {
+    match port {
+        Port::Unix => write_field(f, host, true),
+        Port::Inet(port) if host_optional && host.is_empty() => write!(f, "{port}"),
+        Port::Inet(port) => {
+            write_field(f, host, false)?;
+            write!(f, ":{port}")
+        }
+    }
+}
+
+/// Writes the forward the way it is given to `ssh`, e.g. `-L 8080:localhost:80`
+impl fmt::Display for OpenFwd<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.forwarding_type {
+            ForwardingType::Local => "-L ",
+            ForwardingType::Remote => "-R ",
+            ForwardingType::Dynamic => "-D ",
+        })?;
+        write_address(f, &self.listen_host, self.listen_port, true)?;
+        if !super::is_socks(self) {
+            f.write_str(":")?;
+            write_address(f, &self.connect_host, self.connect_port, false)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+//! Policy enforcement for the [proxy](crate::proxy).
+//!
+//! A [`Policy`] is read from a TOML file such as:
+//!
+//! ```toml
+//! allow_terminate = false
+//! allow_stop_listening = false
+//! # Closing forwards is refused by default, as any client could close those of the others
+//! allow_close_forward = false
+//!
+//! [session]
+//! # A command must match one of these
+//! allow = ['^uptime$', '^systemctl status [a-z-]+$']
+//! # A command matching any of these is refused
+//! deny = ['sudo']
+//! # Names of the environment variables a session may set, none if empty
+//! env = ['^LANG$', '^LC_[A-Z]+$']
+//! allow_agent = false
+//! allow_x11_forwarding = false
+//!
+//! [forward]
+//! types = ["Local", "Remote"]
+//! # `host:port`, or the socket path for Unix sockets, of the connect side
+//! destinations = ['^localhost:(80|443)$', '^/var/run/docker\.sock$']
+//! ```
+//!
+//! Patterns are regular expressions matched anywhere in the value, anchor them with `^` and `$`
+//! to match the whole value. Rejected requests get a `PermissionDenied` response with the reason.
+//!
+//! Requests are refused unless the policy allows them: only alive checks are always forwarded,
+//! and empty lists allow nothing, so that an empty policy refuses every other request. Forwards
+//! acting as a SOCKS proxy reach any destination, so they are refused when `destinations` is not
+//! empty: a policy allowing them lists their type and no destination. Requests the proxy cannot
+//! decode never reach the policy, the proxy refuses them itself.
+
+use std::{fs, path::Path, str::FromStr};
+
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    client::{ForwardingType, Port},
+    forward,
+    proxy::{Filter, Peer, Verdict},
+    Error, MuxMessage, Result,
+};
+
+/// Regular expression read from a policy
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(Self)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+fn find<'a>(patterns: &'a [Pattern], value: &str) -> Option<&'a Pattern> {
+    patterns.iter().find(|p| p.0.is_match(value))
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionPolicy {
+    /// Allowed commands, none if empty
+    pub allow: Vec<Pattern>,
+    pub deny: Vec<Pattern>,
+    /// Allowed environment variable names
+    pub env: Vec<Pattern>,
+    pub allow_agent: bool,
+    pub allow_x11_forwarding: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardPolicy {
+    /// Allowed forwarding types, none if empty
+    pub types: Vec<ForwardingType>,
+    /// Allowed destinations, none if empty
+    pub destinations: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub session: SessionPolicy,
+    pub forward: ForwardPolicy,
+    pub allow_terminate: bool,
+    pub allow_stop_listening: bool,
+    pub allow_close_forward: bool,
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        toml::from_str(s).map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+}
+
+impl Policy {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+
+    fn check_command(&self, command: &str) -> Verdict {
+        if let Some(p) = find(&self.session.deny, command) {
+            return Verdict::Deny(format!("command matches denied pattern `{}`", p.0));
+        }
+        if find(&self.session.allow, command).is_none() {
+            return Verdict::Deny(format!("command `{command}` is not allowed"));
+        }
+        Verdict::Forward
+    }
+
+    fn check_environment(&self, environment: &[impl AsRef<str>]) -> Verdict {
+        for var in environment {
+            let var = var.as_ref();
+            let name = var.split_once('=').map_or(var, |(name, _)| name);
+            if find(&self.session.env, name).is_none() {
+                return Verdict::Deny(format!("environment variable `{name}` is not allowed"));
+            }
+        }
+        Verdict::Forward
+    }
+
+    fn check_destination(&self, host: &str, port: &Port) -> Verdict {
+        let destination = match port {
+            Port::Inet(port) => format!("{host}:{port}"),
+            Port::Unix => host.to_owned(),
+        };
+        if find(&self.forward.destinations, &destination).is_none() {
+            return Verdict::Deny(format!("destination `{destination}` is not allowed"));
+        }
+        Verdict::Forward
+    }
+
+    pub fn check(&self, request: &MuxMessage<'_>) -> Verdict {
+        match request {
+            MuxMessage::NewSession(ns) if ns.want_agent && !self.session.allow_agent => {
+                Verdict::Deny("agent forwarding is not allowed".into())
+            }
+            MuxMessage::NewSession(ns)
+                if ns.want_x11_forwarding && !self.session.allow_x11_forwarding =>
+            {
+                Verdict::Deny("X11 forwarding is not allowed".into())
+            }
+            MuxMessage::NewSession(ns) => match self.check_command(&ns.command) {
+                Verdict::Forward => self.check_environment(&ns.environment),
+                deny => deny,
+            },
+            MuxMessage::OpenFwd(fwd) => {
+                if !self.forward.types.contains(&fwd.forwarding_type) {
+                    return Verdict::Deny(format!(
+                        "{:?} forwarding is not allowed",
+                        fwd.forwarding_type
+                    ));
+                }
+                if !forward::is_socks(fwd) {
+                    self.check_destination(&fwd.connect_host, &fwd.connect_port)
+                } else if self.forward.destinations.is_empty() {
+                    Verdict::Forward
+                } else {
+                    Verdict::Deny(
+                        "SOCKS forwards reach any destination, which is restricted".into(),
+                    )
+                }
+            }
+            MuxMessage::NewStdioFwd(fwd) => {
+                self.check_destination(&fwd.connect_host, &fwd.connect_port)
+            }
+            MuxMessage::AliveCheck(_) => Verdict::Forward,
+            MuxMessage::Terminate(_) if self.allow_terminate => Verdict::Forward,
+            MuxMessage::Terminate(_) => {
+                Verdict::Deny("terminating the master is not allowed".into())
+            }
+            MuxMessage::StopListening(_) if self.allow_stop_listening => Verdict::Forward,
+            MuxMessage::StopListening(_) => {
+                Verdict::Deny("stopping the master is not allowed".into())
+            }
+            MuxMessage::CloseFwd(_) if self.allow_close_forward => Verdict::Forward,
+            MuxMessage::CloseFwd(_) => Verdict::Deny("closing forwards is not allowed".into()),
+        }
+    }
+}
+
+impl Filter for Policy {
+    fn check(&self, _peer: Option<&Peer>, request: &MuxMessage<'_>) -> Verdict {
+        Policy::check(self, request)
+    }
+}
@@ -0,0 +1,426 @@
+//! Control connection shared between threads.
+//!
+//! [`SshControl`] borrows the connection mutably for each request, from sending it until its
+//! response is read. [`SshControlHandle`] instead has a background thread read every response,
+//! handing each to the request with the same id, and the events of sessions to whoever waits for
+//! them, so that requests from several threads are in flight at once.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::Shutdown,
+    os::unix::{io::RawFd, net::UnixStream},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    client,
+    command::{self, Child, SessionEvent, SshCommand},
+    forward::{self, Endpoint, Forward, Registry},
+    server, ControlSocket, Error, MuxMessage, MuxResponse, Packet, Result, SshControl, Wire,
+    CLOSE_ON_DROP_TIMEOUT,
+};
+
+/// Locks `mutex`, whose data stays consistent even if a thread panicked holding it
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Where the reader thread sends what it reads
+#[derive(Default)]
+struct Routes {
+    /// Why the connection is no longer read, once it is not
+    closed: Option<(io::ErrorKind, String)>,
+    /// Requests waiting for their response, by request id
+    pending: HashMap<u32, Sender<Result<MuxResponse<'static>>>>,
+    /// Sessions whose command is still running, by session id
+    running: HashMap<u32, Sender<SessionEvent>>,
+    /// Events of sessions just opened, by session id, until the request opening them hands them
+    /// to their [`Child`]
+    events: HashMap<u32, Receiver<SessionEvent>>,
+}
+
+impl Routes {
+    fn closed_error(&self) -> Option<Error> {
+        let (kind, message) = self.closed.as_ref()?;
+        Some(Error::IO(io::Error::new(*kind, message.clone())))
+    }
+}
+
+/// Writing half of the connection
+struct Writer {
+    socket: UnixStream,
+    buffer: Packet,
+    request_id: u32,
+}
+
+struct Inner {
+    writer: Mutex<Writer>,
+    routes: Arc<Mutex<Routes>>,
+    forwards: Arc<Mutex<Registry>>,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl Inner {
+    /// Sends `request` with the next request id, followed by the descriptors in `fds`, and waits
+    /// at most `timeout` for its response
+    fn send_request<'a>(
+        &self,
+        request: impl Into<MuxMessage<'a>>,
+        fds: &[RawFd],
+        timeout: Option<Duration>,
+    ) -> Result<MuxResponse<'static>> {
+        let mut msg = request.into();
+        let (tx, rx) = mpsc::channel();
+        let request_id = {
+            let mut writer = lock(&self.writer);
+            let request_id = writer.request_id.wrapping_add(1);
+            writer.request_id = request_id;
+            msg.set_request_id(request_id);
+            {
+                let mut routes = lock(&self.routes);
+                if let Some(e) = routes.closed_error() {
+                    return Err(e);
+                }
+                routes.pending.insert(request_id, tx);
+            }
+
+            let Writer { socket, buffer, .. } = &mut *writer;
+            let sent = buffer.set(&msg).and_then(|()| {
+                log::debug!("Will send {msg:?}");
+                buffer.serialize(socket)?;
+                for &fd in fds {
+                    socket.send_fd(fd)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = sent {
+                lock(&self.routes).pending.remove(&request_id);
+                return Err(e);
+            }
+            request_id
+        };
+        let response = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout).map_err(|e| match e {
+                RecvTimeoutError::Timeout => {
+                    lock(&self.routes).pending.remove(&request_id);
+                    Some(io::Error::from(io::ErrorKind::TimedOut).into())
+                }
+                RecvTimeoutError::Disconnected => None,
+            }),
+            None => rx.recv().map_err(|_| None),
+        };
+        response.unwrap_or_else(|e| {
+            Err(e
+                .or_else(|| lock(&self.routes).closed_error())
+                .unwrap_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof).into()))
+        })
+    }
+
+    /// Closes the dropped forwards, waiting at most `timeout` for each
+    fn close_dropped_forwards(&self, timeout: Option<Duration>) -> Result<()> {
+        loop {
+            let Some(request) = Registry::lock(&self.forwards).pop_dropped() else {
+                return Ok(());
+            };
+            let response = match self.send_request(request.clone(), &[], timeout) {
+                Ok(response) => response,
+                Err(e) => {
+                    Registry::lock(&self.forwards).requeue_dropped(request);
+                    return Err(e);
+                }
+            };
+            Result::<server::Ok>::from(response)?;
+        }
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if Registry::lock(&self.forwards).has_dropped() {
+            // The master may not answer anymore, such as after a session ended
+            if let Err(e) = self.close_dropped_forwards(Some(CLOSE_ON_DROP_TIMEOUT)) {
+                log::warn!("Could not close dropped forwards: {e}");
+            }
+        }
+        // Makes the reader thread read the end of the connection
+        let _ = lock(&self.writer).socket.shutdown(Shutdown::Both);
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// Cloneable handle to a control connection, usable from several threads at once
+///
+/// Requests are the same as with [`SshControl`], taking `&self`. All clones share one connection,
+/// and OpenSSH masters serve a single session per connection, closing it once that session ends:
+/// from then on, every request through any clone fails. Use a handle per session, from
+/// [`SshControlHandle::new`], to run several.
+///
+/// Forwards dropped without being closed are closed when the last clone is dropped, waiting a
+/// few seconds at most for the master.
+#[derive(Clone)]
+pub struct SshControlHandle {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for SshControlHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let routes = lock(&self.inner.routes);
+        f.debug_struct("SshControlHandle")
+            .field("closed", &routes.closed)
+            .field("pending", &routes.pending.len())
+            .field("running", &routes.running.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SshControlHandle {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+        SshControl::new(path)?.into_handle()
+    }
+
+    /// Sends `request` after closing the dropped forwards, and waits for its response
+    fn request<'a>(
+        &self,
+        request: impl Into<MuxMessage<'a>>,
+        fds: &[RawFd],
+    ) -> Result<MuxResponse<'static>> {
+        // A failed close is not the failure of this request
+        if let Err(e) = self.close_dropped_forwards() {
+            log::warn!("Could not close dropped forwards: {e}");
+        }
+        self.inner.send_request(request, fds, None)
+    }
+
+    pub fn check_alive(&self) -> Result<u32> {
+        let response = self.request(client::AliveCheck { request_id: 0 }, &[])?;
+        let alive = Result::<server::Alive>::from(response)?;
+        Ok(alive.server_pid)
+    }
+
+    pub fn new_session(&self, command: SshCommand) -> Result<Child> {
+        let setup = command::SessionSetup::new(command)?;
+        let response = self.request(setup.request.clone(), &setup.fds())?;
+        let so = Result::<server::SessionOpened>::from(response)?;
+        let events = lock(&self.inner.routes).events.remove(&so.session_id);
+        let child = setup.into_child(so.session_id);
+        *lock(&child.events) = events;
+        Ok(child)
+    }
+
+    /// Events of `child`'s session, which can only be taken once
+    ///
+    /// Iteration blocks until the next event, and ends once the command exits or the connection
+    /// is lost. Events not taken are dropped with `child`.
+    pub fn events(&self, child: &Child) -> Result<mpsc::IntoIter<SessionEvent>> {
+        let session_id = child.session_id();
+        lock(&child.events)
+            .take()
+            .map(IntoIterator::into_iter)
+            .ok_or_else(|| {
+                Error::InvalidUsage(format!(
+                    "Session {session_id} was not opened through this handle, or is already waited for"
+                ))
+            })
+    }
+
+    /// Waits for `child` to exit, returning its exit value
+    pub fn wait(&self, child: &Child) -> Result<u32> {
+        for event in self.events(child)? {
+            match event {
+                SessionEvent::Opened { .. } | SessionEvent::TtyAllocFailed => {}
+                SessionEvent::Exited(exit_value) => return Ok(exit_value),
+                SessionEvent::ConnectionLost(e) => return Err(e),
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+
+    /// Waits for `child` to exit, collecting its piped stdout and stderr
+    ///
+    /// Like [`std::process::Child::wait_with_output`], `child`'s stdin is closed first.
+    pub fn wait_with_output(&self, mut child: Child) -> Result<command::Output> {
+        let (stdout, stderr) = child.collect_output()?;
+        let status = self.wait(&child)?;
+        Ok(command::Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Asks the master to forward connections from `listen` to `connect`, see
+    /// [`SshControl::open_forward`]
+    pub fn open_forward(
+        &self,
+        forwarding_type: client::ForwardingType,
+        listen: impl Into<Endpoint>,
+        connect: Option<Endpoint>,
+    ) -> Result<Forward> {
+        let request = forward::open_request(forwarding_type, listen.into(), connect)?;
+        self.open_forward_request(request)
+    }
+
+    /// Opens the forward described by `request`, such as one parsed from an `ssh` option
+    pub fn open_forward_request(&self, request: client::OpenFwd<'_>) -> Result<Forward> {
+        let request = forward::checked_request(request)?;
+        let allocated_port = forward::allocated_port(self.request(request.clone(), &[])?)?;
+        Ok(Forward::new(request, allocated_port, &self.inner.forwards))
+    }
+
+    /// Closes a forward opened through this handle
+    ///
+    /// If the request fails, the close is queued like that of a dropped forward.
+    pub fn close_forward(&self, forward: Forward) -> Result<()> {
+        if !Arc::ptr_eq(&forward.registry, &self.inner.forwards) {
+            return Err(Error::InvalidUsage(
+                "Forward was opened on another connection".into(),
+            ));
+        }
+        let Some(request) = Registry::lock(&self.inner.forwards).remove(forward.id) else {
+            return Ok(());
+        };
+        let request = forward::close_request(request);
+        let response = match self.request(request.clone(), &[]) {
+            Ok(response) => response,
+            Err(e) => {
+                // Sent again with the dropped forwards
+                Registry::lock(&self.inner.forwards).requeue_dropped(request);
+                return Err(e);
+            }
+        };
+        Result::<server::Ok>::from(response)?;
+        Ok(())
+    }
+
+    /// Forwards opened through this handle and not closed yet
+    pub fn forwards(&self) -> Vec<client::OpenFwd<'static>> {
+        Registry::lock(&self.inner.forwards)
+            .open()
+            .cloned()
+            .collect()
+    }
+
+    /// Closes the forwards dropped since the last request, see
+    /// [`SshControl::close_dropped_forwards`]
+    pub fn close_dropped_forwards(&self) -> Result<()> {
+        self.inner.close_dropped_forwards(None)
+    }
+
+    pub fn terminate(&self) -> Result<()> {
+        let response = self.request(client::Terminate { request_id: 0 }, &[])?;
+        Result::<server::Ok>::from(response)?;
+        Ok(())
+    }
+}
+
+impl SshControl<UnixStream> {
+    /// Turns this connection into a handle shareable between threads
+    ///
+    /// Forwards opened so far stay attached to the connection, and can be closed through the
+    /// handle.
+    pub fn into_handle(self) -> Result<SshControlHandle> {
+        if self.desynchronized {
+            return Err(Error::Desynchronized);
+        }
+        let (socket, reader_buffer, request_id, forwards) = self.into_parts();
+        let reader_socket = socket.try_clone()?;
+        let routes = Arc::<Mutex<Routes>>::default();
+        let reader = {
+            let routes = Arc::clone(&routes);
+            thread::Builder::new()
+                .name("ssh-control-reader".into())
+                .spawn(move || read_responses(reader_socket, reader_buffer, &routes))?
+        };
+        Ok(SshControlHandle {
+            inner: Arc::new(Inner {
+                writer: Mutex::new(Writer {
+                    socket,
+                    buffer: Vec::with_capacity(1024).into(),
+                    request_id,
+                }),
+                routes,
+                forwards,
+                reader: Some(reader),
+            }),
+        })
+    }
+}
+
+/// Reads the responses from `socket` until the connection fails, routing each to its request or
+/// session
+fn read_responses(mut socket: UnixStream, mut buffer: Packet, routes: &Mutex<Routes>) {
+    let error = loop {
+        let response = match buffer.recv_next::<MuxResponse, _>(&mut socket) {
+            Ok(response) => response.into_owned(),
+            Err(e) => break e,
+        };
+        log::debug!("Received {response:?}");
+        let mut routes = lock(routes);
+        if let Some(request_id) = response.get_request_id() {
+            let Some(tx) = routes.pending.remove(&request_id) else {
+                log::warn!("Response {response:?} to no pending request");
+                continue;
+            };
+            if let MuxResponse::SessionOpened(ref so) = response {
+                // Registered before the response is handed over, so that the events are there
+                // by the time the session is waited for
+                let (events_tx, events_rx) = mpsc::channel();
+                let _ = events_tx.send(SessionEvent::Opened {
+                    session_id: so.session_id,
+                });
+                routes.running.insert(so.session_id, events_tx);
+                routes.events.insert(so.session_id, events_rx);
+            }
+            let _ = tx.send(Ok(response));
+            continue;
+        }
+        let session_id = match response {
+            MuxResponse::ExitMessage(server::ExitMessage { session_id, .. })
+            | MuxResponse::TtyAllocFail(server::TtyAllocFail { session_id }) => session_id,
+            _ => {
+                log::warn!("Unexpected {response:?}");
+                continue;
+            }
+        };
+        let event = SessionEvent::from_response(Ok(response), session_id);
+        let last = event.is_last();
+        let Some(tx) = routes.running.get(&session_id) else {
+            log::warn!("{event:?} for unknown session {session_id}");
+            continue;
+        };
+        let _ = tx.send(event);
+        if last {
+            routes.running.remove(&session_id);
+        }
+    };
+
+    let kind = match error {
+        Error::IO(ref e) => e.kind(),
+        _ => io::ErrorKind::InvalidData,
+    };
+    log::debug!("Control connection closed: {error}");
+    let message = format!("Control connection closed: {error}");
+    let mut routes = lock(routes);
+    for (_, tx) in routes.pending.drain() {
+        let _ = tx.send(Err(Error::IO(io::Error::new(kind, message.clone()))));
+    }
+    for (_, tx) in routes.running.drain() {
+        let _ = tx.send(SessionEvent::ConnectionLost(Error::IO(io::Error::new(
+            kind,
+            message.clone(),
+        ))));
+    }
+    routes.closed = Some((kind, message));
+}
@@ -1,47 +1,164 @@
-use passfd::FdPassingExt;
 use std::{
-    env,
-    mem::ManuallyDrop,
-    os::unix::{io::AsRawFd, net::UnixStream},
+    io::{self, Read},
+    mem::{self, ManuallyDrop},
+    os::unix::net::UnixStream,
     path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
 };
 
+extern crate self as ssh_control;
+
 mod protocol;
 pub use protocol::{
-    client::{self, MuxMessage},
+    client, parse_all,
     server::{self, MuxResponse},
-    Hello, Packet, Wire,
+    Extension, Hello, MuxMessage, NomError, Packet, Wire, DEFAULT_MAX_PACKET_SIZE,
 };
 
+#[cfg(feature = "tokio")]
+pub mod codec;
 pub mod command;
+pub mod dissect;
+pub mod fleet;
+pub mod forward;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+pub mod handle;
+#[cfg(feature = "profile")]
+pub mod profile;
+pub mod proxy;
+#[cfg(feature = "record")]
+pub mod record;
+pub mod remote_fs;
+pub mod sftp;
+pub mod transfer;
+pub mod tty;
 use command::{Child, SshCommand};
+use forward::{Endpoint, Forward, Registry};
 
 pub(crate) mod error;
 pub use error::{Error, Result};
 
-pub struct SshControl {
-    socket: UnixStream,
+mod socket;
+pub use socket::ControlSocket;
+
+#[doc(hidden)]
+pub mod __private {
+    //! Items used by code generated by `#[derive(Wire)]`
+    pub use crate::protocol::{
+        utils::{many, many_terminated, IntoOwned},
+        NomError, Wire,
+    };
+    pub use log;
+    pub use nom;
+}
+
+/// Reader noting whether any byte was read through it
+struct Tracked<'a, R> {
+    reader: &'a mut R,
+    read_any: bool,
+}
+
+impl<'a, R> Tracked<'a, R> {
+    fn new(reader: &'a mut R) -> Self {
+        Self {
+            reader,
+            read_any: false,
+        }
+    }
+}
+
+impl<R> Read for Tracked<'_, R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.read_any |= n > 0;
+        Ok(n)
+    }
+}
+
+/// How long closing the dropped forwards may wait for the master when a connection is dropped
+const CLOSE_ON_DROP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Message of the panic when using a connection taken apart by `into_parts`, which consumes it
+const TAKEN_APART: &str = "the socket is only taken by into_parts";
+
+/// Socket of a connection, borrowed alone so that the other fields stay available
+fn socket_mut<S>(socket: &mut Option<S>) -> &mut S {
+    socket.as_mut().expect(TAKEN_APART)
+}
+
+pub struct SshControl<S = UnixStream>
+where
+    S: ControlSocket,
+{
+    /// Only `None` once taken by `into_parts`, so that dropping the rest does not close forwards
+    socket: Option<S>,
     buffer: Packet,
     request_id: u32,
     expected_request_id: Option<u32>,
+    forwards: Arc<Mutex<Registry>>,
+    /// Whether a read stopped in the middle of a packet, leaving the stream unusable
+    desynchronized: bool,
 }
 
 impl SshControl {
     pub fn new(path: impl AsRef<Path>) -> Result<Self> {
         let socket = UnixStream::connect(path)?;
+        Self::with_socket(socket)
+    }
+}
+
+impl<S> SshControl<S>
+where
+    S: ControlSocket,
+{
+    /// Performs the handshake over an already connected `socket`
+    pub fn with_socket(socket: S) -> Result<Self> {
         let buffer = Vec::with_capacity(1024).into();
 
         let mut me = Self {
-            socket,
+            socket: Some(socket),
             buffer,
             request_id: 0,
             expected_request_id: None,
+            forwards: Arc::default(),
+            desynchronized: false,
         };
         me.send_hello()?;
 
         Ok(me)
     }
 
+    pub fn socket(&self) -> &S {
+        self.socket.as_ref().expect(TAKEN_APART)
+    }
+
+    /// Returns the socket, after closing the dropped forwards as when dropped
+    pub fn into_socket(mut self) -> S {
+        self.close_dropped_forwards_on_drop();
+        let mut socket = self.into_parts().0;
+        if let Err(e) = socket.set_read_timeout(None) {
+            log::debug!("Could not reset the read timeout: {e}");
+        }
+        socket
+    }
+
+    /// Takes the connection apart, leaving the dropped forwards queued
+    pub(crate) fn into_parts(mut self) -> (S, Packet, u32, Arc<Mutex<Registry>>) {
+        let socket = self.socket.take().expect(TAKEN_APART);
+        let buffer = mem::replace(&mut self.buffer, Vec::new().into());
+        (socket, buffer, self.request_id, Arc::clone(&self.forwards))
+    }
+
+    /// Sets the maximum size of packets accepted from the master
+    pub fn set_max_packet_size(&mut self, max_size: usize) {
+        self.buffer.set_max_size(max_size);
+    }
+
     fn get_next_request_id(&mut self) -> u32 {
         let next = self.request_id.wrapping_add(1);
         self.request_id = next;
@@ -52,18 +169,45 @@ impl SshControl {
     where
         T: Into<MuxMessage<'a>>,
     {
+        // A failed close is not the failure of this request
+        if let Err(e) = self.close_dropped_forwards() {
+            log::warn!("Could not close dropped forwards: {e}");
+        }
+        self.send_request(obj)
+    }
+
+    fn send_request<'a, T>(&mut self, obj: T) -> Result<()>
+    where
+        T: Into<MuxMessage<'a>>,
+    {
+        if self.desynchronized {
+            return Err(Error::Desynchronized);
+        }
         let mut msg = obj.into();
         let request_id = self.get_next_request_id();
         msg.set_request_id(request_id);
         self.expected_request_id = Some(request_id);
-        self.buffer.set(&msg);
+        self.buffer.set(&msg)?;
         log::debug!("Will send {msg:?}");
-        self.buffer.serialize(&mut self.socket)?;
+        self.buffer.serialize(socket_mut(&mut self.socket))?;
         Ok(())
     }
 
     fn recv_helper(&mut self) -> Result<MuxResponse<'_>> {
-        let response: MuxResponse = self.buffer.recv_next(&mut self.socket)?;
+        if self.desynchronized {
+            return Err(Error::Desynchronized);
+        }
+        let mut reader = Tracked::new(socket_mut(&mut self.socket));
+        let body = match self.buffer.recv_raw(&mut reader) {
+            Ok(body) => body,
+            Err(e) => {
+                // The rest of the packet would be read as the start of the next one
+                self.desynchronized = reader.read_any;
+                return Err(e);
+            }
+        };
+        let response: MuxResponse = parse_all(body)?;
+        log::debug!("Received {response:?}");
         let expected_request_id = self.expected_request_id.take();
         if response.get_request_id() != expected_request_id {
             log::error!("Request IDs does not match");
@@ -89,10 +233,12 @@ impl SshControl {
             version: 4,
             extensions: Vec::new(),
         };
-        self.buffer.set(&hello);
-        self.buffer.serialize(&mut self.socket)?;
+        self.buffer.set(&hello)?;
+        self.buffer.serialize(socket_mut(&mut self.socket))?;
 
-        let hello = self.buffer.recv_next::<Hello, _>(&mut self.socket)?;
+        let hello = self
+            .buffer
+            .recv_next::<Hello, _>(socket_mut(&mut self.socket))?;
         log::debug!(
             "Server is running version {} with extensions: {:?}",
             hello.version,
@@ -113,61 +259,14 @@ impl SshControl {
     }
 
     pub fn new_session(&mut self, command: SshCommand) -> Result<Child> {
-        let environment: Vec<_> = command
-            .environment
-            .iter()
-            .map(|(k, v)| format!("{k}={v}").into())
-            .collect();
-        let req: MuxMessage = client::NewSession {
-            request_id: 0,
-            want_tty: command.want_tty,
-            want_x11_forwarding: command.want_x11_forwarding,
-            want_agent: false,
-            subsystem: false,
-            escape_char: b'~' as u32,
-            terminal_type: env::var("TERM")
-                .ok()
-                .unwrap_or_else(|| "xterm".into())
-                .into(),
-            command: command.shell_command.into(),
-            environment,
+        let setup = command::SessionSetup::new(command)?;
+        self.send(setup.request.clone())?;
+        for fd in setup.fds() {
+            socket_mut(&mut self.socket).send_fd(fd)?;
         }
-        .into();
-        self.send(req)?;
-
-        let (child_stdin, stdin) = match command.stdin {
-            Some(p) => (Some(p.write), p.read),
-            None => (None, command::PipeRead(std::io::stdin().lock().as_raw_fd())),
-        };
-        let (child_stdout, stdout) = match command.stdout {
-            Some(p) => (Some(p.read), p.write),
-            None => (
-                None,
-                command::PipeWrite(std::io::stdout().lock().as_raw_fd()),
-            ),
-        };
-        let (child_stderr, stderr) = match command.stderr {
-            Some(p) => (Some(p.read), p.write),
-            None => (
-                None,
-                command::PipeWrite(std::io::stderr().lock().as_raw_fd()),
-            ),
-        };
-        let payload = &[0u8][..];
-        self.socket
-            .send_fd_with_payload(stdin.as_raw_fd(), payload)?;
-        self.socket
-            .send_fd_with_payload(stdout.as_raw_fd(), payload)?;
-        self.socket
-            .send_fd_with_payload(stderr.as_raw_fd(), payload)?;
 
         let so: server::SessionOpened = self.recv()?;
-        Ok(Child {
-            stdin: child_stdin,
-            stdout: child_stdout,
-            stderr: child_stderr,
-            session: so.session_id,
-        })
+        Ok(setup.into_child(so.session_id))
     }
 
     pub fn wait(&mut self, child: &Child) -> Result<bool> {
@@ -183,23 +282,84 @@ impl SshControl {
         }
     }
 
+    /// Waits at most `timeout` for `child` to exit, returning its exit value, or `None` if it is
+    /// still running
+    ///
+    /// The socket must support [`ControlSocket::set_read_timeout`]. If the timeout expires in the
+    /// middle of a packet, the error is returned and the connection cannot be used anymore: later
+    /// calls fail with [`Error::Desynchronized`].
+    pub fn wait_timeout(&mut self, child: &Child, timeout: Duration) -> Result<Option<u32>> {
+        socket_mut(&mut self.socket).set_read_timeout(Some(timeout))?;
+        let exit = self.recv::<server::ExitMessage>();
+        socket_mut(&mut self.socket).set_read_timeout(None)?;
+        match exit {
+            Ok(server::ExitMessage {
+                session_id,
+                exit_value,
+            }) if session_id == child.session => Ok(Some(exit_value)),
+            Ok(server::ExitMessage { session_id, .. }) => Err(Error::InvalidPacket {
+                description: format!(
+                    "Exit of session {session_id} while waiting for {}",
+                    child.session
+                )
+                .into(),
+            }),
+            Err(Error::IO(e))
+                if !self.desynchronized
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waits for `child` to exit, collecting its piped stdout and stderr
+    ///
+    /// Like [`std::process::Child::wait_with_output`], `child`'s stdin is closed first.
+    pub fn wait_with_output(&mut self, mut child: Child) -> Result<command::Output> {
+        let (stdout, stderr) = child.collect_output()?;
+
+        let server::ExitMessage {
+            session_id,
+            exit_value,
+        } = self.recv()?;
+        if session_id != child.session {
+            return Err(Error::InvalidPacket {
+                description: format!(
+                    "Exit of session {session_id} while waiting for {}",
+                    child.session
+                )
+                .into(),
+            });
+        }
+        Ok(command::Output {
+            status: exit_value,
+            stdout,
+            stderr,
+        })
+    }
+
     pub fn new_stdio_forward(
         &mut self,
-        host: impl AsRef<str>,
-        port: client::Port,
+        target: impl Into<Endpoint>,
         pipe: Option<command::Pipe>,
     ) -> Result<u32> {
+        let target = target.into();
+        let (connect_host, connect_port) = target.to_wire()?;
         let req: MuxMessage = client::NewStdioFwd {
             request_id: 0,
-            connect_host: host.as_ref().into(),
-            connect_port: port,
+            connect_host,
+            connect_port,
         }
         .into();
         self.send(req)?;
-        let payload = &[0u8][..];
         let pipe = pipe.unwrap_or_else(command::Pipe::stdio);
-        self.socket.send_fd_with_payload(pipe.read.0, payload)?;
-        self.socket.send_fd_with_payload(pipe.write.0, payload)?;
+        socket_mut(&mut self.socket).send_fd(pipe.read.0)?;
+        socket_mut(&mut self.socket).send_fd(pipe.write.0)?;
 
         // Avoid pipe being closed
         let _ = ManuallyDrop::new(pipe);
@@ -209,6 +369,98 @@ impl SshControl {
         Ok(so.session_id)
     }
 
+    fn forward_registry(&self) -> MutexGuard<'_, Registry> {
+        Registry::lock(&self.forwards)
+    }
+
+    /// Asks the master to forward connections from `listen` to `connect`
+    ///
+    /// Dynamic forwards act as a SOCKS proxy and take no `connect` endpoint. Remote forwards
+    /// without one do the same on the server side, local forwards require one.
+    pub fn open_forward(
+        &mut self,
+        forwarding_type: client::ForwardingType,
+        listen: impl Into<Endpoint>,
+        connect: Option<Endpoint>,
+    ) -> Result<Forward> {
+        let request = forward::open_request(forwarding_type, listen.into(), connect)?;
+        self.open_forward_request(request)
+    }
+
+    /// Opens the forward described by `request`, such as one parsed from an `ssh` option
+    pub fn open_forward_request(&mut self, request: client::OpenFwd<'_>) -> Result<Forward> {
+        let request = forward::checked_request(request)?;
+        self.send(request.clone())?;
+        let allocated_port = forward::allocated_port(self.recv_helper()?)?;
+        Ok(Forward::new(request, allocated_port, &self.forwards))
+    }
+
+    /// Closes a forward opened on this connection
+    ///
+    /// If the request cannot be sent, the close is queued like that of a dropped forward.
+    pub fn close_forward(&mut self, forward: Forward) -> Result<()> {
+        if !Arc::ptr_eq(&forward.registry, &self.forwards) {
+            return Err(Error::InvalidUsage(
+                "Forward was opened on another connection".into(),
+            ));
+        }
+        let Some(request) = self.forward_registry().remove(forward.id) else {
+            return Ok(());
+        };
+        let request = forward::close_request(request);
+        if let Err(e) = self.send(request.clone()) {
+            // Sent again with the dropped forwards
+            self.forward_registry().requeue_dropped(request);
+            return Err(e);
+        }
+        let _: server::Ok = self.recv()?;
+        Ok(())
+    }
+
+    /// Forwards opened on this connection and not closed yet
+    pub fn forwards(&self) -> Vec<client::OpenFwd<'static>> {
+        self.forward_registry().open().cloned().collect()
+    }
+
+    /// Closes the forwards dropped since the last request
+    ///
+    /// This happens before every request and when the connection is dropped anyway, with
+    /// failures only logged: calling it is needed to close them sooner, or to get the failures.
+    /// When the connection is dropped, this is skipped if the socket does not support
+    /// [`ControlSocket::set_read_timeout`], since the master may never answer.
+    /// A close that could not be sent stays queued, one the master refused is not retried.
+    pub fn close_dropped_forwards(&mut self) -> Result<()> {
+        loop {
+            let Some(request) = self.forward_registry().pop_dropped() else {
+                return Ok(());
+            };
+            if let Err(e) = self.send_request(request.clone()) {
+                self.forward_registry().requeue_dropped(request);
+                return Err(e);
+            }
+            let _: server::Ok = self.recv()?;
+        }
+    }
+
+    /// Closes the dropped forwards when the connection goes away, waiting at most
+    /// [`CLOSE_ON_DROP_TIMEOUT`] for each, and not at all without a read timeout
+    fn close_dropped_forwards_on_drop(&mut self) {
+        if self.desynchronized || !self.forward_registry().has_dropped() {
+            return;
+        }
+        let Some(socket) = self.socket.as_mut() else {
+            return;
+        };
+        // The master may not answer anymore, such as after a session ended
+        if let Err(e) = socket.set_read_timeout(Some(CLOSE_ON_DROP_TIMEOUT)) {
+            log::warn!("Not closing dropped forwards, the socket has no read timeout: {e}");
+            return;
+        }
+        if let Err(e) = self.close_dropped_forwards() {
+            log::warn!("Could not close dropped forwards: {e}");
+        }
+    }
+
     pub fn terminate(&mut self) -> Result<()> {
         let req: MuxMessage = client::Terminate { request_id: 0 }.into();
         self.send(req)?;
@@ -217,3 +469,12 @@ impl SshControl {
         Ok(())
     }
 }
+
+impl<S> Drop for SshControl<S>
+where
+    S: ControlSocket,
+{
+    fn drop(&mut self) {
+        self.close_dropped_forwards_on_drop();
+    }
+}
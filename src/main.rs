@@ -1,7 +1,15 @@
-use std::{env, fs, io};
+use std::{
+    env, fs,
+    io::{self, Read},
+    os::unix::net::UnixListener,
+    process,
+};
 
 use ssh_control::{
     command::{Pipe, SshCommand},
+    dissect,
+    fleet::{Fleet, Target},
+    proxy::Proxy,
     Result, SshControl,
 };
 
@@ -16,8 +24,124 @@ fn main() {
     }
 }
 
+fn usage() -> ! {
+    let name = env::args().next().unwrap_or_else(|| "ssh_control".into());
+    eprintln!("Usage: {name} CONTROL_PATH");
+    eprintln!("       {name} dissect CAPTURE");
+    eprintln!("       {name} pexec [-j JOBS] COMMAND HOST|CONTROL_PATH...");
+    eprintln!("       {name} proxy LISTEN_PATH CONTROL_PATH");
+    eprintln!("       {name} shell CONTROL_PATH [COMMAND...]");
+    #[cfg(feature = "gateway")]
+    eprintln!("       {name} gateway POLICY LISTEN_PATH CONTROL_PATH");
+    process::exit(2);
+}
+
 fn main_helper() -> Result<()> {
-    let mut ctrl = SshControl::new(env::args().nth(1).unwrap())?;
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["dissect", capture] => dissect(capture),
+        ["pexec", "-j", jobs, command, ref targets @ ..] if !targets.is_empty() => {
+            let jobs = jobs.parse().unwrap_or_else(|_| usage());
+            pexec(jobs, command, targets)
+        }
+        ["pexec", command, ref targets @ ..] if !targets.is_empty() => {
+            pexec(ssh_control::fleet::DEFAULT_CONCURRENCY, command, targets)
+        }
+        ["proxy", listen, master] => proxy(listen, master),
+        ["shell", control_path, ref command @ ..] => shell(control_path, command),
+        #[cfg(feature = "gateway")]
+        ["gateway", policy, listen, master] => gateway(policy, listen, master),
+        [control_path] => run_id(control_path),
+        _ => usage(),
+    }
+}
+
+/// Dumps a capture given either as hex text or raw bytes, `-` reading it from stdin
+fn dissect(capture: &str) -> Result<()> {
+    let data = if capture == "-" {
+        let mut data = Vec::new();
+        io::stdin().read_to_end(&mut data)?;
+        data
+    } else {
+        fs::read(capture)?
+    };
+    let data = std::str::from_utf8(&data)
+        .ok()
+        .and_then(dissect::decode_hex)
+        .unwrap_or(data);
+    for packet in dissect::dissect(&data) {
+        println!("{packet}");
+    }
+    Ok(())
+}
+
+/// Runs `command` on every target, prefixing each line of output with the target name
+fn pexec(jobs: usize, command: &str, targets: &[&str]) -> Result<()> {
+    let targets = targets
+        .iter()
+        .map(|target| {
+            if target.contains('/') {
+                Ok(Target::new(*target, *target))
+            } else {
+                Target::resolve(target)
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut failures = 0;
+    Fleet::new(targets)
+        .concurrency(jobs)
+        .run_each(command, |result| {
+            let name = &result.target.name;
+            match result.output {
+                Ok(output) => {
+                    for line in String::from_utf8_lossy(&output.stdout).lines() {
+                        println!("{name}: {line}");
+                    }
+                    for line in String::from_utf8_lossy(&output.stderr).lines() {
+                        eprintln!("{name}: {line}");
+                    }
+                    if !output.success() {
+                        eprintln!("{name}: exited with status {}", output.status);
+                        failures += 1;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{name}: {e}");
+                    failures += 1;
+                }
+            }
+        });
+    if failures > 0 {
+        log::error!("Command failed on {failures} hosts");
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn proxy(listen: &str, master: &str) -> Result<()> {
+    let listener = UnixListener::bind(listen)?;
+    log::info!("Proxying {listen} to {master}");
+    Proxy::new(master).serve(listener)
+}
+
+#[cfg(feature = "gateway")]
+fn gateway(policy: &str, listen: &str, master: &str) -> Result<()> {
+    let policy = ssh_control::gateway::Policy::load(policy)?;
+    let listener = UnixListener::bind(listen)?;
+    log::info!("Enforcing policy on {listen} for {master}");
+    Proxy::new(master).filter(policy).serve(listener)
+}
+
+/// Runs `command`, or the login shell, in a TTY and exits with its status
+fn shell(control_path: &str, command: &[&str]) -> Result<()> {
+    let mut ctrl = SshControl::new(control_path)?;
+    let status = ctrl.interactive(SshCommand::new(command.join(" ")))?;
+    process::exit(status as i32);
+}
+
+fn run_id(control_path: &str) -> Result<()> {
+    let mut ctrl = SshControl::new(control_path)?;
     let server_pid = ctrl.check_alive()?;
     log::info!("Server pid: {server_pid}");
 
@@ -25,10 +149,11 @@ fn main_helper() -> Result<()> {
     cmd.stderr(Pipe::dev_null()?);
     cmd.stdout(Pipe::new()?);
 
+    // Output of earlier runs is replaced, not overwritten in place
     let mut f = fs::OpenOptions::new()
         .create(true)
         .write(true)
-        .append(false)
+        .truncate(true)
         .open("/tmp/test")?;
 
     let mut child = ctrl.new_session(cmd)?;
@@ -0,0 +1,175 @@
+//! Declarative set of forwards kept open through a master.
+//!
+//! A [`Profile`] is read from a TOML file listing forwards with `ssh` syntax:
+//!
+//! ```toml
+//! forwards = [
+//!     "-L 8080:localhost:80",
+//!     "-L /tmp/docker.sock:/var/run/docker.sock",
+//!     "-R 0:localhost:22",
+//!     "-D 1080",
+//! ]
+//! ```
+//!
+//! Only TOML is read: other formats such as YAML are out of scope, but [`Profile`] implements
+//! [`Deserialize`] for use with any serde format.
+//!
+//! A [`Reconciler`] then opens and closes forwards until those opened through it match the
+//! profile. It only knows the forwards it opened itself, in this process: the mux protocol has no
+//! request listing the forwards of a master, so forwards opened by another client or an earlier
+//! run are neither reused nor closed, and opening one of them again fails while it is still
+//! listening. The forwards of a reconciler are closed when it is dropped, see [`Reconciler`].
+
+use std::{fs, path::Path, str::FromStr};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::{client::OpenFwd, forward::Forward, ControlSocket, Error, Result, SshControl};
+
+fn parse_forwards<'de, D>(deserializer: D) -> std::result::Result<Vec<OpenFwd<'static>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|spec| spec.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    #[serde(deserialize_with = "parse_forwards")]
+    pub forwards: Vec<OpenFwd<'static>>,
+}
+
+impl FromStr for Profile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        toml::from_str(s).map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+}
+
+impl Profile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+}
+
+/// Forward opened while applying a profile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Opened {
+    pub forward: OpenFwd<'static>,
+    /// Port picked by the server for remote forwards requested on port 0
+    pub allocated_port: Option<u16>,
+}
+
+/// Changes made by [`Reconciler::apply`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub opened: Vec<Opened>,
+    pub closed: Vec<OpenFwd<'static>>,
+    /// Number of forwards already open as required
+    pub kept: usize,
+}
+
+/// Keeps the forwards opened through it in line with a [`Profile`]
+///
+/// Dropping the reconciler drops its [`Forward`]s, which are then closed by the next request on
+/// their control connection or when it is dropped: drop the reconciler before its connection, or
+/// call [`Reconciler::close`] to close them right away.
+#[derive(Debug, Default)]
+pub struct Reconciler {
+    forwards: Vec<Forward>,
+}
+
+impl Reconciler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forwards currently open, in the order they were opened
+    pub fn forwards(&self) -> &[Forward] {
+        &self.forwards
+    }
+
+    /// Closes the forwards not in `profile` anymore, then opens the missing ones
+    ///
+    /// On error, the forwards opened or closed so far stay so, and a later call resumes from there.
+    pub fn apply<S>(&mut self, ctrl: &mut SshControl<S>, profile: &Profile) -> Result<Report>
+    where
+        S: ControlSocket,
+    {
+        let mut report = Report::default();
+
+        let (kept, stale): (Vec<_>, Vec<_>) = std::mem::take(&mut self.forwards)
+            .into_iter()
+            .partition(|f| profile.forwards.contains(f.request()));
+        self.forwards = kept;
+        report.kept = self.forwards.len();
+        self.close_forwards(ctrl, stale, &mut report.closed)?;
+
+        for wanted in &profile.forwards {
+            if self.forwards.iter().any(|f| f.request() == wanted) {
+                continue;
+            }
+            let forward = ctrl.open_forward_request(wanted.clone())?;
+            match forward.allocated_port() {
+                Some(port) => log::info!("Opened forward {wanted}, allocated port {port}"),
+                None => log::info!("Opened forward {wanted}"),
+            }
+            report.opened.push(Opened {
+                forward: wanted.clone(),
+                allocated_port: forward.allocated_port(),
+            });
+            self.forwards.push(forward);
+        }
+
+        Ok(report)
+    }
+
+    /// Closes every forward opened through the reconciler, returning them
+    ///
+    /// On error, the forwards not closed yet are kept, except the one whose close failed: if its
+    /// request could not be sent, it is closed with the dropped forwards of `ctrl`.
+    pub fn close<S>(&mut self, ctrl: &mut SshControl<S>) -> Result<Vec<OpenFwd<'static>>>
+    where
+        S: ControlSocket,
+    {
+        let mut closed = Vec::new();
+        let forwards = std::mem::take(&mut self.forwards);
+        self.close_forwards(ctrl, forwards, &mut closed)?;
+        Ok(closed)
+    }
+
+    fn close_forwards<S>(
+        &mut self,
+        ctrl: &mut SshControl<S>,
+        forwards: Vec<Forward>,
+        closed: &mut Vec<OpenFwd<'static>>,
+    ) -> Result<()>
+    where
+        S: ControlSocket,
+    {
+        let mut forwards = forwards.into_iter();
+        while let Some(forward) = forwards.next() {
+            let request = forward.request().clone();
+            if let Err(e) = ctrl.close_forward(forward) {
+                self.forwards.extend(forwards);
+                return Err(e);
+            }
+            log::info!("Closed forward {request}");
+            closed.push(request);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Reconciler {
+    fn drop(&mut self) {
+        for forward in self.forwards.drain(..) {
+            log::info!("Closing forward {}", forward.request());
+        }
+    }
+}
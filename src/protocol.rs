@@ -4,10 +4,12 @@ use std::{
     mem::size_of,
 };
 
+use crate::Error;
+
 use nom::{
     combinator::{map, verify},
     error::context,
-    multi::{length_data, many0},
+    multi::length_data,
     number::streaming::be_u32,
     sequence::{preceded, tuple},
 };
@@ -35,52 +37,33 @@ pub trait Wire<'a>: Sized {
 pub mod client;
 pub mod server;
 mod strings;
-mod utils;
 pub use client::MuxMessage;
+pub(crate) mod utils;
+pub use ssh_control_derive::Wire;
+use utils::many;
 
-const MUX_HELLO: u32 = 0x00000001;
+#[cfg(feature = "proptest")]
+mod arbitrary;
 
-#[derive(Debug)]
+pub(crate) const MUX_HELLO: u32 = 0x00000001;
+
+/// Default upper bound for the body of a received packet (256 KiB)
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Wire)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Extension<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub name: Cow<'a, str>,
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub value: Cow<'a, str>,
 }
 
-impl<'a> Wire<'a> for Extension<'a> {
-    fn parse<E>(input: &'a [u8]) -> nom::IResult<&'a [u8], Self, E>
-    where
-        E: NomError<'a>,
-    {
-        context(
-            "Extension",
-            map(
-                tuple((<Cow<'_, str> as Wire>::parse, <Cow<'_, str> as Wire>::parse)),
-                |(name, value)| Self { name, value },
-            ),
-        )(input)
-    }
-
-    fn serialize<W>(&self, writer: &mut W) -> io::Result<()>
-    where
-        W: Write,
-    {
-        self.name.serialize(writer)?;
-        self.value.serialize(writer)
-    }
-}
-
-impl<'a> Extension<'a> {
-    pub fn into_owned(self) -> Extension<'static> {
-        Extension {
-            name: Cow::Owned(self.name.into_owned()),
-            value: Cow::Owned(self.value.into_owned()),
-        }
-    }
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Hello<'a> {
     pub version: u32,
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub extensions: Vec<Extension<'a>>,
 }
 
@@ -107,13 +90,7 @@ impl<'a> Wire<'a> for Hello<'a> {
             preceded(
                 verify(be_u32, |v| *v == MUX_HELLO),
                 map(
-                    tuple((be_u32, |i: &'a [u8]| {
-                        if i.is_empty() {
-                            Ok((i, Vec::new()))
-                        } else {
-                            many0(Extension::parse)(i)
-                        }
-                    })),
+                    tuple((be_u32, many(Extension::parse))),
                     |(version, extensions)| Self {
                         version,
                         extensions,
@@ -139,34 +116,52 @@ impl<'a> Wire<'a> for Hello<'a> {
 #[derive(Debug)]
 pub struct Packet {
     buffer: Vec<u8>,
+    max_size: usize,
 }
 
 impl From<Vec<u8>> for Packet {
     fn from(buffer: Vec<u8>) -> Self {
-        Self { buffer }
+        Self {
+            buffer,
+            max_size: DEFAULT_MAX_PACKET_SIZE,
+        }
     }
 }
 
 impl Packet {
-    pub fn set<'a, T: Wire<'a>>(&mut self, val: &'a T) {
+    /// Sets the maximum body size accepted by [`Packet::recv_next`]
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn set<'a, T: Wire<'a>>(&mut self, val: &'a T) -> crate::Result<()> {
         const ZERO: [u8; 4] = 0u32.to_be_bytes();
         self.buffer.clear();
         self.buffer.extend_from_slice(&ZERO[..]);
-        val.serialize(&mut self.buffer).unwrap();
-        let size: u32 = self
-            .buffer
-            .len()
-            .checked_sub(ZERO.len())
-            .unwrap()
-            .try_into()
-            .expect("Buffer is over 4GB?!");
-        for (i, b) in size.to_be_bytes().iter().enumerate() {
-            self.buffer[i] = *b;
+        if let Err(e) = val.serialize(&mut self.buffer) {
+            self.buffer.clear();
+            return Err(e.into());
         }
+        let size = self.buffer.len() - ZERO.len();
+        let size: u32 = match size.try_into() {
+            Ok(s) => s,
+            Err(_) => {
+                self.buffer.clear();
+                return Err(Error::PacketTooLarge {
+                    size,
+                    max: u32::MAX as usize,
+                });
+            }
+        };
+        self.buffer[..ZERO.len()].copy_from_slice(&size.to_be_bytes()[..]);
+        Ok(())
     }
 
-    #[allow(clippy::uninit_vec)]
-    fn recv<R>(&mut self, reader: &mut R) -> io::Result<()>
+    fn recv<R>(&mut self, reader: &mut R) -> crate::Result<()>
     where
         R: Read,
     {
@@ -174,14 +169,26 @@ impl Packet {
         reader.read_exact(&mut raw_size[..])?;
         let size = u32::from_be_bytes(raw_size) as usize;
         self.buffer.clear();
+        if size > self.max_size {
+            return Err(Error::PacketTooLarge {
+                size,
+                max: self.max_size,
+            });
+        }
         self.buffer.reserve(size);
         log::debug!("Will received {size} bytes object");
-        unsafe { self.buffer.set_len(size) };
-        if let Err(e) = reader.read_exact(&mut self.buffer[..]) {
-            unsafe { self.buffer.set_len(0) };
-            Err(e)
-        } else {
-            Ok(())
+        // `read_to_end` only hands initialized memory to the reader and reuses the
+        // capacity reserved above.
+        match reader.take(size as u64).read_to_end(&mut self.buffer) {
+            Ok(received) if received == size => Ok(()),
+            Ok(_) => {
+                self.buffer.clear();
+                Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+            }
+            Err(e) => {
+                self.buffer.clear();
+                Err(e.into())
+            }
         }
     }
 
@@ -191,11 +198,31 @@ impl Packet {
         R: Read,
     {
         self.recv(reader)?;
-        let (rest, obj) = T::parse(&self.buffer[..])?;
+        let obj = parse_all(&self.buffer[..])?;
         log::debug!("Received {obj:?}");
-        assert_eq!(rest.len(), 0);
         Ok(obj)
     }
+
+    /// Reads the next packet without parsing it and returns its body
+    pub fn recv_raw<R>(&mut self, reader: &mut R) -> crate::Result<&[u8]>
+    where
+        R: Read,
+    {
+        self.recv(reader)?;
+        Ok(&self.buffer[..])
+    }
+}
+
+/// Parses a whole packet body, failing if some bytes are left over
+pub fn parse_all<'a, T>(body: &'a [u8]) -> crate::Result<T>
+where
+    T: Wire<'a>,
+{
+    let (rest, obj) = T::parse(body)?;
+    if !rest.is_empty() {
+        return Err(Error::TrailingData { len: rest.len() });
+    }
+    Ok(obj)
 }
 
 impl<'a> Wire<'a> for Packet {
@@ -205,8 +232,8 @@ impl<'a> Wire<'a> for Packet {
     {
         context(
             "Packet",
-            map(length_data(be_u32), |buffer: &[u8]| Self {
-                buffer: buffer.to_vec(),
+            map(length_data(be_u32), |buffer: &[u8]| {
+                Self::from(buffer.to_vec())
             }),
         )(input)
     }
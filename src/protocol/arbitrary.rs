@@ -0,0 +1,238 @@
+use std::borrow::Cow;
+
+use proptest::{
+    arbitrary::{any, Arbitrary},
+    collection::vec,
+    prop_oneof,
+    strategy::{BoxedStrategy, Just, Strategy},
+};
+
+use crate::protocol::{
+    client::{self, ForwardingType, MuxMessage, Port},
+    server::{self, MuxResponse},
+    Extension, Hello,
+};
+
+fn string() -> impl Strategy<Value = Cow<'static, str>> {
+    any::<String>().prop_map(Cow::Owned)
+}
+
+impl Arbitrary for ForwardingType {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![Just(Self::Local), Just(Self::Remote), Just(Self::Dynamic)].boxed()
+    }
+}
+
+impl Arbitrary for Port {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![any::<u16>().prop_map(Self::Inet), Just(Self::Unix)].boxed()
+    }
+}
+
+impl Arbitrary for Extension<'static> {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (string(), string())
+            .prop_map(|(name, value)| Self { name, value })
+            .boxed()
+    }
+}
+
+impl Arbitrary for Hello<'static> {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (any::<u32>(), vec(any::<Extension>(), 0..4))
+            .prop_map(|(version, extensions)| Self {
+                version,
+                extensions,
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for client::NewSession<'static> {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (
+            any::<u32>(),
+            any::<[bool; 4]>(),
+            any::<u32>(),
+            string(),
+            string(),
+            vec(string(), 0..4),
+        )
+            .prop_map(
+                |(
+                    request_id,
+                    [want_tty, want_x11_forwarding, want_agent, subsystem],
+                    escape_char,
+                    terminal_type,
+                    command,
+                    environment,
+                )| Self {
+                    request_id,
+                    want_tty,
+                    want_x11_forwarding,
+                    want_agent,
+                    subsystem,
+                    escape_char,
+                    terminal_type,
+                    command,
+                    environment,
+                },
+            )
+            .boxed()
+    }
+}
+
+macro_rules! impl_arbitrary_fwd {
+    ($type:ident) => {
+        impl Arbitrary for client::$type<'static> {
+            type Parameters = ();
+            type Strategy = BoxedStrategy<Self>;
+
+            fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+                (
+                    any::<u32>(),
+                    any::<ForwardingType>(),
+                    string(),
+                    any::<Port>(),
+                    string(),
+                    any::<Port>(),
+                )
+                    .prop_map(
+                        |(
+                            request_id,
+                            forwarding_type,
+                            listen_host,
+                            listen_port,
+                            connect_host,
+                            connect_port,
+                        )| Self {
+                            request_id,
+                            forwarding_type,
+                            listen_host,
+                            listen_port,
+                            connect_host,
+                            connect_port,
+                        },
+                    )
+                    .boxed()
+            }
+        }
+    };
+}
+impl_arbitrary_fwd!(OpenFwd);
+impl_arbitrary_fwd!(CloseFwd);
+
+impl Arbitrary for client::NewStdioFwd<'static> {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        (any::<u32>(), string(), any::<Port>())
+            .prop_map(|(request_id, connect_host, connect_port)| Self {
+                request_id,
+                connect_host,
+                connect_port,
+            })
+            .boxed()
+    }
+}
+
+macro_rules! impl_arbitrary_u32s {
+    ($type:path, $($field:ident),+) => {
+        impl Arbitrary for $type {
+            type Parameters = ();
+            type Strategy = BoxedStrategy<Self>;
+
+            fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+                any::<[u32; [$(stringify!($field)),+].len()]>()
+                    .prop_map(|[$($field),+]| Self { $($field),+ })
+                    .boxed()
+            }
+        }
+    };
+}
+impl_arbitrary_u32s!(client::AliveCheck, request_id);
+impl_arbitrary_u32s!(client::Terminate, request_id);
+impl_arbitrary_u32s!(client::StopListening, request_id);
+impl_arbitrary_u32s!(server::Ok, client_request_id);
+impl_arbitrary_u32s!(server::ExitMessage, session_id, exit_value);
+impl_arbitrary_u32s!(server::Alive, client_request_id, server_pid);
+impl_arbitrary_u32s!(server::SessionOpened, client_request_id, session_id);
+impl_arbitrary_u32s!(
+    server::RemotePort,
+    client_request_id,
+    allocated_remote_listen_port
+);
+impl_arbitrary_u32s!(server::TtyAllocFail, session_id);
+
+macro_rules! impl_arbitrary_reason {
+    ($type:ident) => {
+        impl Arbitrary for server::$type<'static> {
+            type Parameters = ();
+            type Strategy = BoxedStrategy<Self>;
+
+            fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+                (any::<u32>(), string())
+                    .prop_map(|(client_request_id, reason)| Self {
+                        client_request_id,
+                        reason,
+                    })
+                    .boxed()
+            }
+        }
+    };
+}
+impl_arbitrary_reason!(PermissionDenied);
+impl_arbitrary_reason!(Failure);
+
+impl Arbitrary for MuxMessage<'static> {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![
+            any::<client::NewSession>().prop_map(Self::from),
+            any::<client::AliveCheck>().prop_map(Self::from),
+            any::<client::Terminate>().prop_map(Self::from),
+            any::<client::OpenFwd>().prop_map(Self::from),
+            any::<client::CloseFwd>().prop_map(Self::from),
+            any::<client::NewStdioFwd>().prop_map(Self::from),
+            any::<client::StopListening>().prop_map(Self::from),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for MuxResponse<'static> {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: Self::Parameters) -> Self::Strategy {
+        prop_oneof![
+            any::<server::Ok>().prop_map(Self::from),
+            any::<server::PermissionDenied>().prop_map(Self::from),
+            any::<server::Failure>().prop_map(Self::from),
+            any::<server::ExitMessage>().prop_map(Self::from),
+            any::<server::Alive>().prop_map(Self::from),
+            any::<server::SessionOpened>().prop_map(Self::from),
+            any::<server::RemotePort>().prop_map(Self::from),
+            any::<server::TtyAllocFail>().prop_map(Self::from),
+        ]
+        .boxed()
+    }
+}
@@ -24,21 +24,22 @@ pub use open_fwd::OpenFwd;
 pub use stop_listening::StopListening;
 pub use terminate::Terminate;
 
-const NEW_SESSION: u32 = 0x10000002;
-const ALIVE_CHECK: u32 = 0x10000004;
-const TERMINATE: u32 = 0x10000005;
-const OPEN_FWD: u32 = 0x10000006;
-const CLOSE_FWD: u32 = 0x10000007;
-const NEW_STDIO_FWD: u32 = 0x10000008;
-const STOP_LISTENING: u32 = 0x10000009;
-
-const FWD_LOCAL: u32 = 1;
-const FWD_REMOTE: u32 = 2;
-const FWD_DYNAMIC: u32 = 3;
-
-const LISTEN_TYPE_UNIX: u32 = -2i32 as u32;
-
-#[derive(Debug)]
+pub(crate) const NEW_SESSION: u32 = 0x10000002;
+pub(crate) const ALIVE_CHECK: u32 = 0x10000004;
+pub(crate) const TERMINATE: u32 = 0x10000005;
+pub(crate) const OPEN_FWD: u32 = 0x10000006;
+pub(crate) const CLOSE_FWD: u32 = 0x10000007;
+pub(crate) const NEW_STDIO_FWD: u32 = 0x10000008;
+pub(crate) const STOP_LISTENING: u32 = 0x10000009;
+
+pub(crate) const FWD_LOCAL: u32 = 1;
+pub(crate) const FWD_REMOTE: u32 = 2;
+pub(crate) const FWD_DYNAMIC: u32 = 3;
+
+pub(crate) const LISTEN_TYPE_UNIX: u32 = -2i32 as u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u32)]
 pub enum ForwardingType {
     Local,
@@ -74,7 +75,8 @@ impl<'a> Wire<'a> for ForwardingType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Port {
     Inet(u16),
     Unix,
@@ -105,13 +107,18 @@ impl<'a> Wire<'a> for Port {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MuxMessage<'a> {
+    #[cfg_attr(feature = "serde", serde(borrow))]
     NewSession(new_session::NewSession<'a>),
     AliveCheck(alive_check::AliveCheck),
     Terminate(terminate::Terminate),
+    #[cfg_attr(feature = "serde", serde(borrow))]
     OpenFwd(open_fwd::OpenFwd<'a>),
+    #[cfg_attr(feature = "serde", serde(borrow))]
     CloseFwd(close_fwd::CloseFwd<'a>),
+    #[cfg_attr(feature = "serde", serde(borrow))]
     NewStdioFwd(new_stdio_fwd::NewStdioFwd<'a>),
     StopListening(stop_listening::StopListening),
 }
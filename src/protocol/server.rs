@@ -26,19 +26,22 @@ pub use remote_port::RemotePort;
 pub use session_opened::SessionOpened;
 pub use tty_alloc_fail::TtyAllocFail;
 
-const OK: u32 = 0x80000001;
-const PERMISSION_DENIED: u32 = 0x80000002;
-const FAILURE: u32 = 0x80000003;
-const EXIT_MESSAGE: u32 = 0x80000004;
-const ALIVE: u32 = 0x80000005;
-const SESSION_OPENED: u32 = 0x80000006;
-const REMOTE_PORT: u32 = 0x80000007;
-const TTY_ALLOC_FAIL: u32 = 0x80000008;
+pub(crate) const OK: u32 = 0x80000001;
+pub(crate) const PERMISSION_DENIED: u32 = 0x80000002;
+pub(crate) const FAILURE: u32 = 0x80000003;
+pub(crate) const EXIT_MESSAGE: u32 = 0x80000004;
+pub(crate) const ALIVE: u32 = 0x80000005;
+pub(crate) const SESSION_OPENED: u32 = 0x80000006;
+pub(crate) const REMOTE_PORT: u32 = 0x80000007;
+pub(crate) const TTY_ALLOC_FAIL: u32 = 0x80000008;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MuxResponse<'a> {
     Ok(ok::Ok),
+    #[cfg_attr(feature = "serde", serde(borrow))]
     PermissionDenied(permission_denied::PermissionDenied<'a>),
+    #[cfg_attr(feature = "serde", serde(borrow))]
     Failure(failure::Failure<'a>),
     ExitMessage(exit_message::ExitMessage),
     Alive(alive::Alive),
@@ -1,55 +1,12 @@
-use std::{
-    borrow::Cow,
-    io::{self, Write},
-};
+use std::borrow::Cow;
 
-use nom::{combinator::map, error::context, number::streaming::be_u32, sequence::tuple};
+use crate::protocol::{server::MuxResponse, Wire};
 
-use crate::protocol::{server::MuxResponse, NomError, Wire};
-
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Wire)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[wire(message = MuxResponse)]
 pub struct PermissionDenied<'a> {
     pub client_request_id: u32,
+    #[cfg_attr(feature = "serde", serde(borrow))]
     pub reason: Cow<'a, str>,
 }
-
-impl<'a> Wire<'a> for PermissionDenied<'a> {
-    fn parse<E>(input: &'a [u8]) -> nom::IResult<&'a [u8], Self, E>
-    where
-        E: NomError<'a>,
-    {
-        context(
-            "PermissionDenied",
-            map(
-                tuple((be_u32, <Cow<'_, str> as Wire>::parse)),
-                |(client_request_id, reason)| Self {
-                    client_request_id,
-                    reason,
-                },
-            ),
-        )(input)
-    }
-
-    fn serialize<W>(&self, writer: &mut W) -> io::Result<()>
-    where
-        W: Write,
-    {
-        self.client_request_id.serialize(writer)?;
-        self.reason.serialize(writer)
-    }
-}
-
-impl<'a> From<PermissionDenied<'a>> for MuxResponse<'a> {
-    fn from(value: PermissionDenied<'a>) -> Self {
-        Self::PermissionDenied(value)
-    }
-}
-
-impl<'a> PermissionDenied<'a> {
-    pub fn into_owned(self) -> PermissionDenied<'static> {
-        PermissionDenied {
-            client_request_id: self.client_request_id,
-            reason: Cow::Owned(self.reason.into_owned()),
-        }
-    }
-}
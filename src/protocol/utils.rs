@@ -1,6 +1,9 @@
-use std::io::{self, Write};
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+};
 
-use nom::{combinator::map, multi::many0, number::streaming::be_u32};
+use nom::{bytes::streaming::tag, combinator::map, number::streaming::be_u32};
 
 use crate::protocol::{NomError, Wire};
 
@@ -42,16 +45,80 @@ impl<'a> Wire<'a> for bool {
     }
 }
 
+/// Applies `f` until `input` is exhausted, as lists are not length-prefixed but run up to the
+/// end of the packet.
 pub fn many<'a, O, E, F>(mut f: F) -> impl FnMut(&'a [u8]) -> nom::IResult<&'a [u8], Vec<O>, E>
 where
     F: nom::Parser<&'a [u8], O, E>,
     E: NomError<'a>,
 {
-    move |input: &'a [u8]| {
-        if input.is_empty() {
-            Ok((input, Vec::new()))
-        } else {
-            many0(|i| f.parse(i))(input)
+    move |mut input: &'a [u8]| {
+        let mut items = Vec::new();
+        while !input.is_empty() {
+            let (rest, item) = f.parse(input)?;
+            if rest.len() == input.len() {
+                return Err(nom::Err::Error(E::from_error_kind(
+                    input,
+                    nom::error::ErrorKind::Many0,
+                )));
+            }
+            items.push(item);
+            input = rest;
+        }
+        Ok((input, items))
+    }
+}
+
+/// Applies `f` until `terminator` is found, consuming it.
+pub fn many_terminated<'a, O, E, F>(
+    mut f: F,
+    terminator: &'static [u8],
+) -> impl FnMut(&'a [u8]) -> nom::IResult<&'a [u8], Vec<O>, E>
+where
+    F: nom::Parser<&'a [u8], O, E>,
+    E: NomError<'a>,
+{
+    move |mut input: &'a [u8]| {
+        let mut items = Vec::new();
+        loop {
+            if let Ok((rest, _)) = tag::<_, _, E>(terminator)(input) {
+                return Ok((rest, items));
+            }
+            let (rest, item) = f.parse(input)?;
+            if rest.len() == input.len() {
+                return Err(nom::Err::Error(E::from_error_kind(
+                    input,
+                    nom::error::ErrorKind::Many0,
+                )));
+            }
+            items.push(item);
+            input = rest;
         }
     }
 }
+
+/// Conversion of borrowed fields into their `'static` counterpart, used by `#[derive(Wire)]`
+pub trait IntoOwned {
+    type Owned: 'static;
+
+    fn into_owned(self) -> Self::Owned;
+}
+
+impl<'a> IntoOwned for Cow<'a, str> {
+    type Owned = Cow<'static, str>;
+
+    fn into_owned(self) -> Cow<'static, str> {
+        Cow::Owned(Cow::into_owned(self))
+    }
+}
+
+impl<T> IntoOwned for Vec<T>
+where
+    T: IntoOwned,
+{
+    type Owned = Vec<T::Owned>;
+
+    fn into_owned(self) -> Self::Owned {
+        self.into_iter().map(IntoOwned::into_owned).collect()
+    }
+}
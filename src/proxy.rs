@@ -0,0 +1,349 @@
+//! Transparent proxy in front of a master socket.
+//!
+//! Every connection accepted by the proxy gets its own connection to the master. Packets are
+//! relayed unchanged in both directions, along with the file descriptors passed with
+//! `NewSession` and `NewStdioFwd` requests. Requests are decoded and logged, and may be refused
+//! by a [`Filter`], in which case the client receives a `PermissionDenied` response. Requests the
+//! proxy cannot decode, including message types it does not know, are refused the same way
+//! without being seen by the filter: only requests the filter approved reach the master.
+
+use std::{
+    fmt,
+    io::{self, Write},
+    mem::{size_of, MaybeUninit},
+    net::Shutdown,
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+};
+
+use passfd::FdPassingExt;
+
+use crate::{
+    client, parse_all, server, Error, Hello, MuxMessage, MuxResponse, Packet, Result, Wire,
+};
+
+/// Credentials of the process connected to the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peer {
+    pub pid: libc::pid_t,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl Peer {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn of(socket: &UnixStream) -> io::Result<Self> {
+        let mut cred: MaybeUninit<libc::ucred> = MaybeUninit::uninit();
+        let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                cred.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        if ret == 0 {
+            let cred = unsafe { cred.assume_init() };
+            Ok(Self {
+                pid: cred.pid,
+                uid: cred.uid,
+                gid: cred.gid,
+            })
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn of(_socket: &UnixStream) -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid={} uid={} gid={}", self.pid, self.uid, self.gid)
+    }
+}
+
+/// Decision taken by a [`Filter`] on a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Forward,
+    Deny(String),
+}
+
+pub trait Filter: Send + Sync {
+    fn check(&self, peer: Option<&Peer>, request: &MuxMessage<'_>) -> Verdict;
+}
+
+impl<F> Filter for F
+where
+    F: Fn(Option<&Peer>, &MuxMessage<'_>) -> Verdict + Send + Sync,
+{
+    fn check(&self, peer: Option<&Peer>, request: &MuxMessage<'_>) -> Verdict {
+        self(peer, request)
+    }
+}
+
+/// Forwards everything
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl Filter for AllowAll {
+    fn check(&self, _peer: Option<&Peer>, _request: &MuxMessage<'_>) -> Verdict {
+        Verdict::Forward
+    }
+}
+
+/// Number of file descriptors the client passes after `request`
+pub fn passed_fds(request: &MuxMessage<'_>) -> usize {
+    match request {
+        MuxMessage::NewSession(_) => 3,
+        MuxMessage::NewStdioFwd(_) => 2,
+        _ => 0,
+    }
+}
+
+/// Type and request id heading a request body, which can be read even if the rest is invalid
+fn request_header(body: &[u8]) -> Option<(u32, u32)> {
+    let field = |at: usize| Some(u32::from_be_bytes(body.get(at..at + 4)?.try_into().ok()?));
+    Some((field(0)?, field(4)?))
+}
+
+/// Why the undecodable request of type `message_type` is refused, and how many descriptors the
+/// client passes after it
+fn undecodable(message_type: u32, error: &Error) -> (String, usize) {
+    match message_type {
+        client::NEW_SESSION => (format!("Malformed NewSession request: {error}"), 3),
+        client::NEW_STDIO_FWD => (format!("Malformed NewStdioFwd request: {error}"), 2),
+        client::ALIVE_CHECK
+        | client::TERMINATE
+        | client::OPEN_FWD
+        | client::CLOSE_FWD
+        | client::STOP_LISTENING => (format!("Malformed request: {error}"), 0),
+        message_type => (format!("Unsupported request type 0x{message_type:08x}"), 0),
+    }
+}
+
+fn lock_client(client: &Mutex<UnixStream>) -> io::Result<MutexGuard<'_, UnixStream>> {
+    client
+        .lock()
+        .map_err(|_| io::Error::other("client writer poisoned"))
+}
+
+fn deny(client: &Mutex<UnixStream>, request_id: u32, reason: String) -> Result<()> {
+    let response: MuxResponse = server::PermissionDenied {
+        client_request_id: request_id,
+        reason: reason.into(),
+    }
+    .into();
+    let mut raw = Vec::new();
+    response.serialize(&mut raw)?;
+    write_packet(&mut *lock_client(client)?, &raw[..])?;
+    Ok(())
+}
+
+fn write_packet<W>(writer: &mut W, body: &[u8]) -> io::Result<()>
+where
+    W: Write,
+{
+    let size: u32 = body
+        .len()
+        .try_into()
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let mut raw = Vec::with_capacity(size_of::<u32>() + body.len());
+    raw.extend_from_slice(&size.to_be_bytes()[..]);
+    raw.extend_from_slice(body);
+    writer.write_all(&raw[..])
+}
+
+fn close_fd(fd: RawFd) {
+    if unsafe { libc::close(fd) } < 0 {
+        log::warn!("Could not close passed fd {fd}");
+    }
+}
+
+pub struct Proxy {
+    master: PathBuf,
+    filter: Arc<dyn Filter>,
+}
+
+impl fmt::Debug for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Proxy")
+            .field("master", &self.master)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Proxy {
+    pub fn new(master: impl Into<PathBuf>) -> Self {
+        Self {
+            master: master.into(),
+            filter: Arc::new(AllowAll),
+        }
+    }
+
+    pub fn filter(&mut self, filter: impl Filter + 'static) -> &mut Self {
+        self.filter = Arc::new(filter);
+        self
+    }
+
+    /// Accepts connections forever, handling each of them in its own thread
+    pub fn serve(&self, listener: UnixListener) -> Result<()> {
+        for client in listener.incoming() {
+            let client = client?;
+            let master = self.master.clone();
+            let filter = Arc::clone(&self.filter);
+            thread::spawn(move || {
+                if let Err(e) = handle(client, master, filter) {
+                    log::warn!("Proxied connection failed: {e}");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Relays a single client connection until either side closes it
+    pub fn handle(&self, client: UnixStream) -> Result<()> {
+        handle(client, self.master.clone(), Arc::clone(&self.filter))
+    }
+}
+
+fn handle(client: UnixStream, master: PathBuf, filter: Arc<dyn Filter>) -> Result<()> {
+    let peer = match Peer::of(&client) {
+        Ok(peer) => Some(peer),
+        Err(e) => {
+            log::warn!("Could not get peer credentials: {e}");
+            None
+        }
+    };
+    let who = peer.map_or_else(|| "unknown peer".to_owned(), |p| p.to_string());
+    let master = UnixStream::connect(master)?;
+    log::info!("{who}: connected");
+
+    let client_writer = Arc::new(Mutex::new(client.try_clone()?));
+    let responses = {
+        let master = master.try_clone()?;
+        let client_writer = Arc::clone(&client_writer);
+        let who = who.clone();
+        thread::spawn(move || relay_responses(master, client_writer, &who))
+    };
+
+    let result = relay_requests(
+        client,
+        &master,
+        &client_writer,
+        peer.as_ref(),
+        &who,
+        &*filter,
+    );
+    let _ = master.shutdown(Shutdown::Both);
+    let relayed = responses.join();
+    log::info!("{who}: disconnected");
+    match relayed {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::debug!("{who}: response relay stopped: {e}"),
+        Err(_) => return Err(io::Error::other("response relay panicked").into()),
+    }
+    result
+}
+
+fn relay_requests(
+    mut client: UnixStream,
+    mut master: &UnixStream,
+    client_writer: &Mutex<UnixStream>,
+    peer: Option<&Peer>,
+    who: &str,
+    filter: &dyn Filter,
+) -> Result<()> {
+    let mut packet: Packet = Vec::with_capacity(1024).into();
+    let body = packet.recv_raw(&mut client)?;
+    let hello: Hello = parse_all(body)?;
+    log::debug!("{who}: hello {hello:?}");
+    write_packet(&mut master, body)?;
+
+    loop {
+        let body = match packet.recv_raw(&mut client) {
+            Ok(body) => body,
+            Err(crate::Error::IO(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let request: MuxMessage = match parse_all(body) {
+            Ok(request) => request,
+            Err(e) => {
+                let Some((message_type, request_id)) = request_header(body) else {
+                    return Err(Error::InvalidPacket {
+                        description: format!("Request of {} bytes has no header", body.len())
+                            .into(),
+                    });
+                };
+                let (reason, fds) = undecodable(message_type, &e);
+                log::warn!("{who}: denied undecodable request: {reason}");
+                // Descriptors are received so that they do not desynchronize the stream
+                for _ in 0..fds {
+                    close_fd(client.recv_fd()?);
+                }
+                deny(client_writer, request_id, reason)?;
+                continue;
+            }
+        };
+        let fds = (0..passed_fds(&request))
+            .map(|_| client.recv_fd())
+            .collect::<io::Result<Vec<_>>>()?;
+
+        match filter.check(peer, &request) {
+            Verdict::Forward => {
+                log::info!("{who}: {request:?}");
+                write_packet(&mut master, body)?;
+                for fd in &fds {
+                    master.send_fd_with_payload(*fd, &[0u8][..])?;
+                }
+            }
+            Verdict::Deny(reason) => {
+                log::warn!("{who}: denied {request:?}: {reason}");
+                deny(client_writer, request.get_request_id(), reason)?;
+            }
+        }
+        fds.into_iter().for_each(close_fd);
+    }
+}
+
+fn relay_responses(
+    mut master: UnixStream,
+    client: Arc<Mutex<UnixStream>>,
+    who: &str,
+) -> Result<()> {
+    let mut packet: Packet = Vec::with_capacity(1024).into();
+    let body = packet.recv_raw(&mut master)?;
+    let hello: Hello = parse_all(body)?;
+    log::debug!("{who}: master hello {hello:?}");
+    write_packet(&mut *lock_client(&client)?, body)?;
+
+    let result = loop {
+        let body = match packet.recv_raw(&mut master) {
+            Ok(body) => body,
+            Err(e) => break Err(e),
+        };
+        match parse_all::<MuxResponse>(body) {
+            Ok(response) => log::debug!("{who}: {response:?}"),
+            Err(e) => log::error!("{who}: forwarding undecodable response: {e}"),
+        }
+        if let Err(e) = lock_client(&client).and_then(|mut client| write_packet(&mut *client, body))
+        {
+            break Err(e.into());
+        }
+    };
+    if let Ok(client) = lock_client(&client) {
+        let _ = client.shutdown(Shutdown::Both);
+    }
+    result
+}
@@ -0,0 +1,406 @@
+//! Recording of the traffic exchanged with a master, and replay of such recordings.
+//!
+//! Recordings are stored as JSON lines, one [`Record`] per framed packet.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    mem::size_of,
+    os::unix::{
+        io::RawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use passfd::FdPassingExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::RawBytes, parse_all, ControlSocket, Error, Hello, MuxMessage, MuxResponse, Result,
+    DEFAULT_MAX_PACKET_SIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    /// From the client to the master
+    Sent,
+    /// From the master to the client
+    Received,
+}
+
+/// Decoded content of a recorded packet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+    #[serde(deserialize_with = "owned::hello")]
+    Hello(Hello<'static>),
+    #[serde(deserialize_with = "owned::request")]
+    Request(MuxMessage<'static>),
+    #[serde(deserialize_with = "owned::response")]
+    Response(MuxResponse<'static>),
+}
+
+mod owned {
+    //! Deserialization of messages that are not borrowed from the input
+
+    use serde::{Deserialize, Deserializer};
+
+    use crate::{Hello, MuxMessage, MuxResponse};
+
+    macro_rules! owned {
+        ($name:ident, $type:ident) => {
+            pub fn $name<'de, D>(deserializer: D) -> Result<$type<'static>, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                $type::deserialize(deserializer).map($type::into_owned)
+            }
+        };
+    }
+    owned!(hello, Hello);
+    owned!(request, MuxMessage);
+    owned!(response, MuxResponse);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub direction: Direction,
+    pub timestamp: SystemTime,
+    /// Packet as seen on the socket, length included
+    #[serde(with = "hex")]
+    pub packet: Vec<u8>,
+    /// `None` if the packet could not be parsed
+    pub message: Option<Message>,
+    /// Number of file descriptors passed right after this packet
+    pub fds: usize,
+}
+
+impl Record {
+    fn new(direction: Direction, packet: Vec<u8>, after_hello: bool) -> Self {
+        let body = &packet[size_of::<u32>()..];
+        let message = match (direction, after_hello) {
+            (_, false) => parse_all(body)
+                .ok()
+                .map(|m: Hello| Message::Hello(m.into_owned())),
+            (Direction::Sent, true) => parse_all(body)
+                .ok()
+                .map(|m: MuxMessage| Message::Request(m.into_owned())),
+            (Direction::Received, true) => parse_all(body)
+                .ok()
+                .map(|m: MuxResponse| Message::Response(m.into_owned())),
+        };
+        Self {
+            direction,
+            timestamp: SystemTime::now(),
+            packet,
+            message,
+            fds: 0,
+        }
+    }
+}
+
+mod hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use crate::error::RawBytes;
+
+    pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(&format_args!("{:?}", RawBytes(bytes)))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = <&str>::deserialize(deserializer)?;
+        let digits = s
+            .strip_prefix("0x")
+            .ok_or_else(|| D::Error::custom("missing 0x prefix"))?;
+        if digits.len() % 2 != 0 {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        digits
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                let digit = |c: u8| char::from(c).to_digit(16);
+                match (digit(pair[0]), digit(pair[1])) {
+                    (Some(high), Some(low)) => Ok((high << 4 | low) as u8),
+                    _ => Err(D::Error::custom("invalid hex digit")),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Splits a byte stream into framed packets
+#[derive(Debug)]
+struct Framer {
+    buffer: Vec<u8>,
+    seen_hello: bool,
+    max_size: usize,
+}
+
+impl Default for Framer {
+    fn default() -> Self {
+        Self {
+            buffer: Vec::new(),
+            seen_hello: false,
+            max_size: DEFAULT_MAX_PACKET_SIZE,
+        }
+    }
+}
+
+impl Framer {
+    /// Frames the packets completed by `data`, failing for a packet body over `max_size` before
+    /// buffering it
+    fn feed(&mut self, data: &[u8], direction: Direction) -> io::Result<Vec<Record>> {
+        self.buffer.extend_from_slice(data);
+        let mut records = Vec::new();
+        while let Some(raw_size) = self.buffer.get(..size_of::<u32>()) {
+            let size = u32::from_be_bytes(raw_size.try_into().unwrap()) as usize;
+            if size > self.max_size {
+                self.buffer.clear();
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    Error::PacketTooLarge {
+                        size,
+                        max: self.max_size,
+                    },
+                ));
+            }
+            let len = size_of::<u32>() + size;
+            if self.buffer.len() < len {
+                break;
+            }
+            let rest = self.buffer.split_off(len);
+            let packet = std::mem::replace(&mut self.buffer, rest);
+            records.push(Record::new(direction, packet, self.seen_hello));
+            self.seen_hello = true;
+        }
+        Ok(records)
+    }
+}
+
+/// Wraps a [`ControlSocket`] and writes every packet going through it to `output`.
+///
+/// Records are written once the next packet is seen, so that the file descriptors passed after a
+/// packet are accounted for. Remaining records are written when the recorder is dropped.
+pub struct Recorder<S, W = BufWriter<fs::File>>
+where
+    W: Write,
+{
+    inner: S,
+    output: W,
+    outgoing: Framer,
+    incoming: Framer,
+    pending: Option<Record>,
+}
+
+impl<S> Recorder<S> {
+    pub fn create(inner: S, path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = fs::File::create(path)?;
+        Ok(Self::new(inner, BufWriter::new(file)))
+    }
+}
+
+impl<S, W> Recorder<S, W>
+where
+    W: Write,
+{
+    pub fn new(inner: S, output: W) -> Self {
+        Self {
+            inner,
+            output,
+            outgoing: Framer::default(),
+            incoming: Framer::default(),
+            pending: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Sets the maximum body size of the packets framed in both directions
+    ///
+    /// Traffic with a larger packet fails with [`io::ErrorKind::InvalidData`], like
+    /// [`SshControl::set_max_packet_size`](crate::SshControl::set_max_packet_size) does.
+    pub fn set_max_packet_size(&mut self, max_size: usize) {
+        self.outgoing.max_size = max_size;
+        self.incoming.max_size = max_size;
+    }
+
+    fn push(&mut self, records: Vec<Record>) -> io::Result<()> {
+        for record in records {
+            if let Some(previous) = self.pending.replace(record) {
+                self.write_record(&previous)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        serde_json::to_writer(&mut self.output, record)?;
+        self.output.write_all(b"\n")?;
+        self.output.flush()
+    }
+
+    /// Writes the pending record, if any
+    pub fn flush_records(&mut self) -> io::Result<()> {
+        match self.pending.take() {
+            Some(record) => self.write_record(&record),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S, W> Drop for Recorder<S, W>
+where
+    W: Write,
+{
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_records() {
+            log::warn!("Could not write last record: {e}");
+        }
+    }
+}
+
+impl<S, W> Read for Recorder<S, W>
+where
+    S: Read,
+    W: Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let records = self.incoming.feed(&buf[..n], Direction::Received)?;
+        self.push(records)?;
+        Ok(n)
+    }
+}
+
+impl<S, W> Write for Recorder<S, W>
+where
+    S: Write,
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let records = self.outgoing.feed(&buf[..n], Direction::Sent)?;
+        self.push(records)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S, W> ControlSocket for Recorder<S, W>
+where
+    S: ControlSocket,
+    W: Write,
+{
+    fn send_fd(&mut self, fd: RawFd) -> io::Result<()> {
+        self.inner.send_fd(fd)?;
+        match self.pending {
+            Some(ref mut record) if record.direction == Direction::Sent => record.fds += 1,
+            _ => log::warn!("File descriptor {fd} passed without a request"),
+        }
+        Ok(())
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+/// Reads a recording written by a [`Recorder`]
+pub fn read_records<R>(reader: R) -> Result<Vec<Record>>
+where
+    R: Read,
+{
+    let mut records = Vec::new();
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line).map_err(io::Error::from)?);
+    }
+    Ok(records)
+}
+
+/// Fake master playing back a recording.
+///
+/// Packets the client sent are expected byte for byte, packets the master sent are written back.
+#[derive(Debug, Clone)]
+pub struct Replayer {
+    records: Vec<Record>,
+}
+
+impl Replayer {
+    pub fn new(records: Vec<Record>) -> Self {
+        Self { records }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(read_records(fs::File::open(path)?)?))
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Plays the recording on an accepted connection, then waits for the client to disconnect
+    pub fn serve(&self, socket: &mut UnixStream) -> Result<()> {
+        for (index, record) in self.records.iter().enumerate() {
+            match record.direction {
+                Direction::Received => socket.write_all(&record.packet[..])?,
+                Direction::Sent => {
+                    let mut packet = vec![0u8; record.packet.len()];
+                    socket.read_exact(&mut packet[..])?;
+                    if packet != record.packet {
+                        return Err(Error::InvalidPacket {
+                            description: format!(
+                                "record {index}: expected {:?}, received {:?}",
+                                RawBytes(&record.packet),
+                                RawBytes(&packet)
+                            )
+                            .into(),
+                        });
+                    }
+                    for _ in 0..record.fds {
+                        let fd = socket.recv_fd()?;
+                        if unsafe { libc::close(fd) } < 0 {
+                            log::warn!("Could not close passed fd {fd}");
+                        }
+                    }
+                }
+            }
+        }
+
+        // Wait for the client to hang up, it must not send anything more
+        let mut extra = [0u8; 1];
+        match socket.read(&mut extra[..])? {
+            0 => Ok(()),
+            _ => Err(Error::InvalidPacket {
+                description: "data received after the end of the recording".into(),
+            }),
+        }
+    }
+
+    /// Listens on `path` and plays the recording to the first client connecting
+    pub fn spawn(self, path: impl AsRef<Path>) -> io::Result<thread::JoinHandle<Result<()>>> {
+        let listener = UnixListener::bind(path)?;
+        Ok(thread::spawn(move || {
+            let (mut socket, _) = listener.accept()?;
+            self.serve(&mut socket)
+        }))
+    }
+}
@@ -0,0 +1,239 @@
+//! Remote file system operations, each one running a small POSIX shell script in a session.
+//!
+//! Failures are reported as [`Error::RemoteFs`] with an [`io::ErrorKind`] telling the usual cases
+//! apart. Scripts check for those cases themselves and exit with a dedicated status, falling back
+//! to the messages of the failing command otherwise.
+
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    command::{quote, Output, Pipe, SshCommand},
+    Error, Result, SshControl,
+};
+
+// Exit statuses of the scripts for the errors they detect
+const NOT_FOUND: u32 = 100;
+const PERMISSION_DENIED: u32 = 101;
+const NOT_A_DIRECTORY: u32 = 102;
+const IS_A_DIRECTORY: u32 = 103;
+
+/// Defines `fail STATUS MESSAGE`, and `exists PATH` which fails with [`NOT_FOUND`] unless `PATH`
+/// exists, even as a dangling symlink
+const PRELUDE: &str = "fail() { echo \"$2\" >&2; exit \"$1\"; }; \
+    exists() { [ -e \"$1\" ] || [ -L \"$1\" ] || fail 100 'No such file or directory'; }; ";
+
+// File types in the mode, with the same values everywhere
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Metadata returned by [`RemoteFs::stat`], which follows symlinks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Mode bits, including the file type
+    pub mode: u32,
+    pub size: u64,
+    pub uid: u32,
+    pub gid: u32,
+    /// Modification time in seconds since the epoch
+    pub mtime: i64,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    /// Permission bits, without the file type
+    pub fn permissions(&self) -> u32 {
+        self.mode & 0o7777
+    }
+
+    /// Parses `mode size uid gid mtime`, the mode being in hexadecimal
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let mode = fields.next()?;
+        let mode = u32::from_str_radix(mode.strip_prefix("0x").unwrap_or(mode), 16).ok()?;
+        let mut next = || fields.next()?.parse::<i64>().ok();
+        Some(Self {
+            mode,
+            size: next()? as u64,
+            uid: next()? as u32,
+            gid: next()? as u32,
+            mtime: next()?,
+        })
+    }
+}
+
+/// File system of the host behind a master
+///
+/// OpenSSH masters close the control connection when a session ends, so every operation connects
+/// to the master again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteFs {
+    control_path: PathBuf,
+}
+
+impl RemoteFs {
+    pub fn new(control_path: impl Into<PathBuf>) -> Self {
+        Self {
+            control_path: control_path.into(),
+        }
+    }
+
+    pub fn control_path(&self) -> &Path {
+        &self.control_path
+    }
+
+    /// Runs `script` after [`PRELUDE`], feeding it `input`
+    fn session(&self, script: &str, input: &[u8]) -> Result<Output> {
+        let mut ctrl = SshControl::new(&self.control_path)?;
+        let mut command = SshCommand::new(format!("{PRELUDE}{script}"));
+        command.stdin(Pipe::new()?);
+        command.stdout(Pipe::new()?);
+        command.stderr(Pipe::new()?);
+        let mut child = ctrl.new_session(command)?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let written = stdin.write_all(input);
+        drop(stdin);
+
+        let output = ctrl.wait_with_output(child)?;
+        // The script failing explains why it stopped reading its input
+        if output.success() {
+            written?;
+        }
+        Ok(output)
+    }
+
+    /// Like [`session`](Self::session), turning failures into errors about `path`
+    fn run(&self, path: &str, script: &str, input: &[u8]) -> Result<Output> {
+        let output = self.session(script, input)?;
+        if output.success() {
+            Ok(output)
+        } else {
+            Err(failure(path, &output))
+        }
+    }
+
+    /// Whether `path` exists, a dangling symlink counting as existing
+    pub fn exists(&self, path: &str) -> Result<bool> {
+        let quoted = quote(path);
+        let output = self.session(&format!("[ -e {quoted} ] || [ -L {quoted} ]"), b"")?;
+        match output.status {
+            0 => Ok(true),
+            1 => Ok(false),
+            _ => Err(failure(path, &output)),
+        }
+    }
+
+    /// Reads the content of the file at `path`
+    pub fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let quoted = quote(path);
+        let script = format!(
+            "exists {quoted}; \
+             [ -d {quoted} ] && fail {IS_A_DIRECTORY} 'Is a directory'; \
+             [ -r {quoted} ] || fail {PERMISSION_DENIED} 'Permission denied'; \
+             exec cat < {quoted}"
+        );
+        Ok(self.run(path, &script, b"")?.stdout)
+    }
+
+    /// Reads the content of the file at `path`, which must be valid UTF-8
+    pub fn read_to_string(&self, path: &str) -> Result<String> {
+        String::from_utf8(self.read(path)?).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{path} does not contain valid UTF-8"),
+            )
+            .into()
+        })
+    }
+
+    /// Replaces the file at `path` with `content` and `mode` permissions
+    ///
+    /// The content is written to a temporary file in the same directory, which is then renamed
+    /// over `path`: readers see either the old or the new content, never a partial file.
+    pub fn write_atomic(&self, path: &str, content: &[u8], mode: u32) -> Result<()> {
+        let quoted = quote(path);
+        let (dir, name) = match path.rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some((dir, name)) => (dir, name),
+            None => (".", path),
+        };
+        let dir = quote(dir);
+        let template = quote(name);
+        let script = format!(
+            "exists {dir}; [ -d {dir} ] || fail {NOT_A_DIRECTORY} 'Parent is not a directory'; \
+             [ -d {quoted} ] && fail {IS_A_DIRECTORY} 'Is a directory'; \
+             tmp=$(mktemp {dir}/.{template}.XXXXXX) || exit; \
+             trap 'rm -f -- \"$tmp\"' EXIT; \
+             cat > \"$tmp\" && chmod {mode:o} -- \"$tmp\" && mv -f -- \"$tmp\" {quoted} && trap - EXIT"
+        );
+        self.run(path, &script, content).map(drop)
+    }
+
+    /// Creates the directory at `path` and its missing parents
+    pub fn mkdir_p(&self, path: &str) -> Result<()> {
+        self.run(path, &format!("mkdir -p -- {}", quote(path)), b"")
+            .map(drop)
+    }
+
+    /// Sets the permissions of `path` to `mode`
+    pub fn chmod(&self, path: &str, mode: u32) -> Result<()> {
+        let quoted = quote(path);
+        let script = format!("exists {quoted}; chmod {mode:o} -- {quoted}");
+        self.run(path, &script, b"").map(drop)
+    }
+
+    /// Metadata of `path`, following symlinks
+    pub fn stat(&self, path: &str) -> Result<Metadata> {
+        let quoted = quote(path);
+        // GNU stat, or BSD stat whose -f option would mean something else to GNU stat
+        let script = format!(
+            "exists {quoted}; \
+             if stat -c %s -- / >/dev/null 2>&1; \
+             then exec stat -L -c '%f %s %u %g %Y' -- {quoted}; \
+             else exec stat -L -f '%Xp %z %u %g %m' -- {quoted}; fi"
+        );
+        let output = self.run(path, &script, b"")?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Metadata::parse(&stdout).ok_or_else(|| Error::InvalidPacket {
+            description: format!("Unexpected stat output {stdout:?}").into(),
+        })
+    }
+}
+
+/// Error for a failed script, classified by its exit status, or else its error message
+fn failure(path: &str, output: &Output) -> Error {
+    let message = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    let kind = match output.status {
+        NOT_FOUND => io::ErrorKind::NotFound,
+        PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+        NOT_A_DIRECTORY => io::ErrorKind::NotADirectory,
+        IS_A_DIRECTORY => io::ErrorKind::IsADirectory,
+        _ if message.contains("No such file or directory") => io::ErrorKind::NotFound,
+        _ if message.contains("Permission denied") => io::ErrorKind::PermissionDenied,
+        _ if message.contains("Operation not permitted") => io::ErrorKind::PermissionDenied,
+        _ if message.contains("File exists") => io::ErrorKind::AlreadyExists,
+        _ if message.contains("Not a directory") => io::ErrorKind::NotADirectory,
+        _ if message.contains("Is a directory") => io::ErrorKind::IsADirectory,
+        _ => io::ErrorKind::Other,
+    };
+    Error::RemoteFs {
+        kind,
+        path: path.to_owned(),
+        message,
+    }
+}
@@ -0,0 +1,640 @@
+//! SFTP version 3 client, running over the `sftp` subsystem of a session.
+//!
+//! The protocol is described in
+//! [draft-ietf-secsh-filexfer-02](https://datatracker.ietf.org/doc/html/draft-ietf-secsh-filexfer-02),
+//! which OpenSSH implements. Requests are sent one at a time, each waiting for its response.
+
+use std::{
+    fmt,
+    io::{Read, Write},
+    ops::BitOr,
+};
+
+use nom::{
+    combinator::{cond, map, opt},
+    error::{context, VerboseError},
+    multi::{count, length_data, many0},
+    number::complete::{be_u32, be_u64, be_u8},
+    sequence::{pair, tuple},
+    IResult,
+};
+
+use crate::{
+    command::{Pipe, PipeRead, PipeWrite, SshCommand},
+    ControlSocket, Error, Result, SshControl, DEFAULT_MAX_PACKET_SIZE,
+};
+
+const VERSION: u32 = 3;
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_WRITE: u8 = 6;
+const SSH_FXP_LSTAT: u8 = 7;
+const SSH_FXP_FSTAT: u8 = 8;
+const SSH_FXP_SETSTAT: u8 = 9;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_REMOVE: u8 = 13;
+const SSH_FXP_MKDIR: u8 = 14;
+const SSH_FXP_RMDIR: u8 = 15;
+const SSH_FXP_REALPATH: u8 = 16;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_RENAME: u8 = 18;
+const SSH_FXP_READLINK: u8 = 19;
+const SSH_FXP_SYMLINK: u8 = 20;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x00000001;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x00000002;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x00000004;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x00000008;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x80000000;
+
+// File types in permissions, with the same values everywhere
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Largest read requested at once, which every server accepts
+const READ_SIZE: u32 = 32 * 1024;
+
+/// Status code of a failed request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCode {
+    Eof,
+    NoSuchFile,
+    PermissionDenied,
+    Failure,
+    BadMessage,
+    NoConnection,
+    ConnectionLost,
+    OpUnsupported,
+    Other(u32),
+}
+
+impl From<u32> for StatusCode {
+    fn from(code: u32) -> Self {
+        match code {
+            1 => Self::Eof,
+            2 => Self::NoSuchFile,
+            3 => Self::PermissionDenied,
+            4 => Self::Failure,
+            5 => Self::BadMessage,
+            6 => Self::NoConnection,
+            7 => Self::ConnectionLost,
+            8 => Self::OpUnsupported,
+            code => Self::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Eof => f.write_str("end of file"),
+            Self::NoSuchFile => f.write_str("no such file"),
+            Self::PermissionDenied => f.write_str("permission denied"),
+            Self::Failure => f.write_str("failure"),
+            Self::BadMessage => f.write_str("bad message"),
+            Self::NoConnection => f.write_str("no connection"),
+            Self::ConnectionLost => f.write_str("connection lost"),
+            Self::OpUnsupported => f.write_str("operation unsupported"),
+            Self::Other(code) => write!(f, "status {code}"),
+        }
+    }
+}
+
+/// Flags of [`Sftp::open`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(pub u32);
+
+impl OpenFlags {
+    pub const READ: Self = Self(0x01);
+    pub const WRITE: Self = Self(0x02);
+    pub const APPEND: Self = Self(0x04);
+    pub const CREATE: Self = Self(0x08);
+    pub const TRUNCATE: Self = Self(0x10);
+    pub const EXCLUDE: Self = Self(0x20);
+}
+
+impl BitOr for OpenFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Name and data of extensions
+pub type Extensions = Vec<(String, Vec<u8>)>;
+
+/// File attributes, each one being optional
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attributes {
+    pub size: Option<u64>,
+    pub uid_gid: Option<(u32, u32)>,
+    /// Mode bits, including the file type
+    pub permissions: Option<u32>,
+    pub atime_mtime: Option<(u32, u32)>,
+    pub extended: Extensions,
+}
+
+impl Attributes {
+    fn file_type(&self) -> Option<u32> {
+        self.permissions.map(|p| p & S_IFMT)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == Some(S_IFDIR)
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type() == Some(S_IFREG)
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type() == Some(S_IFLNK)
+    }
+
+    fn parse(input: &[u8]) -> IResult<&[u8], Self, VerboseError<&[u8]>> {
+        let (input, flags) = be_u32(input)?;
+        let has = |flag| flags & flag != 0;
+        context(
+            "Attributes",
+            map(
+                tuple((
+                    cond(has(SSH_FILEXFER_ATTR_SIZE), be_u64),
+                    cond(has(SSH_FILEXFER_ATTR_UIDGID), pair(be_u32, be_u32)),
+                    cond(has(SSH_FILEXFER_ATTR_PERMISSIONS), be_u32),
+                    cond(has(SSH_FILEXFER_ATTR_ACMODTIME), pair(be_u32, be_u32)),
+                    cond(has(SSH_FILEXFER_ATTR_EXTENDED), extensions),
+                )),
+                |(size, uid_gid, permissions, atime_mtime, extended)| Self {
+                    size,
+                    uid_gid,
+                    permissions,
+                    atime_mtime,
+                    extended: extended.unwrap_or_default(),
+                },
+            ),
+        )(input)
+    }
+
+    fn serialize(&self, buffer: &mut Vec<u8>) {
+        let mut flags = 0;
+        let mut fields = Vec::new();
+        if let Some(size) = self.size {
+            flags |= SSH_FILEXFER_ATTR_SIZE;
+            fields.extend_from_slice(&size.to_be_bytes());
+        }
+        if let Some((uid, gid)) = self.uid_gid {
+            flags |= SSH_FILEXFER_ATTR_UIDGID;
+            put_u32(&mut fields, uid);
+            put_u32(&mut fields, gid);
+        }
+        if let Some(permissions) = self.permissions {
+            flags |= SSH_FILEXFER_ATTR_PERMISSIONS;
+            put_u32(&mut fields, permissions);
+        }
+        if let Some((atime, mtime)) = self.atime_mtime {
+            flags |= SSH_FILEXFER_ATTR_ACMODTIME;
+            put_u32(&mut fields, atime);
+            put_u32(&mut fields, mtime);
+        }
+        if !self.extended.is_empty() {
+            flags |= SSH_FILEXFER_ATTR_EXTENDED;
+            put_u32(&mut fields, self.extended.len() as u32);
+            for (name, data) in &self.extended {
+                put_string(&mut fields, name.as_bytes());
+                put_string(&mut fields, data);
+            }
+        }
+        put_u32(buffer, flags);
+        buffer.extend_from_slice(&fields);
+    }
+}
+
+/// Entry returned by [`Sftp::read_dir`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub filename: String,
+    /// `ls -l` like description, whose format depends on the server
+    pub longname: String,
+    pub attributes: Attributes,
+}
+
+/// Handle of an opened file or directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handle(Vec<u8>);
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_string(buffer: &mut Vec<u8>, value: &[u8]) {
+    put_u32(buffer, value.len() as u32);
+    buffer.extend_from_slice(value);
+}
+
+fn string(input: &[u8]) -> IResult<&[u8], &[u8], VerboseError<&[u8]>> {
+    length_data(be_u32)(input)
+}
+
+fn utf8(input: &[u8]) -> IResult<&[u8], String, VerboseError<&[u8]>> {
+    map(string, |s| String::from_utf8_lossy(s).into_owned())(input)
+}
+
+fn extensions(input: &[u8]) -> IResult<&[u8], Extensions, VerboseError<&[u8]>> {
+    let (input, n) = be_u32(input)?;
+    count(pair(utf8, map(string, <[u8]>::to_vec)), n as usize)(input)
+}
+
+fn dir_entries(input: &[u8]) -> IResult<&[u8], Vec<DirEntry>, VerboseError<&[u8]>> {
+    let (input, n) = be_u32(input)?;
+    count(
+        map(
+            tuple((utf8, utf8, Attributes::parse)),
+            |(filename, longname, attributes)| DirEntry {
+                filename,
+                longname,
+                attributes,
+            },
+        ),
+        n as usize,
+    )(input)
+}
+
+/// Response to a request, still to be checked against what the request expects
+enum Response<'a> {
+    Status { code: u32, message: String },
+    Handle(&'a [u8]),
+    Data(&'a [u8]),
+    Name(Vec<DirEntry>),
+    Attrs(Attributes),
+}
+
+impl Response<'_> {
+    fn unexpected(self) -> Error {
+        match self {
+            Self::Status { code, message } => Error::Sftp {
+                code: code.into(),
+                message,
+            },
+            Self::Handle(_) => unexpected("SSH_FXP_HANDLE"),
+            Self::Data(_) => unexpected("SSH_FXP_DATA"),
+            Self::Name(_) => unexpected("SSH_FXP_NAME"),
+            Self::Attrs(_) => unexpected("SSH_FXP_ATTRS"),
+        }
+    }
+}
+
+fn unexpected(name: &str) -> Error {
+    Error::InvalidPacket {
+        description: format!("Unexpected {name} response").into(),
+    }
+}
+
+fn response(input: &[u8]) -> Result<(&[u8], u32, Response<'_>)> {
+    let (input, (r#type, id)) = pair(be_u8, be_u32::<_, VerboseError<&[u8]>>)(input)?;
+    let (input, response) = match r#type {
+        SSH_FXP_STATUS => map(
+            // The message and language tag are missing from some old servers
+            tuple((be_u32, opt(utf8), opt(string))),
+            |(code, message, _language)| Response::Status {
+                code,
+                message: message.unwrap_or_default(),
+            },
+        )(input)?,
+        SSH_FXP_HANDLE => map(string, Response::Handle)(input)?,
+        SSH_FXP_DATA => map(string, Response::Data)(input)?,
+        SSH_FXP_NAME => map(dir_entries, Response::Name)(input)?,
+        SSH_FXP_ATTRS => map(Attributes::parse, Response::Attrs)(input)?,
+        _ => return Err(unexpected(&format!("type {type}"))),
+    };
+    Ok((input, id, response))
+}
+
+/// SFTP session
+///
+/// Usually obtained from [`SshControl::sftp`], but any pair of streams connected to an SFTP
+/// server will do.
+pub struct Sftp<R = PipeRead, W = PipeWrite> {
+    reader: R,
+    writer: W,
+    version: u32,
+    extensions: Extensions,
+    request_id: u32,
+    buffer: Vec<u8>,
+}
+
+impl<R, W> fmt::Debug for Sftp<R, W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sftp")
+            .field("version", &self.version)
+            .field("extensions", &self.extensions)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R, W> Sftp<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    /// Negotiates the protocol version with the server
+    pub fn new(reader: R, writer: W) -> Result<Self> {
+        let mut me = Self {
+            reader,
+            writer,
+            version: 0,
+            extensions: Vec::new(),
+            request_id: 0,
+            buffer: Vec::new(),
+        };
+        let mut init = vec![SSH_FXP_INIT];
+        put_u32(&mut init, VERSION);
+        me.send(&init)?;
+        me.recv()?;
+        let (version, extensions) = match be_u8::<_, VerboseError<&[u8]>>(&me.buffer[..])? {
+            (rest, SSH_FXP_VERSION) => {
+                let (_, (version, extensions)) =
+                    pair(be_u32, many0(pair(utf8, map(string, <[u8]>::to_vec))))(rest)?;
+                (version, extensions)
+            }
+            (_, r#type) => return Err(unexpected(&format!("type {type} instead of version"))),
+        };
+        log::debug!("SFTP server version {version} with extensions {extensions:?}");
+        if version < VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        me.version = version;
+        me.extensions = extensions;
+        Ok(me)
+    }
+
+    /// Version announced by the server, this client only speaking version 3
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Extensions announced by the server
+    pub fn extensions(&self) -> &[(String, Vec<u8>)] {
+        &self.extensions
+    }
+
+    fn send(&mut self, body: &[u8]) -> Result<()> {
+        let mut packet = Vec::with_capacity(body.len() + 4);
+        put_string(&mut packet, body);
+        self.writer.write_all(&packet)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<()> {
+        let mut size = [0u8; 4];
+        self.reader.read_exact(&mut size)?;
+        let size = u32::from_be_bytes(size) as usize;
+        // Leave room for a full read on top of the maximum mux packet size
+        let max = DEFAULT_MAX_PACKET_SIZE + READ_SIZE as usize;
+        if size > max {
+            return Err(Error::PacketTooLarge { size, max });
+        }
+        self.buffer.clear();
+        (&mut self.reader)
+            .take(size as u64)
+            .read_to_end(&mut self.buffer)?;
+        if self.buffer.len() < size {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+
+    /// Sends a request made of `r#type`, a fresh request id and the `fields` written by `write`,
+    /// then returns the response
+    fn request(&mut self, r#type: u8, write: impl FnOnce(&mut Vec<u8>)) -> Result<Response<'_>> {
+        self.request_id = self.request_id.wrapping_add(1);
+        let mut body = vec![r#type];
+        put_u32(&mut body, self.request_id);
+        write(&mut body);
+        self.send(&body)?;
+        self.recv()?;
+
+        let (rest, id, response) = response(&self.buffer)?;
+        if id != self.request_id {
+            return Err(Error::InvalidResponseID {
+                expected: Some(self.request_id),
+                received: Some(id),
+            });
+        }
+        if !rest.is_empty() {
+            return Err(Error::TrailingData { len: rest.len() });
+        }
+        Ok(response)
+    }
+
+    fn expect_ok(&mut self, r#type: u8, write: impl FnOnce(&mut Vec<u8>)) -> Result<()> {
+        match self.request(r#type, write)? {
+            Response::Status { code: 0, .. } => Ok(()),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    fn expect_handle(&mut self, r#type: u8, write: impl FnOnce(&mut Vec<u8>)) -> Result<Handle> {
+        match self.request(r#type, write)? {
+            Response::Handle(handle) => Ok(Handle(handle.to_vec())),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    fn expect_attrs(&mut self, r#type: u8, write: impl FnOnce(&mut Vec<u8>)) -> Result<Attributes> {
+        match self.request(r#type, write)? {
+            Response::Attrs(attrs) => Ok(attrs),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    /// Single name answered by `realpath` and `readlink`
+    fn expect_name(&mut self, r#type: u8, path: &str) -> Result<String> {
+        match self.request(r#type, |b| put_string(b, path.as_bytes()))? {
+            Response::Name(mut entries) if entries.len() == 1 => Ok(entries.remove(0).filename),
+            Response::Name(entries) => Err(Error::InvalidPacket {
+                description: format!("Expected 1 name, got {}", entries.len()).into(),
+            }),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    pub fn open(
+        &mut self,
+        path: &str,
+        flags: OpenFlags,
+        attributes: &Attributes,
+    ) -> Result<Handle> {
+        self.expect_handle(SSH_FXP_OPEN, |b| {
+            put_string(b, path.as_bytes());
+            put_u32(b, flags.0);
+            attributes.serialize(b);
+        })
+    }
+
+    pub fn close(&mut self, handle: Handle) -> Result<()> {
+        self.expect_ok(SSH_FXP_CLOSE, |b| put_string(b, &handle.0))
+    }
+
+    /// Reads up to `len` bytes at `offset`, `None` meaning the end of the file was reached
+    pub fn read(&mut self, handle: &Handle, offset: u64, len: u32) -> Result<Option<Vec<u8>>> {
+        let response = self.request(SSH_FXP_READ, |b| {
+            put_string(b, &handle.0);
+            b.extend_from_slice(&offset.to_be_bytes());
+            put_u32(b, len);
+        })?;
+        match response {
+            Response::Data(data) => Ok(Some(data.to_vec())),
+            Response::Status { code: 1, .. } => Ok(None),
+            response => Err(response.unexpected()),
+        }
+    }
+
+    pub fn write(&mut self, handle: &Handle, offset: u64, data: &[u8]) -> Result<()> {
+        self.expect_ok(SSH_FXP_WRITE, |b| {
+            put_string(b, &handle.0);
+            b.extend_from_slice(&offset.to_be_bytes());
+            put_string(b, data);
+        })
+    }
+
+    /// Attributes of `path`, following symbolic links
+    pub fn stat(&mut self, path: &str) -> Result<Attributes> {
+        self.expect_attrs(SSH_FXP_STAT, |b| put_string(b, path.as_bytes()))
+    }
+
+    /// Attributes of `path`, not following symbolic links
+    pub fn lstat(&mut self, path: &str) -> Result<Attributes> {
+        self.expect_attrs(SSH_FXP_LSTAT, |b| put_string(b, path.as_bytes()))
+    }
+
+    pub fn fstat(&mut self, handle: &Handle) -> Result<Attributes> {
+        self.expect_attrs(SSH_FXP_FSTAT, |b| put_string(b, &handle.0))
+    }
+
+    pub fn setstat(&mut self, path: &str, attributes: &Attributes) -> Result<()> {
+        self.expect_ok(SSH_FXP_SETSTAT, |b| {
+            put_string(b, path.as_bytes());
+            attributes.serialize(b);
+        })
+    }
+
+    /// Entries of the directory at `path`, including `.` and `..` if the server lists them
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<DirEntry>> {
+        let handle = self.expect_handle(SSH_FXP_OPENDIR, |b| put_string(b, path.as_bytes()))?;
+        let mut entries = Vec::new();
+        let result = loop {
+            match self.request(SSH_FXP_READDIR, |b| put_string(b, &handle.0)) {
+                Ok(Response::Name(mut more)) => entries.append(&mut more),
+                Ok(Response::Status { code: 1, .. }) => break Ok(entries),
+                Ok(response) => break Err(response.unexpected()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.close(handle)?;
+        result
+    }
+
+    pub fn remove(&mut self, path: &str) -> Result<()> {
+        self.expect_ok(SSH_FXP_REMOVE, |b| put_string(b, path.as_bytes()))
+    }
+
+    pub fn mkdir(&mut self, path: &str, attributes: &Attributes) -> Result<()> {
+        self.expect_ok(SSH_FXP_MKDIR, |b| {
+            put_string(b, path.as_bytes());
+            attributes.serialize(b);
+        })
+    }
+
+    pub fn rmdir(&mut self, path: &str) -> Result<()> {
+        self.expect_ok(SSH_FXP_RMDIR, |b| put_string(b, path.as_bytes()))
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        self.expect_ok(SSH_FXP_RENAME, |b| {
+            put_string(b, from.as_bytes());
+            put_string(b, to.as_bytes());
+        })
+    }
+
+    /// Creates a symbolic link at `link` pointing to `target`
+    pub fn symlink(&mut self, target: &str, link: &str) -> Result<()> {
+        // OpenSSH swapped the arguments compared to the specification, and other servers
+        // followed to stay compatible with its client
+        self.expect_ok(SSH_FXP_SYMLINK, |b| {
+            put_string(b, target.as_bytes());
+            put_string(b, link.as_bytes());
+        })
+    }
+
+    pub fn readlink(&mut self, path: &str) -> Result<String> {
+        self.expect_name(SSH_FXP_READLINK, path)
+    }
+
+    /// Canonical absolute path of `path`, `.` giving the current directory
+    pub fn realpath(&mut self, path: &str) -> Result<String> {
+        self.expect_name(SSH_FXP_REALPATH, path)
+    }
+
+    /// Reads the whole file at `path`
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>> {
+        let handle = self.open(path, OpenFlags::READ, &Attributes::default())?;
+        let mut content = Vec::new();
+        let result = loop {
+            match self.read(&handle, content.len() as u64, READ_SIZE) {
+                Ok(Some(data)) => content.extend_from_slice(&data),
+                Ok(None) => break Ok(content),
+                Err(e) => break Err(e),
+            }
+        };
+        self.close(handle)?;
+        result
+    }
+
+    /// Creates or truncates the file at `path` and writes `content` to it
+    pub fn write_file(&mut self, path: &str, content: &[u8]) -> Result<()> {
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let handle = self.open(path, flags, &Attributes::default())?;
+        let mut offset = 0;
+        let result = content.chunks(READ_SIZE as usize).try_for_each(|chunk| {
+            self.write(&handle, offset, chunk)?;
+            offset += chunk.len() as u64;
+            Ok(())
+        });
+        self.close(handle)?;
+        result
+    }
+}
+
+impl<S> SshControl<S>
+where
+    S: ControlSocket,
+{
+    /// Starts an SFTP session through the `sftp` subsystem
+    ///
+    /// As with other sessions, OpenSSH masters close the control connection when it ends.
+    pub fn sftp(&mut self) -> Result<Sftp> {
+        let mut command = SshCommand::new("sftp");
+        command.subsystem(true);
+        command.stdin(Pipe::new()?);
+        command.stdout(Pipe::new()?);
+        command.stderr(Pipe::dev_null()?);
+        let mut child = self.new_session(command)?;
+        let reader = child.stdout.take().expect("stdout is piped");
+        let writer = child.stdin.take().expect("stdin is piped");
+        Sftp::new(reader, writer)
+    }
+}
@@ -0,0 +1,43 @@
+use std::{
+    io::{self, Read, Write},
+    os::unix::{io::RawFd, net::UnixStream},
+    time::Duration,
+};
+
+use passfd::FdPassingExt;
+
+/// Byte stream to a master, able to pass file descriptors alongside the packets
+pub trait ControlSocket: Read + Write {
+    fn send_fd(&mut self, fd: RawFd) -> io::Result<()>;
+
+    /// Makes reads fail with [`io::ErrorKind::WouldBlock`] or [`io::ErrorKind::TimedOut`] after
+    /// `timeout`, or block again with `None`
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        let _ = timeout;
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl ControlSocket for UnixStream {
+    fn send_fd(&mut self, fd: RawFd) -> io::Result<()> {
+        // The master expects a single byte of payload with each descriptor
+        self.send_fd_with_payload(fd, &[0u8][..])
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl<S> ControlSocket for &mut S
+where
+    S: ControlSocket + ?Sized,
+{
+    fn send_fd(&mut self, fd: RawFd) -> io::Result<()> {
+        (**self).send_fd(fd)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
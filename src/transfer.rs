@@ -0,0 +1,269 @@
+//! File transfers through sessions, `scp` style.
+//!
+//! Files are streamed through `cat` on the remote host and verified with POSIX `cksum`, so any
+//! host with a POSIX shell will do. Mode bits are preserved in both directions, except for the
+//! setuid, setgid and sticky bits of downloaded files. Both directions write to a file next to
+//! the destination, which only replaces it once verified.
+//!
+//! Each transfer runs one session, after which OpenSSH masters close the control connection: use
+//! a new [`SshControl`] for every transfer.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Seek, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{
+    command::{quote, Child, Output, Pipe, SshCommand},
+    ControlSocket, Error, Result, SshControl,
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+/// Checksum computed by POSIX `cksum`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Cksum {
+    crc: u32,
+    len: u64,
+}
+
+impl Cksum {
+    fn push(crc: u32, byte: u8) -> u32 {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.crc = data.iter().fold(self.crc, |crc, &b| Self::push(crc, b));
+        self.len += data.len() as u64;
+    }
+
+    /// Value printed by `cksum`, which also covers the length
+    fn value(&self) -> u32 {
+        let mut crc = self.crc;
+        let mut len = self.len;
+        while len != 0 {
+            crc = Self::push(crc, len as u8);
+            len >>= 8;
+        }
+        !crc
+    }
+
+    /// Checks `cksum` output against the data seen
+    fn verify(&self, line: &str) -> Result<()> {
+        let (crc, len) = parse_cksum(line)?;
+        if len != self.len {
+            Err(Error::VerificationFailed(format!(
+                "remote has {len} bytes, local has {}",
+                self.len
+            )))
+        } else if crc != self.value() {
+            Err(Error::VerificationFailed(format!(
+                "remote checksum {crc} differs from local checksum {}",
+                self.value()
+            )))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn parse_cksum(line: &str) -> Result<(u32, u64)> {
+    let mut fields = line.split_whitespace();
+    match (
+        fields.next().and_then(|crc| crc.parse().ok()),
+        fields.next().and_then(|len| len.parse().ok()),
+    ) {
+        (Some(crc), Some(len)) => Ok((crc, len)),
+        _ => Err(Error::VerificationFailed(format!(
+            "unexpected cksum output {line:?}"
+        ))),
+    }
+}
+
+fn piped(command: String) -> Result<SshCommand> {
+    let mut cmd = SshCommand::new(command);
+    cmd.stdin(Pipe::new()?);
+    cmd.stdout(Pipe::new()?);
+    cmd.stderr(Pipe::new()?);
+    Ok(cmd)
+}
+
+/// Copies `reader` to `writer` by chunks, reporting progress after each one
+fn copy(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    total: u64,
+    progress: &mut impl FnMut(u64, u64),
+) -> io::Result<Cksum> {
+    let mut cksum = Cksum::default();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..n])?;
+        cksum.update(&buffer[..n]);
+        progress(cksum.len, total);
+    }
+    Ok(cksum)
+}
+
+/// Status of the remote command takes precedence over the local result, which is likely a
+/// consequence of the command failing
+fn check<T>(output: Output, result: Result<T>) -> Result<(Output, T)> {
+    if !output.success() {
+        return Err(Error::CommandFailed {
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        });
+    }
+    Ok((output, result?))
+}
+
+impl<S> SshControl<S>
+where
+    S: ControlSocket,
+{
+    /// Copies the `local` file to `remote`, returning the number of bytes copied
+    pub fn upload(&mut self, local: impl AsRef<Path>, remote: &str) -> Result<u64> {
+        self.upload_with_progress(local, remote, |_, _| {})
+    }
+
+    /// Like [`upload`](Self::upload), calling `progress` with the bytes sent so far and the total
+    pub fn upload_with_progress(
+        &mut self,
+        local: impl AsRef<Path>,
+        remote: &str,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<u64> {
+        let mut file = File::open(local)?;
+        let metadata = file.metadata()?;
+        let mode = metadata.permissions().mode() & 0o7777;
+        // Checksum the remote copy must have to replace `remote`
+        let expected = copy(&mut file, &mut io::sink(), metadata.len(), &mut |_, _| {})?;
+        file.rewind()?;
+
+        let (dir, name) = match remote.rsplit_once('/') {
+            Some(("", name)) => ("/", name),
+            Some((dir, name)) => (dir, name),
+            None => (".", remote),
+        };
+        let (dir, template, remote) = (quote(dir), quote(name), quote(remote));
+        // Received next to `remote`, which is only replaced once the copy is complete
+        let mut child = self.new_session(piped(format!(
+            "tmp=$(mktemp {dir}/.{template}.XXXXXX) || exit; \
+             trap 'rm -f -- \"$tmp\"' EXIT; \
+             cat > \"$tmp\" && chmod {mode:o} -- \"$tmp\" && sum=$(cksum < \"$tmp\") || exit; \
+             echo \"$sum\"; set -- $sum; \
+             [ \"$1 $2\" = '{crc} {len}' ] || {{ echo 'checksum mismatch' >&2; exit 1; }}; \
+             mv -f -- \"$tmp\" {remote} && trap - EXIT",
+            crc = expected.value(),
+            len = expected.len,
+        ))?)?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let sent = copy(&mut file, &mut stdin, metadata.len(), &mut progress);
+        drop(stdin);
+
+        let (output, cksum) = check(self.wait_with_output(child)?, sent.map_err(Error::from))?;
+        cksum.verify(&String::from_utf8_lossy(&output.stdout))?;
+        Ok(cksum.len)
+    }
+
+    /// Copies the `remote` file to `local`, returning the number of bytes copied
+    pub fn download(&mut self, remote: &str, local: impl AsRef<Path>) -> Result<u64> {
+        self.download_with_progress(remote, local, |_, _| {})
+    }
+
+    /// Like [`download`](Self::download), calling `progress` with the bytes received so far and
+    /// the total
+    pub fn download_with_progress(
+        &mut self,
+        remote: &str,
+        local: impl AsRef<Path>,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<u64> {
+        let remote = quote(remote);
+        // Mode bits with GNU stat, or BSD stat, then the checksum and the content
+        let mut child = self.new_session(piped(format!(
+            "(stat -c %a -- {remote} 2>/dev/null || stat -f %Lp -- {remote}) \
+             && cksum < {remote} && exec cat < {remote}"
+        ))?)?;
+        let local = local.as_ref();
+        let partial = partial_path(local)?;
+        let received = receive(&mut child, &partial, &mut progress);
+        let result = self
+            .wait_with_output(child)
+            .and_then(|output| check(output, received))
+            .and_then(|(_, len)| Ok(fs::rename(&partial, local).map(|()| len)?));
+        if result.is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+        result
+    }
+}
+
+/// File next to `local` receiving a download, renamed to `local` once verified
+fn partial_path(local: &Path) -> Result<PathBuf> {
+    let name = local
+        .file_name()
+        .ok_or_else(|| Error::InvalidUsage(format!("{} does not name a file", local.display())))?;
+    let mut partial = OsString::from(".");
+    partial.push(name);
+    partial.push(format!(".{}.part", process::id()));
+    Ok(local.with_file_name(partial))
+}
+
+fn receive(child: &mut Child, local: &Path, progress: &mut impl FnMut(u64, u64)) -> Result<u64> {
+    let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+    let mut mode = String::new();
+    stdout.read_line(&mut mode)?;
+    let mut line = String::new();
+    stdout.read_line(&mut line)?;
+    if line.is_empty() {
+        return Err(Error::VerificationFailed("no checksum received".into()));
+    }
+    let mode = u32::from_str_radix(mode.trim(), 8)
+        .map_err(|_| Error::VerificationFailed(format!("unexpected mode {mode:?}")))?;
+    let (_, len) = parse_cksum(&line)?;
+
+    let mut file = File::create(local)?;
+    let cksum = copy(&mut (&mut stdout).take(len), &mut file, len, progress)?;
+    cksum.verify(&line)?;
+    if stdout.fill_buf()?.is_empty() {
+        fs::set_permissions(local, fs::Permissions::from_mode(mode & 0o777))?;
+        Ok(cksum.len)
+    } else {
+        Err(Error::VerificationFailed(format!(
+            "remote file grew past {len} bytes"
+        )))
+    }
+}
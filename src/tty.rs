@@ -0,0 +1,206 @@
+//! Interactive sessions in the local terminal.
+//!
+//! The mux protocol has no window change request: like `ssh -S`, the master is sent `SIGWINCH`
+//! when the local terminal is resized, and reads the new size from the terminal it was passed
+//! before forwarding it to the remote TTY.
+
+use std::{
+    io,
+    mem::MaybeUninit,
+    os::unix::io::RawFd,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::{command::SshCommand, ControlSocket, Result, SshControl};
+
+/// How often the window size is checked while waiting for an interactive session
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WindowSize {
+    pub rows: u16,
+    pub cols: u16,
+    pub xpixel: u16,
+    pub ypixel: u16,
+}
+
+/// Where the local window size comes from
+pub trait SizeSource {
+    fn window_size(&mut self) -> io::Result<WindowSize>;
+}
+
+/// Local terminal, whose size is read with `TIOCGWINSZ`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Terminal(pub RawFd);
+
+impl Terminal {
+    pub fn stdin() -> Self {
+        Self(libc::STDIN_FILENO)
+    }
+
+    pub fn is_tty(&self) -> bool {
+        unsafe { libc::isatty(self.0) == 1 }
+    }
+}
+
+impl SizeSource for Terminal {
+    fn window_size(&mut self) -> io::Result<WindowSize> {
+        let mut size = MaybeUninit::<libc::winsize>::zeroed();
+        if unsafe { libc::ioctl(self.0, libc::TIOCGWINSZ, size.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let size = unsafe { size.assume_init() };
+        Ok(WindowSize {
+            rows: size.ws_row,
+            cols: size.ws_col,
+            xpixel: size.ws_xpixel,
+            ypixel: size.ws_ypixel,
+        })
+    }
+}
+
+/// Calls `notify` with the new size each time [`check`](Self::check) finds that `source` changed
+pub struct WindowWatcher<S, N> {
+    source: S,
+    notify: N,
+    size: WindowSize,
+}
+
+impl<S, N> WindowWatcher<S, N>
+where
+    S: SizeSource,
+    N: FnMut(WindowSize) -> io::Result<()>,
+{
+    /// Watches `source` from its current size, which the master already knows
+    pub fn new(mut source: S, notify: N) -> io::Result<Self> {
+        let size = source.window_size()?;
+        Ok(Self {
+            source,
+            notify,
+            size,
+        })
+    }
+
+    pub fn size(&self) -> WindowSize {
+        self.size
+    }
+
+    /// Returns the new size if it changed since the last check, after notifying it
+    pub fn check(&mut self) -> io::Result<Option<WindowSize>> {
+        let size = self.source.window_size()?;
+        if size == self.size {
+            return Ok(None);
+        }
+        (self.notify)(size)?;
+        self.size = size;
+        Ok(Some(size))
+    }
+}
+
+/// Notification making the master with process id `pid` forward the size of the terminals it was
+/// passed to their sessions
+pub fn notify_master(pid: u32) -> impl FnMut(WindowSize) -> io::Result<()> {
+    move |_| {
+        if unsafe { libc::kill(pid as libc::pid_t, libc::SIGWINCH) } == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+static SIGWINCH_RECEIVED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigwinch(_: libc::c_int) {
+    SIGWINCH_RECEIVED.store(true, Ordering::Relaxed);
+}
+
+/// Installs a `SIGWINCH` handler recording resizes for [`take_sigwinch`]
+pub fn install_sigwinch_handler() -> io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigwinch as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGWINCH, &action, std::ptr::null_mut()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Whether `SIGWINCH` was received since the last call
+pub fn take_sigwinch() -> bool {
+    SIGWINCH_RECEIVED.swap(false, Ordering::Relaxed)
+}
+
+/// Terminal in raw mode, restored when dropped
+pub struct RawMode {
+    fd: RawFd,
+    saved: libc::termios,
+}
+
+impl RawMode {
+    pub fn enter(terminal: Terminal) -> io::Result<Self> {
+        let mut saved = MaybeUninit::<libc::termios>::zeroed();
+        if unsafe { libc::tcgetattr(terminal.0, saved.as_mut_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let saved = unsafe { saved.assume_init() };
+        let mut raw = saved;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(terminal.0, libc::TCSADRAIN, &raw) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: terminal.0,
+            saved,
+        })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(self.fd, libc::TCSADRAIN, &self.saved) };
+    }
+}
+
+impl<S> SshControl<S>
+where
+    S: ControlSocket,
+{
+    /// Runs `command` in a TTY attached to the local terminal, returning its exit value
+    ///
+    /// The terminal is put in raw mode while the command runs, and resizes are propagated to the
+    /// remote TTY. Its standard streams must not be redirected.
+    pub fn interactive(&mut self, mut command: SshCommand) -> Result<u32> {
+        let pid = self.check_alive()?;
+        let terminal = Terminal::stdin();
+        let tty = terminal.is_tty();
+        command.want_tty(tty);
+        install_sigwinch_handler()?;
+        let mut watcher = if tty {
+            Some(WindowWatcher::new(terminal, notify_master(pid))?)
+        } else {
+            None
+        };
+
+        let child = self.new_session(command)?;
+        let _raw = if tty {
+            Some(RawMode::enter(terminal)?)
+        } else {
+            None
+        };
+        loop {
+            if let Some(status) = self.wait_timeout(&child, POLL_INTERVAL)? {
+                return Ok(status);
+            }
+            if let Some(ref mut watcher) = watcher {
+                if take_sigwinch() {
+                    watcher.check()?;
+                }
+            }
+        }
+    }
+}
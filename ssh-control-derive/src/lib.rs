@@ -0,0 +1,262 @@
+//! `#[derive(Wire)]` for the messages of `ssh-control`.
+//!
+//! Fields are (de)serialized in declaration order with their own `Wire` implementation. `Vec`
+//! fields are lists running up to the end of the packet, or up to a terminator given with
+//! `#[wire(terminator = b"...")]`, which must not be a valid start of an item.
+//!
+//! Supported attributes:
+//! * `#[wire(message = MuxMessage)]` on the struct implements `From<Struct>` for the enum
+//!   `MuxMessage`, using the variant named after the struct;
+//! * `#[wire(reserved_before)]` on a field reads (and ignores) a reserved string right before
+//!   it, writing an empty one when serializing.
+
+use proc_macro2::{Span, TokenStream, TokenTree};
+use quote::{quote, ToTokens};
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Fields, GenericArgument, Lifetime,
+    LitByteStr, Path, PathArguments, Type,
+};
+
+#[proc_macro_derive(Wire, attributes(wire))]
+pub fn derive_wire(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct Field {
+    ident: syn::Ident,
+    ty: Type,
+    reserved_before: bool,
+    terminator: Option<LitByteStr>,
+}
+
+fn parse_fields(data: &Data, span: Span) -> syn::Result<Vec<Field>> {
+    let fields = match data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(named) => &named.named,
+            _ => return Err(syn::Error::new(span, "Wire needs named fields")),
+        },
+        _ => return Err(syn::Error::new(span, "Wire can only be derived on structs")),
+    };
+
+    let mut out = Vec::with_capacity(fields.len());
+    for field in fields {
+        let mut reserved_before = false;
+        let mut terminator = None;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("wire")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("reserved_before") {
+                    reserved_before = true;
+                    Ok(())
+                } else if meta.path.is_ident("terminator") {
+                    terminator = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("unknown wire field attribute"))
+                }
+            })?;
+        }
+        if terminator.is_some() && vec_item(&field.ty).is_none() {
+            return Err(syn::Error::new(
+                field.ty.span(),
+                "terminator is only allowed on Vec fields",
+            ));
+        }
+        out.push(Field {
+            ident: field.ident.clone().expect("named field"),
+            ty: field.ty.clone(),
+            reserved_before,
+            terminator,
+        });
+    }
+    Ok(out)
+}
+
+fn vec_item(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let last = path.path.segments.last()?;
+    if last.ident != "Vec" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &last.arguments else {
+        return None;
+    };
+    match args.args.first()? {
+        GenericArgument::Type(item) if args.args.len() == 1 => Some(item),
+        _ => None,
+    }
+}
+
+fn mentions_lifetime(ty: &Type, lifetime: &Lifetime) -> bool {
+    fn walk(tokens: TokenStream, name: &syn::Ident) -> bool {
+        let tokens: Vec<_> = tokens.into_iter().collect();
+        tokens.iter().enumerate().any(|(i, tt)| match tt {
+            TokenTree::Punct(p) => {
+                p.as_char() == '\''
+                    && matches!(tokens.get(i + 1), Some(TokenTree::Ident(id)) if id == name)
+            }
+            TokenTree::Group(g) => walk(g.stream(), name),
+            _ => false,
+        })
+    }
+    walk(ty.to_token_stream(), &lifetime.ident)
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let krate = quote!(::ssh_control::__private);
+
+    let mut message: Option<Path> = None;
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("wire")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("message") {
+                message = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("unknown wire attribute"))
+            }
+        })?;
+    }
+
+    if input.generics.type_params().next().is_some()
+        || input.generics.const_params().next().is_some()
+        || input.generics.lifetimes().count() > 1
+    {
+        return Err(syn::Error::new(
+            input.generics.span(),
+            "Wire only supports structs with at most one lifetime",
+        ));
+    }
+    let struct_lifetime = input
+        .generics
+        .lifetimes()
+        .next()
+        .map(|l| l.lifetime.clone());
+    let lifetime = struct_lifetime
+        .clone()
+        .unwrap_or_else(|| Lifetime::new("'a", Span::call_site()));
+    let (self_ty, owned_ty, message_ty) = match struct_lifetime {
+        Some(ref l) => (
+            quote!(#name<#l>),
+            quote!(#name<'static>),
+            message.as_ref().map(|m| quote!(#m<#l>)),
+        ),
+        None => (
+            quote!(#name),
+            quote!(#name),
+            message.as_ref().map(|m| quote!(#m<'_>)),
+        ),
+    };
+
+    let fields = parse_fields(&input.data, input.ident.span())?;
+    let context = name.to_string();
+
+    let parse_fields = fields.iter().map(|f| {
+        let ident = &f.ident;
+        let ty = &f.ty;
+        let reserved = f.reserved_before.then(|| {
+            quote! {
+                let (input, reserved) =
+                    <::std::borrow::Cow<'_, str> as #krate::Wire>::parse(input)?;
+                if !reserved.is_empty() {
+                    #krate::log::warn!("Reserved string is not empty: {reserved:?}");
+                }
+            }
+        });
+        let parser = match (vec_item(ty), &f.terminator) {
+            (Some(item), Some(terminator)) => quote! {
+                #krate::many_terminated(<#item as #krate::Wire>::parse, &#terminator[..])
+            },
+            (Some(item), None) => quote!(#krate::many(<#item as #krate::Wire>::parse)),
+            (None, _) => quote!(<#ty as #krate::Wire>::parse),
+        };
+        quote! {
+            #reserved
+            let (input, #ident) = #parser(input)?;
+        }
+    });
+
+    let serialize_fields = fields.iter().map(|f| {
+        let ident = &f.ident;
+        let reserved = f.reserved_before.then(|| {
+            quote! {
+                #krate::Wire::serialize(&::std::borrow::Cow::Borrowed(""), writer)?;
+            }
+        });
+        let body = match (vec_item(&f.ty), &f.terminator) {
+            (Some(_), terminator) => {
+                let terminator = terminator
+                    .as_ref()
+                    .map(|t| quote!(::std::io::Write::write_all(writer, &#t[..])?;));
+                quote! {
+                    for item in &self.#ident {
+                        #krate::Wire::serialize(item, writer)?;
+                    }
+                    #terminator
+                }
+            }
+            (None, _) => quote!(#krate::Wire::serialize(&self.#ident, writer)?;),
+        };
+        quote!(#reserved #body)
+    });
+
+    let idents: Vec<_> = fields.iter().map(|f| &f.ident).collect();
+    let owned_fields = fields.iter().map(|f| {
+        let ident = &f.ident;
+        if struct_lifetime
+            .as_ref()
+            .is_some_and(|l| mentions_lifetime(&f.ty, l))
+        {
+            quote!(#ident: #krate::IntoOwned::into_owned(self.#ident))
+        } else {
+            quote!(#ident: self.#ident)
+        }
+    });
+
+    let generics = struct_lifetime.as_ref().map(|l| quote!(<#l>));
+    let from_impl = message_ty.map(|message_ty| {
+        quote! {
+            impl #generics ::std::convert::From<#self_ty> for #message_ty {
+                fn from(value: #self_ty) -> Self {
+                    Self::#name(value)
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        impl<#lifetime> #krate::Wire<#lifetime> for #self_ty {
+            fn parse<E>(input: &#lifetime [u8]) -> #krate::nom::IResult<&#lifetime [u8], Self, E>
+            where
+                E: #krate::NomError<#lifetime>,
+            {
+                #krate::nom::error::context(#context, |input: &#lifetime [u8]| {
+                    #(#parse_fields)*
+                    ::std::result::Result::Ok((input, Self { #(#idents),* }))
+                })(input)
+            }
+
+            fn serialize<W>(&self, writer: &mut W) -> ::std::io::Result<()>
+            where
+                W: ::std::io::Write,
+            {
+                #(#serialize_fields)*
+                ::std::result::Result::Ok(())
+            }
+        }
+
+        impl #generics #self_ty {
+            pub fn into_owned(self) -> #owned_ty {
+                #name {
+                    #(#owned_fields),*
+                }
+            }
+        }
+
+        #from_impl
+    })
+}
@@ -0,0 +1,100 @@
+use std::borrow::Cow;
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use ssh_control::{client, codec::MuxCodec, server, Error, Hello, MuxMessage, MuxResponse, Wire};
+use tokio::net::UnixStream;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+fn hello() -> Hello<'static> {
+    Hello {
+        version: 4,
+        extensions: Vec::new(),
+    }
+}
+
+#[tokio::test]
+async fn client_server_exchange() {
+    let (client, server) = UnixStream::pair().unwrap();
+    let mut client = Framed::new(client, MuxCodec::<Hello>::new());
+    let mut server = Framed::new(server, MuxCodec::<Hello>::new());
+
+    client.send(hello()).await.unwrap();
+    assert_eq!(server.next().await.unwrap().unwrap(), hello());
+    server.send(hello()).await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), hello());
+
+    let mut client = client.map_codec(MuxCodec::switch::<MuxResponse>);
+    let mut server = server.map_codec(MuxCodec::switch::<MuxMessage>);
+
+    let check: MuxMessage = client::AliveCheck { request_id: 1 }.into();
+    client.send(check.clone()).await.unwrap();
+    assert_eq!(server.next().await.unwrap().unwrap(), check);
+
+    let alive: MuxResponse = server::Alive {
+        client_request_id: 1,
+        server_pid: 42,
+    }
+    .into();
+    server.send(alive.clone()).await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), alive);
+}
+
+#[test]
+fn partial_packets() {
+    let msg: MuxResponse = server::Failure {
+        client_request_id: 3,
+        reason: Cow::Borrowed("nope"),
+    }
+    .into();
+    let mut codec = MuxCodec::<MuxResponse>::new();
+    let mut encoded = BytesMut::new();
+    codec.encode(msg.clone(), &mut encoded).unwrap();
+
+    let mut src = BytesMut::new();
+    for b in &encoded[..encoded.len() - 1] {
+        src.extend_from_slice(&[*b]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+    src.extend_from_slice(&encoded[encoded.len() - 1..]);
+    assert_eq!(codec.decode(&mut src).unwrap(), Some(msg));
+    assert!(src.is_empty());
+}
+
+#[test]
+fn oversized_packet() {
+    let mut codec = MuxCodec::<MuxMessage>::with_max_size(16);
+    let mut src = BytesMut::from(&[0u8, 0, 0, 17][..]);
+    assert!(matches!(
+        codec.decode(&mut src),
+        Err(Error::PacketTooLarge { size: 17, max: 16 })
+    ));
+}
+
+#[test]
+fn trailing_data() {
+    let msg: MuxMessage = client::Terminate { request_id: 1 }.into();
+    let mut raw = Vec::new();
+    msg.serialize(&mut raw).unwrap();
+    let mut src = BytesMut::new();
+    src.extend_from_slice(&(raw.len() as u32 + 1).to_be_bytes());
+    src.extend_from_slice(&raw);
+    src.extend_from_slice(b"x");
+
+    let mut codec = MuxCodec::<MuxMessage>::new();
+    assert!(matches!(
+        codec.decode(&mut src),
+        Err(Error::TrailingData { len: 1 })
+    ));
+}
+
+#[test]
+fn partial_packet_reserves_its_size() {
+    let mut codec = MuxCodec::<MuxMessage>::new();
+    let mut src = BytesMut::new();
+    src.extend_from_slice(&4096u32.to_be_bytes());
+    src.extend_from_slice(&[0; 10]);
+    assert!(codec.decode(&mut src).unwrap().is_none());
+    assert!(src.capacity() >= 4 + 4096);
+    assert_eq!(src.len(), 14);
+}
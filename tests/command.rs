@@ -0,0 +1,383 @@
+use std::{
+    env, fs,
+    io::{Read, Write},
+    os::unix::{
+        io::{FromRawFd, OwnedFd},
+        net::UnixStream,
+    },
+    process,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use passfd::FdPassingExt;
+use ssh_control::{
+    client,
+    command::{EnvFilter, Pipe, SshCommand},
+    record::Replayer,
+    server, Error, MuxMessage, MuxResponse, Packet, SshControl, Wire,
+};
+
+mod common;
+use common::{handshake, hello_records, request, response, socket_path, spawn_shell_master};
+
+fn output(name: &str, mut cmd: SshCommand) -> (u32, String) {
+    let path = socket_path(name);
+    let master = spawn_shell_master(&path, 1);
+    cmd.stdin(Pipe::dev_null().unwrap());
+    cmd.stdout(Pipe::new().unwrap());
+    cmd.stderr(Pipe::dev_null().unwrap());
+
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let child = ctrl.new_session(cmd).unwrap();
+    let output = ctrl.wait_with_output(child).unwrap();
+    master.join().unwrap();
+    let _ = fs::remove_file(&path);
+    (output.status, String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn quoted_arguments() {
+    let args = [
+        "a b",
+        "it's",
+        "$(touch pwned)",
+        "`id`",
+        "*",
+        "",
+        "\\n",
+        "-x",
+    ];
+    let mut cmd = SshCommand::program("printf");
+    cmd.arg("[%s]\\n").args(args);
+    let (status, stdout) = output("command-args", cmd);
+    assert_eq!(status, 0);
+    let expected: String = args.iter().map(|arg| format!("[{arg}]\n")).collect();
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn current_dir() {
+    let dir = env::temp_dir().join(format!("ssh-control-{}-it's cwd.d", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+
+    let mut cmd = SshCommand::program("pwd");
+    cmd.current_dir(dir.to_str().unwrap());
+    let (status, stdout) = output("command-cwd", cmd);
+    assert_eq!(status, 0);
+    assert_eq!(stdout.trim_end(), dir.to_str().unwrap());
+
+    let mut cmd = SshCommand::new("echo ran");
+    cmd.current_dir(dir.join("missing").to_str().unwrap());
+    let (status, stdout) = output("command-cwd-missing", cmd);
+    assert_ne!(status, 0);
+    assert_eq!(stdout, "");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn env_clear() {
+    let mut cmd = SshCommand::new("echo \"${HOME:-unset} $GREETING\"");
+    cmd.env("DROPPED", "1")
+        .env_clear()
+        .env("GREETING", "hello 'world'");
+    let (status, stdout) = output("command-env-clear", cmd);
+    assert_eq!(status, 0);
+    assert_eq!(stdout, "unset hello 'world'\n");
+}
+
+#[test]
+fn sorted_environment() {
+    let mut expected = hello_records();
+    expected.push(request(
+        client::NewSession {
+            request_id: 1,
+            want_tty: false,
+            want_x11_forwarding: false,
+            want_agent: false,
+            subsystem: false,
+            escape_char: b'~' as u32,
+            terminal_type: env::var("TERM").unwrap_or_else(|_| "xterm".into()).into(),
+            command: "true".into(),
+            environment: vec!["ALPHA=1".into(), "BETA=2".into(), "LC_TIME=C".into()],
+        }
+        .into(),
+        3,
+    ));
+    expected.push(response(
+        server::SessionOpened {
+            client_request_id: 1,
+            session_id: 1,
+        }
+        .into(),
+    ));
+
+    let path = socket_path("command-env-order");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let mut cmd = SshCommand::new("true");
+    let mut filter = EnvFilter::new();
+    filter.allow("*").deny("SECRET_*");
+    cmd.env("LC_TIME", "C")
+        .env("SECRET_TOKEN", "hunter2")
+        .env("BETA", "2")
+        .env("ALPHA", "1")
+        .env_filter(filter);
+    cmd.stdin(Pipe::dev_null().unwrap())
+        .stdout(Pipe::dev_null().unwrap())
+        .stderr(Pipe::dev_null().unwrap());
+    ctrl.new_session(cmd).unwrap();
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn subsystem_request() {
+    let mut expected = hello_records();
+    expected.push(request(
+        client::NewSession {
+            request_id: 1,
+            want_tty: false,
+            want_x11_forwarding: false,
+            want_agent: false,
+            subsystem: true,
+            escape_char: b'~' as u32,
+            terminal_type: env::var("TERM").unwrap_or_else(|_| "xterm".into()).into(),
+            command: "sftp".into(),
+            environment: vec!["LANG=C".into()],
+        }
+        .into(),
+        3,
+    ));
+    expected.push(response(
+        server::SessionOpened {
+            client_request_id: 1,
+            session_id: 1,
+        }
+        .into(),
+    ));
+
+    let path = socket_path("command-subsystem");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+    let mut ctrl = SshControl::new(&path).unwrap();
+    // The subsystem name is sent as is, without the directory change
+    let mut cmd = SshCommand::new("sftp");
+    cmd.subsystem(true)
+        .current_dir("/tmp")
+        .env("LANG", "C")
+        .stdin(Pipe::dev_null().unwrap())
+        .stdout(Pipe::dev_null().unwrap())
+        .stderr(Pipe::dev_null().unwrap());
+    ctrl.new_session(cmd).unwrap();
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn invalid_environment() {
+    let path = socket_path("command-env-invalid");
+    let master = Replayer::new(hello_records()).spawn(&path).unwrap();
+    let mut ctrl = SshControl::new(&path).unwrap();
+    for (key, value) in [("A=B", "1"), ("", "1"), ("NUL\0", "1"), ("VALUE", "a\0b")] {
+        let mut cmd = SshCommand::new("true");
+        cmd.env(key, value);
+        match ctrl.new_session(cmd) {
+            Err(Error::InvalidEnvironment(_)) => {}
+            other => panic!("unexpected {other:?} for {key:?}"),
+        }
+    }
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn env_filter_patterns() {
+    let filter: EnvFilter = "LANG LC_* TERM?".parse().unwrap();
+    assert!(filter.matches("LANG"));
+    assert!(filter.matches("LC_ALL"));
+    assert!(filter.matches("TERMX"));
+    assert!(!filter.matches("TERM"));
+    assert!(!filter.matches("LANGUAGE"));
+    assert!(!filter.matches("PATH"));
+
+    // As with SendEnv, a negated pattern removes the patterns it matches
+    let filter: EnvFilter = "LANG LC_* -LC_*".parse().unwrap();
+    assert!(filter.matches("LANG"));
+    assert!(!filter.matches("LC_ALL"));
+    let filter: EnvFilter = "LC_* -LC_ALL".parse().unwrap();
+    assert!(filter.matches("LC_ALL"));
+
+    assert!("*A*B*".parse::<EnvFilter>().unwrap().matches("xAyyBz"));
+    assert!(!"*A*B".parse::<EnvFilter>().unwrap().matches("xAyyBz"));
+    assert!("LANG -".parse::<EnvFilter>().is_err());
+}
+
+#[test]
+fn copy_env_filtered() {
+    env::set_var("SSH_CONTROL_TEST_COPIED", "yes");
+    env::set_var("SSH_CONTROL_TEST_OTHER", "no");
+    let mut cmd =
+        SshCommand::new("echo \"$SSH_CONTROL_TEST_COPIED ${SSH_CONTROL_TEST_OTHER:-unset}\"");
+    // The test master's shell inherits this process' environment
+    cmd.env_clear();
+    cmd.copy_env_filtered("SSH_CONTROL_TEST_C*").unwrap();
+    let (status, stdout) = output("command-env-filtered", cmd);
+    assert_eq!(status, 0);
+    assert_eq!(stdout, "yes unset\n");
+}
+
+#[test]
+fn tty_signals() {
+    let path = socket_path("command-tty-signal");
+    let master = spawn_shell_master(&path, 1);
+    let mut ctrl = SshControl::new(&path).unwrap();
+    // The test master runs commands without a TTY, so cat shows what the terminal would get
+    let mut cmd = SshCommand::new("cat");
+    cmd.want_tty(true)
+        .stdin(Pipe::new().unwrap())
+        .stdout(Pipe::new().unwrap())
+        .stderr(Pipe::dev_null().unwrap());
+    let mut child = ctrl.new_session(cmd).unwrap();
+    assert!(child.is_tty());
+
+    child.signal(libc::SIGINT).unwrap();
+    child.signal(libc::SIGTSTP).unwrap();
+    assert!(matches!(
+        child.signal(libc::SIGUSR1),
+        Err(Error::CannotSignal(_))
+    ));
+    child.signal(libc::SIGHUP).unwrap();
+    drop(child.stdin.take());
+    let mut typed = Vec::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_end(&mut typed)
+        .unwrap();
+    assert_eq!(typed, b"\x03\x1a\r~.");
+
+    assert_eq!(
+        ctrl.wait_timeout(&child, Duration::from_secs(10)).unwrap(),
+        Some(0)
+    );
+    master.join().unwrap();
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn kill_without_tty() {
+    let path = socket_path("command-kill");
+    let master = spawn_shell_master(&path, 1);
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let mut cmd = SshCommand::new("cat");
+    cmd.stdin(Pipe::new().unwrap())
+        .stdout(Pipe::new().unwrap())
+        .stderr(Pipe::new().unwrap());
+    let mut child = ctrl.new_session(cmd).unwrap();
+    assert!(!child.is_tty());
+    assert!(matches!(
+        child.signal(libc::SIGINT),
+        Err(Error::CannotSignal(_))
+    ));
+
+    child.kill().unwrap();
+    assert!(child.stdin.is_none() && child.stdout.is_none() && child.stderr.is_none());
+    assert_eq!(
+        ctrl.wait_timeout(&child, Duration::from_secs(10)).unwrap(),
+        Some(0)
+    );
+    master.join().unwrap();
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn wait_timeout() {
+    let path = socket_path("command-wait-timeout");
+    let master = spawn_shell_master(&path, 1);
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let mut cmd = SshCommand::new("sleep 1; exit 7");
+    cmd.stdin(Pipe::dev_null().unwrap())
+        .stdout(Pipe::dev_null().unwrap())
+        .stderr(Pipe::dev_null().unwrap());
+    let child = ctrl.new_session(cmd).unwrap();
+
+    assert_eq!(
+        ctrl.wait_timeout(&child, Duration::from_millis(50))
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        ctrl.wait_timeout(&child, Duration::from_secs(10)).unwrap(),
+        Some(7)
+    );
+    master.join().unwrap();
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn wait_timeout_mid_packet() {
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let (resume, resumed) = mpsc::channel();
+    let master = thread::spawn(move || {
+        let mut packet = handshake(&mut server);
+        let request: MuxMessage = packet.recv_next(&mut server).unwrap();
+        let MuxMessage::NewSession(session) = request.into_owned() else {
+            panic!("unexpected request");
+        };
+        for _ in 0..3 {
+            drop(unsafe { OwnedFd::from_raw_fd(server.recv_fd().unwrap()) });
+        }
+        let opened = server::SessionOpened {
+            client_request_id: session.request_id,
+            session_id: 1,
+        };
+        packet.set(&MuxResponse::from(opened)).unwrap();
+        packet.serialize(&mut server).unwrap();
+
+        // The exit message is written in two parts, the second after the client timed out
+        let mut exit: Packet = Vec::new().into();
+        let exit_message = server::ExitMessage {
+            session_id: 1,
+            exit_value: 0,
+        };
+        exit.set(&MuxResponse::from(exit_message)).unwrap();
+        let mut bytes = Vec::new();
+        exit.serialize(&mut bytes).unwrap();
+        let (first, second) = bytes.split_at(6);
+        server.write_all(first).unwrap();
+        resumed.recv().unwrap();
+        server.write_all(second).unwrap();
+        server
+    });
+
+    let mut ctrl = SshControl::with_socket(client).unwrap();
+    let mut cmd = SshCommand::new("true");
+    cmd.stdin(Pipe::dev_null().unwrap())
+        .stdout(Pipe::dev_null().unwrap())
+        .stderr(Pipe::dev_null().unwrap());
+    let child = ctrl.new_session(cmd).unwrap();
+
+    let err = ctrl
+        .wait_timeout(&child, Duration::from_millis(100))
+        .unwrap_err();
+    assert!(matches!(err, Error::IO(_)), "{err:?}");
+    resume.send(()).unwrap();
+    let _server = master.join().unwrap();
+
+    // The rest of the exit message is not taken for a new packet
+    assert!(matches!(
+        ctrl.wait_timeout(&child, Duration::from_secs(10)),
+        Err(Error::Desynchronized)
+    ));
+    assert!(matches!(ctrl.check_alive(), Err(Error::Desynchronized)));
+    assert!(matches!(ctrl.into_handle(), Err(Error::Desynchronized)));
+}
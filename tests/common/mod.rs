@@ -0,0 +1,276 @@
+#![allow(dead_code)]
+
+use std::{
+    env,
+    io::{self, Read, Write},
+    os::unix::{io::RawFd, net::UnixStream},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use passfd::FdPassingExt;
+
+use ssh_control::{
+    record::{Direction, Message, Record},
+    ControlSocket, Hello, MuxMessage, MuxResponse, Packet, Wire,
+};
+
+pub fn socket_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("ssh-control-{}-{name}", process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Performs the handshake of a client connection by hand, returning the buffer to use for the
+/// next packets
+pub fn handshake(socket: &mut UnixStream) -> Packet {
+    let mut packet: Packet = Vec::new().into();
+    let hello = Hello {
+        version: 4,
+        extensions: Vec::new(),
+    };
+    packet.set(&hello).unwrap();
+    packet.serialize(socket).unwrap();
+    let _: Hello = packet.recv_next(socket).unwrap();
+    packet
+}
+
+/// Writes `body` as a packet, whether it is a valid message or not
+pub fn write_body(socket: &mut UnixStream, body: &[u8]) {
+    socket
+        .write_all(&(body.len() as u32).to_be_bytes())
+        .unwrap();
+    socket.write_all(body).unwrap();
+}
+
+pub fn record<'a, T>(direction: Direction, value: &'a T, message: Message, fds: usize) -> Record
+where
+    T: Wire<'a>,
+{
+    let mut packet: Packet = Vec::new().into();
+    packet.set(value).unwrap();
+    let mut raw = Vec::new();
+    packet.serialize(&mut raw).unwrap();
+    Record {
+        direction,
+        timestamp: SystemTime::now(),
+        packet: raw,
+        message: Some(message),
+        fds,
+    }
+}
+
+pub fn hello_records() -> Vec<Record> {
+    let hello = Hello {
+        version: 4,
+        extensions: Vec::new(),
+    };
+    vec![
+        record(Direction::Sent, &hello, Message::Hello(hello.clone()), 0),
+        record(
+            Direction::Received,
+            &hello,
+            Message::Hello(hello.clone()),
+            0,
+        ),
+    ]
+}
+
+pub fn request(msg: MuxMessage<'static>, fds: usize) -> Record {
+    record(Direction::Sent, &msg, Message::Request(msg.clone()), fds)
+}
+
+pub fn response(msg: MuxResponse<'static>) -> Record {
+    record(Direction::Received, &msg, Message::Response(msg.clone()), 0)
+}
+
+/// Master answering a single `NewSession` by writing `stdout` and `stderr` to the passed pipes,
+/// then exiting with `status`
+pub fn spawn_exec_master(
+    path: &std::path::Path,
+    stdout: &'static [u8],
+    stderr: &'static [u8],
+    status: u32,
+) -> std::thread::JoinHandle<()> {
+    use std::{
+        fs::File,
+        io::Write,
+        os::unix::{io::FromRawFd, net::UnixListener},
+    };
+
+    use ssh_control::server;
+
+    let listener = UnixListener::bind(path).unwrap();
+    std::thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut packet: Packet = Vec::new().into();
+        let hello: Hello = packet.recv_next(&mut socket).unwrap();
+        let hello = hello.into_owned();
+        packet.set(&hello).unwrap();
+        packet.serialize(&mut socket).unwrap();
+
+        let request: MuxMessage = packet.recv_next(&mut socket).unwrap();
+        let MuxMessage::NewSession(session) = request else {
+            panic!("Unexpected request {request:?}");
+        };
+        let request_id = session.request_id;
+        let fds: Vec<_> = (0..3)
+            .map(|_| unsafe { File::from_raw_fd(socket.recv_fd().unwrap()) })
+            .collect();
+        let opened: MuxResponse = server::SessionOpened {
+            client_request_id: request_id,
+            session_id: 7,
+        }
+        .into();
+        packet.set(&opened).unwrap();
+        packet.serialize(&mut socket).unwrap();
+
+        let [_stdin, mut out, mut err] = <[File; 3]>::try_from(fds).unwrap();
+        out.write_all(stdout).unwrap();
+        err.write_all(stderr).unwrap();
+        drop((out, err));
+
+        let exit: MuxResponse = server::ExitMessage {
+            session_id: 7,
+            exit_value: status,
+        }
+        .into();
+        packet.set(&exit).unwrap();
+        packet.serialize(&mut socket).unwrap();
+    })
+}
+
+/// Master running the commands of `connections` successive connections with `sh -c` on the
+/// local host, one session per connection like OpenSSH
+pub fn spawn_shell_master(
+    path: &std::path::Path,
+    connections: usize,
+) -> std::thread::JoinHandle<()> {
+    use std::{
+        os::unix::{
+            io::{FromRawFd, OwnedFd},
+            net::UnixListener,
+        },
+        process::{Command, Stdio},
+    };
+
+    use ssh_control::server;
+
+    let listener = UnixListener::bind(path).unwrap();
+    std::thread::spawn(move || {
+        for _ in 0..connections {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut packet: Packet = Vec::new().into();
+            let hello: Hello = packet.recv_next(&mut socket).unwrap();
+            let hello = hello.into_owned();
+            packet.set(&hello).unwrap();
+            packet.serialize(&mut socket).unwrap();
+
+            let request: MuxMessage = packet.recv_next(&mut socket).unwrap();
+            let MuxMessage::NewSession(session) = request.into_owned() else {
+                continue;
+            };
+            let mut fds: Vec<_> = (0..3)
+                .map(|_| {
+                    let fd = socket.recv_fd().unwrap();
+                    unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+                    unsafe { OwnedFd::from_raw_fd(fd) }
+                })
+                .collect();
+            let stderr = fds.pop().unwrap();
+            let stdout = fds.pop().unwrap();
+            let stdin = fds.pop().unwrap();
+            let mut command = if session.subsystem {
+                match &*session.command {
+                    "sftp" => Command::new(sftp_server().expect("no sftp-server")),
+                    subsystem => Command::new(subsystem),
+                }
+            } else {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(&*session.command);
+                command
+            };
+            for var in &session.environment {
+                if let Some((key, value)) = var.split_once('=') {
+                    command.env(key, value);
+                }
+            }
+            let mut child = command
+                .stdin(Stdio::from(stdin))
+                .stdout(Stdio::from(stdout))
+                .stderr(Stdio::from(stderr))
+                .spawn()
+                .unwrap();
+            // Drop the command, which holds the parent's copy of the fds
+            drop(command);
+
+            let opened: MuxResponse = server::SessionOpened {
+                client_request_id: session.request_id,
+                session_id: 1,
+            }
+            .into();
+            packet.set(&opened).unwrap();
+            packet.serialize(&mut socket).unwrap();
+
+            let status = child.wait().unwrap();
+            let exit: MuxResponse = server::ExitMessage {
+                session_id: 1,
+                exit_value: status.code().unwrap_or(255) as u32,
+            }
+            .into();
+            packet.set(&exit).unwrap();
+            let _ = packet.serialize(&mut socket);
+        }
+    })
+}
+
+/// Local `sftp-server`, from `$SFTP_SERVER` or the usual locations
+pub fn sftp_server() -> Option<PathBuf> {
+    env::var_os("SFTP_SERVER").map(PathBuf::from).or_else(|| {
+        [
+            "/usr/lib/openssh/sftp-server",
+            "/usr/libexec/openssh/sftp-server",
+            "/usr/libexec/sftp-server",
+            "/usr/lib/ssh/sftp-server",
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .find(|path| path.exists())
+    })
+}
+
+/// Socket failing the next write once `fail` is set
+pub struct FailingWrite {
+    pub socket: UnixStream,
+    pub fail: Arc<AtomicBool>,
+}
+
+impl Read for FailingWrite {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.read(buf)
+    }
+}
+
+impl Write for FailingWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.fail.swap(false, Ordering::Relaxed) {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl ControlSocket for FailingWrite {
+    fn send_fd(&mut self, fd: RawFd) -> io::Result<()> {
+        self.socket.send_fd(fd)
+    }
+}
@@ -0,0 +1,35 @@
+use std::borrow::Cow;
+
+use nom::error::VerboseError;
+use ssh_control::Wire;
+
+#[derive(Debug, PartialEq, Wire)]
+struct Custom<'a> {
+    id: u32,
+    #[wire(reserved_before)]
+    flag: bool,
+    #[wire(terminator = b"\xff\xff\xff\xff")]
+    names: Vec<Cow<'a, str>>,
+    trailer: Cow<'a, str>,
+}
+
+#[test]
+fn derive_outside_crate() {
+    let value = Custom {
+        id: 7,
+        flag: true,
+        names: vec!["a".into(), "bc".into()],
+        trailer: "end".into(),
+    };
+    let mut raw = Vec::new();
+    value.serialize(&mut raw).unwrap();
+    assert_eq!(
+        &raw[..],
+        b"\0\0\0\x07\0\0\0\0\0\0\0\x01\0\0\0\x01a\0\0\0\x02bc\xff\xff\xff\xff\0\0\0\x03end"
+    );
+
+    let (rest, parsed) = Custom::parse::<VerboseError<&[u8]>>(&raw[..]).unwrap();
+    assert!(rest.is_empty());
+    assert_eq!(parsed, value);
+    let _: Custom<'static> = parsed.into_owned();
+}
@@ -0,0 +1,73 @@
+use ssh_control::dissect::{decode_hex, dissect};
+
+const NEW_SESSION: &[u8] = b"\x00\x00\x00\x34\
+    \x10\x00\x00\x02\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\
+    \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x7e\
+    \x00\x00\x00\x05xterm\x00\x00\x00\x07echo hi";
+
+#[test]
+fn annotates_every_field() {
+    let packets = dissect(NEW_SESSION);
+    assert_eq!(packets.len(), 1);
+    let packet = &packets[0];
+    assert_eq!(packet.name, Some("MUX_C_NEW_SESSION"));
+    assert_eq!(packet.length, Some(0x34));
+    assert_eq!(packet.error, None);
+
+    let names: Vec<_> = packet.fields.iter().map(|f| &*f.name).collect();
+    assert_eq!(
+        names,
+        [
+            "length",
+            "type",
+            "request_id",
+            "reserved",
+            "want_tty",
+            "want_x11_forwarding",
+            "want_agent",
+            "subsystem",
+            "escape_char",
+            "terminal_type",
+            "command",
+        ]
+    );
+    let command = packet.fields.last().unwrap();
+    assert_eq!((command.offset, command.len), (45, 11));
+    assert_eq!(command.value, "7 bytes \"echo hi\"");
+}
+
+#[test]
+fn reports_truncated_packets() {
+    let packets = dissect(&NEW_SESSION[..50]);
+    assert_eq!(packets.len(), 1);
+    let packet = &packets[0];
+    assert_eq!(packet.fields.last().unwrap().name, "terminal_type");
+    let error = packet.error.as_deref().unwrap();
+    assert!(
+        error.contains("truncated packet: 46 of 52 bytes"),
+        "{error}"
+    );
+    assert!(error.contains("command"), "{error}");
+}
+
+#[test]
+fn continues_after_bad_packet() {
+    let mut data = b"\x00\x00\x00\x08\x12\x34\x56\x78\x00\x00\x00\x01".to_vec();
+    data.extend_from_slice(b"\x00\x00\x00\x0c\x80\x00\x00\x05\x00\x00\x00\x01\x00\x00\x04\xd2");
+    let packets = dissect(&data);
+    assert_eq!(packets.len(), 2);
+    assert!(packets[0].error.as_deref().unwrap().contains("0x12345678"));
+    assert_eq!(packets[1].offset, 12);
+    assert_eq!(packets[1].name, Some("MUX_S_ALIVE"));
+    assert_eq!(packets[1].error, None);
+}
+
+#[test]
+fn hex_input() {
+    assert_eq!(
+        decode_hex("0x0000 0004\n1000 0004\n"),
+        Some(b"\x00\x00\x00\x04\x10\x00\x00\x04".to_vec())
+    );
+    assert_eq!(decode_hex("abc"), None);
+    assert_eq!(decode_hex("zz"), None);
+}
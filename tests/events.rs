@@ -0,0 +1,147 @@
+use std::{
+    fs,
+    io::{self, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    thread,
+};
+
+use futures::{SinkExt, StreamExt};
+use ssh_control::{
+    codec::MuxCodec,
+    command::{Pipe, SessionEvent, SessionEventStream, SshCommand},
+    record::Direction,
+    server, Error, MuxResponse, SshControl,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+mod common;
+use common::{hello_records, response, socket_path, spawn_shell_master};
+
+fn piped(command: &str) -> SshCommand {
+    let mut cmd = SshCommand::new(command);
+    cmd.stdin(Pipe::dev_null().unwrap())
+        .stdout(Pipe::dev_null().unwrap())
+        .stderr(Pipe::dev_null().unwrap());
+    cmd
+}
+
+#[test]
+fn opened_then_exited() {
+    let path = socket_path("events-exit");
+    let master = spawn_shell_master(&path, 1);
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let child = ctrl.new_session(piped("exit 3")).unwrap();
+
+    let events: Vec<_> = child.events(&mut ctrl).collect();
+    assert!(matches!(
+        events[..],
+        [
+            SessionEvent::Opened { session_id: 1 },
+            SessionEvent::Exited(3)
+        ]
+    ));
+    master.join().unwrap();
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn tty_failure_then_connection_lost() {
+    let mut records = hello_records();
+    records.push(response(
+        server::SessionOpened {
+            client_request_id: 1,
+            session_id: 4,
+        }
+        .into(),
+    ));
+    records.push(response(server::TtyAllocFail { session_id: 4 }.into()));
+
+    // Master sending its responses, then closing the connection
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let master = thread::spawn(move || {
+        for record in records
+            .iter()
+            .filter(|r| r.direction == Direction::Received)
+        {
+            server.write_all(&record.packet).unwrap();
+        }
+        server.shutdown(Shutdown::Write).unwrap();
+        io::copy(&mut server, &mut io::sink()).unwrap();
+    });
+
+    let mut ctrl = SshControl::with_socket(client).unwrap();
+    let mut cmd = piped("top");
+    cmd.want_tty(true);
+    let child = ctrl.new_session(cmd).unwrap();
+    assert_eq!(child.session_id(), 4);
+
+    let mut events = child.events(&mut ctrl);
+    assert!(matches!(
+        events.next(),
+        Some(SessionEvent::Opened { session_id: 4 })
+    ));
+    assert!(matches!(events.next(), Some(SessionEvent::TtyAllocFailed)));
+    match events.next() {
+        Some(SessionEvent::ConnectionLost(Error::IO(_))) => {}
+        other => panic!("unexpected {other:?}"),
+    }
+    assert!(events.next().is_none());
+    drop(ctrl);
+    master.join().unwrap();
+}
+
+#[tokio::test]
+async fn event_stream() {
+    let (client, server) = tokio::net::UnixStream::pair().unwrap();
+    let mut server = FramedWrite::new(server, MuxCodec::<MuxResponse>::new());
+    let responses = FramedRead::new(client, MuxCodec::<MuxResponse>::new());
+
+    let sent: [MuxResponse; 3] = [
+        server::TtyAllocFail { session_id: 2 }.into(),
+        server::ExitMessage {
+            session_id: 2,
+            exit_value: 0,
+        }
+        .into(),
+        server::ExitMessage {
+            session_id: 2,
+            exit_value: 1,
+        }
+        .into(),
+    ];
+    for response in sent {
+        server.send(response).await.unwrap();
+    }
+
+    let events: Vec<_> = SessionEventStream::new(responses, 2).collect().await;
+    assert!(matches!(
+        events[..],
+        [
+            SessionEvent::Opened { session_id: 2 },
+            SessionEvent::TtyAllocFailed,
+            SessionEvent::Exited(0)
+        ]
+    ));
+}
+
+#[tokio::test]
+async fn event_stream_unexpected_response() {
+    let (client, server) = tokio::net::UnixStream::pair().unwrap();
+    let mut server = FramedWrite::new(server, MuxCodec::<MuxResponse>::new());
+    let responses = FramedRead::new(client, MuxCodec::<MuxResponse>::new());
+    let other: MuxResponse = server::ExitMessage {
+        session_id: 9,
+        exit_value: 0,
+    }
+    .into();
+    server.send(other).await.unwrap();
+    drop(server);
+
+    let mut events = SessionEventStream::new(responses, 2).skip(1);
+    assert!(matches!(
+        events.next().await,
+        Some(SessionEvent::ConnectionLost(Error::InvalidPacket { .. }))
+    ));
+    assert!(events.next().await.is_none());
+}
@@ -0,0 +1,70 @@
+use ssh_control::{
+    command::{Pipe, SshCommand},
+    fleet::{Fleet, Target},
+    Error, SshControl,
+};
+
+mod common;
+use common::{socket_path, spawn_exec_master};
+
+#[test]
+fn wait_with_output() {
+    let path = socket_path("exec");
+    let master = spawn_exec_master(&path, b"hello\n", b"warning\n", 3);
+
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let mut cmd = SshCommand::new("hello");
+    cmd.stdin(Pipe::dev_null().unwrap());
+    cmd.stdout(Pipe::new().unwrap());
+    cmd.stderr(Pipe::new().unwrap());
+    let child = ctrl.new_session(cmd).unwrap();
+    let output = ctrl.wait_with_output(child).unwrap();
+    assert_eq!(output.stdout, b"hello\n");
+    assert_eq!(output.stderr, b"warning\n");
+    assert_eq!(output.status, 3);
+    assert!(!output.success());
+
+    master.join().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn run_on_fleet() {
+    let outputs: [&[u8]; 3] = [b"one\n", b"two\n", b"three\n"];
+    let mut targets = Vec::new();
+    let mut masters = Vec::new();
+    for (i, stdout) in outputs.into_iter().enumerate() {
+        let path = socket_path(&format!("fleet-{i}"));
+        masters.push(spawn_exec_master(&path, stdout, b"", i as u32));
+        targets.push(Target::new(format!("host{i}"), path));
+    }
+    let missing = socket_path("fleet-missing");
+    targets.insert(1, Target::new("missing", &missing));
+
+    let mut fleet = Fleet::new(targets.clone());
+    fleet.concurrency(2);
+    let results = fleet.run("hostname");
+
+    assert_eq!(results.len(), 4);
+    for (result, target) in results.iter().zip(&targets) {
+        assert_eq!(&result.target, target);
+    }
+    assert!(matches!(results[1].output, Err(Error::IO(_))));
+    let successful: Vec<_> = [0, 2, 3]
+        .iter()
+        .map(|&i| results[i].output.as_ref().unwrap())
+        .collect();
+    assert_eq!(successful[0].stdout, b"one\n");
+    assert!(successful[0].success());
+    assert_eq!(successful[1].stdout, b"two\n");
+    assert_eq!(successful[1].status, 1);
+    assert_eq!(successful[2].stdout, b"three\n");
+    assert_eq!(successful[2].status, 2);
+
+    for master in masters {
+        master.join().unwrap();
+    }
+    for target in &targets {
+        let _ = std::fs::remove_file(&target.control_path);
+    }
+}
@@ -0,0 +1,586 @@
+use std::{
+    io::{self, Read, Write},
+    os::unix::{io::RawFd, net::UnixStream},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use passfd::FdPassingExt;
+
+use ssh_control::{
+    client::{self, ForwardingType, Port},
+    forward::{Endpoint, Forward},
+    handle::SshControlHandle,
+    record::{Record, Replayer},
+    server, ControlSocket, Error, SshControl,
+};
+
+mod common;
+use common::{hello_records, request, response, socket_path, FailingWrite};
+
+fn open(request_id: u32, forwarding_type: ForwardingType, listen_port: u16) -> Record {
+    request(
+        client::OpenFwd {
+            request_id,
+            forwarding_type,
+            listen_host: "localhost".into(),
+            listen_port: Port::Inet(listen_port),
+            connect_host: "db".into(),
+            connect_port: Port::Inet(5432),
+        }
+        .into(),
+        0,
+    )
+}
+
+fn close(request_id: u32, forwarding_type: ForwardingType, listen_port: u16) -> Record {
+    request(
+        client::CloseFwd {
+            request_id,
+            forwarding_type,
+            listen_host: "localhost".into(),
+            listen_port: Port::Inet(listen_port),
+            connect_host: "db".into(),
+            connect_port: Port::Inet(5432),
+        }
+        .into(),
+        0,
+    )
+}
+
+fn ok(client_request_id: u32) -> Record {
+    response(server::Ok { client_request_id }.into())
+}
+
+#[test]
+fn forwards_are_closed_explicitly_or_on_drop() {
+    let mut expected = hello_records();
+    expected.push(open(1, ForwardingType::Local, 15432));
+    expected.push(ok(1));
+    expected.push(open(2, ForwardingType::Remote, 0));
+    expected.push(response(
+        server::RemotePort {
+            client_request_id: 2,
+            allocated_remote_listen_port: 40000,
+        }
+        .into(),
+    ));
+    expected.push(close(3, ForwardingType::Local, 15432));
+    expected.push(ok(3));
+    expected.push(close(4, ForwardingType::Remote, 0));
+    expected.push(ok(4));
+
+    let path = socket_path("forward");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let local = ctrl
+        .open_forward(
+            ForwardingType::Local,
+            ("localhost", 15432),
+            Some(Endpoint::tcp("db", 5432)),
+        )
+        .unwrap();
+    assert_eq!(local.allocated_port(), None);
+    let remote = ctrl
+        .open_forward(
+            ForwardingType::Remote,
+            ("localhost", 0),
+            Some(Endpoint::tcp("db", 5432)),
+        )
+        .unwrap();
+    assert_eq!(remote.forwarding_type(), ForwardingType::Remote);
+    assert_eq!(remote.connect(), Some(Endpoint::tcp("db", 5432)));
+    assert_eq!(remote.allocated_port(), Some(40000));
+    assert_eq!(ctrl.forwards().len(), 2);
+
+    drop(local);
+    assert_eq!(ctrl.forwards(), [remote.request().clone()]);
+    ctrl.close_forward(remote).unwrap();
+    assert!(ctrl.forwards().is_empty());
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+fn open_local(ctrl: &mut SshControl, listen_port: u16) -> Forward {
+    ctrl.open_forward(
+        ForwardingType::Local,
+        ("localhost", listen_port),
+        Some(Endpoint::tcp("db", 5432)),
+    )
+    .unwrap()
+}
+
+#[test]
+fn dropped_forwards_are_closed_with_the_connection() {
+    let mut expected = hello_records();
+    expected.push(open(1, ForwardingType::Local, 15432));
+    expected.push(ok(1));
+    expected.push(open(2, ForwardingType::Local, 15433));
+    expected.push(ok(2));
+    // The first close is refused, which neither fails the next request nor loses the second one
+    expected.push(close(3, ForwardingType::Local, 15432));
+    expected.push(response(
+        server::Failure {
+            client_request_id: 3,
+            reason: "no such forward".into(),
+        }
+        .into(),
+    ));
+    expected.push(request(client::AliveCheck { request_id: 4 }.into(), 0));
+    expected.push(response(
+        server::Alive {
+            client_request_id: 4,
+            server_pid: 1,
+        }
+        .into(),
+    ));
+    expected.push(close(5, ForwardingType::Local, 15433));
+    expected.push(ok(5));
+
+    let path = socket_path("forward-drop-connection");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let first = open_local(&mut ctrl, 15432);
+    let second = open_local(&mut ctrl, 15433);
+    drop(first);
+    drop(second);
+    assert_eq!(ctrl.check_alive().unwrap(), 1);
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+/// Socket without read timeouts
+struct NoTimeout(UnixStream);
+
+impl Read for NoTimeout {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for NoTimeout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl ControlSocket for NoTimeout {
+    fn send_fd(&mut self, fd: RawFd) -> io::Result<()> {
+        self.0.send_fd(fd)
+    }
+}
+
+#[test]
+fn dropped_forwards_are_kept_without_read_timeout() {
+    // Closing could wait forever for the master, so neither dropping the connection nor taking
+    // its socket does
+    for take_socket in [false, true] {
+        let mut expected = hello_records();
+        expected.push(open(1, ForwardingType::Local, 15432));
+        expected.push(ok(1));
+
+        let path = socket_path(&format!("forward-drop-no-timeout-{take_socket}"));
+        let master = Replayer::new(expected).spawn(&path).unwrap();
+
+        let socket = NoTimeout(UnixStream::connect(&path).unwrap());
+        let mut ctrl = SshControl::with_socket(socket).unwrap();
+        let forward = ctrl
+            .open_forward(
+                ForwardingType::Local,
+                ("localhost", 15432),
+                Some(Endpoint::tcp("db", 5432)),
+            )
+            .unwrap();
+        drop(forward);
+        if take_socket {
+            drop(ctrl.into_socket());
+        } else {
+            drop(ctrl);
+        }
+
+        master.join().unwrap().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+#[test]
+fn unsent_close_is_retried() {
+    let mut expected = hello_records();
+    expected.push(open(1, ForwardingType::Local, 15432));
+    expected.push(ok(1));
+    // Request 2 could not be sent
+    expected.push(close(3, ForwardingType::Local, 15432));
+    expected.push(ok(3));
+
+    let path = socket_path("forward-close-retried");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+
+    let fail = Arc::new(AtomicBool::new(false));
+    let socket = FailingWrite {
+        socket: UnixStream::connect(&path).unwrap(),
+        fail: Arc::clone(&fail),
+    };
+    let mut ctrl = SshControl::with_socket(socket).unwrap();
+    let forward = ctrl
+        .open_forward(
+            ForwardingType::Local,
+            ("localhost", 15432),
+            Some(Endpoint::tcp("db", 5432)),
+        )
+        .unwrap();
+    fail.store(true, Ordering::Relaxed);
+    assert!(matches!(ctrl.close_forward(forward), Err(Error::IO(_))));
+    assert!(ctrl.forwards().is_empty());
+    ctrl.close_dropped_forwards().unwrap();
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn dropped_forwards_are_closed_with_the_handle() {
+    let mut expected = hello_records();
+    expected.push(open(1, ForwardingType::Local, 15432));
+    expected.push(ok(1));
+    expected.push(close(2, ForwardingType::Local, 15432));
+    expected.push(ok(2));
+
+    let path = socket_path("forward-drop-handle");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+
+    let handle = SshControlHandle::new(&path).unwrap();
+    let clone = handle.clone();
+    let forward = handle
+        .open_forward(
+            ForwardingType::Local,
+            ("localhost", 15432),
+            Some(Endpoint::tcp("db", 5432)),
+        )
+        .unwrap();
+    drop(forward);
+    drop(handle);
+    // Closed once the last clone is dropped
+    drop(clone);
+
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn forward_closed_on_another_connection() {
+    let mut expected = hello_records();
+    expected.push(open(1, ForwardingType::Local, 15432));
+    expected.push(ok(1));
+    expected.push(close(2, ForwardingType::Local, 15432));
+    expected.push(ok(2));
+
+    let path = socket_path("forward-owner");
+    let other_path = socket_path("forward-other");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+    let other_master = Replayer::new(hello_records()).spawn(&other_path).unwrap();
+
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let mut other = SshControl::new(&other_path).unwrap();
+    let forward = open_local(&mut ctrl, 15432);
+    assert!(matches!(
+        other.close_forward(forward),
+        Err(Error::InvalidUsage(_))
+    ));
+    drop(other);
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    other_master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&other_path);
+}
+
+#[test]
+fn denied_forward_is_not_tracked() {
+    let mut expected = hello_records();
+    expected.push(open(1, ForwardingType::Local, 15432));
+    expected.push(response(
+        server::PermissionDenied {
+            client_request_id: 1,
+            reason: "forwarding disabled".into(),
+        }
+        .into(),
+    ));
+
+    let path = socket_path("forward-denied");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let err = ctrl
+        .open_forward(
+            ForwardingType::Local,
+            ("localhost", 15432),
+            Some(Endpoint::tcp("db", 5432)),
+        )
+        .unwrap_err();
+    assert!(matches!(err, Error::PermissionDenied(_)), "{err:?}");
+    assert!(ctrl.forwards().is_empty());
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn unix_socket_forward() {
+    let mut expected = hello_records();
+    expected.push(request(
+        client::OpenFwd {
+            request_id: 1,
+            forwarding_type: ForwardingType::Local,
+            listen_host: "/tmp/docker.sock".into(),
+            listen_port: Port::Unix,
+            connect_host: "/var/run/docker.sock".into(),
+            connect_port: Port::Unix,
+        }
+        .into(),
+        0,
+    ));
+    expected.push(ok(1));
+
+    let path = socket_path("forward-unix");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let forward = ctrl
+        .open_forward(
+            ForwardingType::Local,
+            Path::new("/tmp/docker.sock"),
+            Some(Endpoint::unix("/var/run/docker.sock")),
+        )
+        .unwrap();
+    assert_eq!(forward.listen(), Endpoint::unix("/tmp/docker.sock"));
+    // Dropped after the connection, so the forward is left open
+    drop(ctrl);
+    drop(forward);
+
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn endpoint_validation() {
+    assert!(matches!(
+        Endpoint::from_wire("", Port::Unix),
+        Err(Error::InvalidEndpoint(_))
+    ));
+    assert_eq!(
+        Endpoint::from_wire("/run/app.sock", Port::Unix).unwrap(),
+        Endpoint::unix("/run/app.sock")
+    );
+    assert_eq!(
+        Endpoint::from_wire("", Port::Inet(8080)).unwrap(),
+        Endpoint::tcp("", 8080)
+    );
+    assert!(Endpoint::unix("").to_wire().is_err());
+    assert!(Endpoint::tcp("a\0b", 22).to_wire().is_err());
+
+    assert_eq!(Endpoint::tcp("::1", 22).to_string(), "[::1]:22");
+    assert_eq!(Endpoint::tcp("db", 5432).to_string(), "db:5432");
+    assert_eq!(Endpoint::unix("/run/app.sock").to_string(), "/run/app.sock");
+}
+
+#[test]
+fn connect_endpoint_matches_forwarding_type() {
+    let path = socket_path("forward-invalid");
+    let master = Replayer::new(hello_records()).spawn(&path).unwrap();
+
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let err = ctrl
+        .open_forward(ForwardingType::Local, ("", 8080), None)
+        .unwrap_err();
+    assert!(matches!(err, Error::InvalidEndpoint(_)), "{err:?}");
+    let err = ctrl
+        .open_forward(
+            ForwardingType::Dynamic,
+            ("", 1080),
+            Some(Endpoint::tcp("db", 5432)),
+        )
+        .unwrap_err();
+    assert!(matches!(err, Error::InvalidEndpoint(_)), "{err:?}");
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn parse_forward_specs() {
+    let parse = |spec: &str| spec.parse::<client::OpenFwd>().unwrap();
+    let fwd =
+        |forwarding_type, listen_host: &str, listen_port, connect_host: &str, connect_port| {
+            client::OpenFwd {
+                request_id: 0,
+                forwarding_type,
+                listen_host: listen_host.to_owned().into(),
+                listen_port,
+                connect_host: connect_host.to_owned().into(),
+                connect_port,
+            }
+        };
+
+    assert_eq!(
+        parse("-L 8080:localhost:80"),
+        fwd(
+            ForwardingType::Local,
+            "",
+            Port::Inet(8080),
+            "localhost",
+            Port::Inet(80)
+        )
+    );
+    assert_eq!(
+        parse("-L*:8080:[2001:db8::1]:80"),
+        fwd(
+            ForwardingType::Local,
+            "*",
+            Port::Inet(8080),
+            "2001:db8::1",
+            Port::Inet(80)
+        )
+    );
+    assert_eq!(
+        parse("-L [::1]:8080:db:5432"),
+        fwd(
+            ForwardingType::Local,
+            "::1",
+            Port::Inet(8080),
+            "db",
+            Port::Inet(5432)
+        )
+    );
+    assert_eq!(
+        parse("-L /tmp/local.sock:/var/run/docker.sock"),
+        fwd(
+            ForwardingType::Local,
+            "/tmp/local.sock",
+            Port::Unix,
+            "/var/run/docker.sock",
+            Port::Unix
+        )
+    );
+    assert_eq!(
+        parse("-L 2375:/var/run/docker.sock"),
+        fwd(
+            ForwardingType::Local,
+            "",
+            Port::Inet(2375),
+            "/var/run/docker.sock",
+            Port::Unix
+        )
+    );
+    assert_eq!(
+        parse("-R /tmp/remote.sock:localhost:22"),
+        fwd(
+            ForwardingType::Remote,
+            "/tmp/remote.sock",
+            Port::Unix,
+            "localhost",
+            Port::Inet(22)
+        )
+    );
+    assert_eq!(
+        parse("-R 0:localhost:22"),
+        fwd(
+            ForwardingType::Remote,
+            "",
+            Port::Inet(0),
+            "localhost",
+            Port::Inet(22)
+        )
+    );
+    assert_eq!(
+        parse("-R 1080"),
+        fwd(
+            ForwardingType::Remote,
+            "",
+            Port::Inet(1080),
+            "",
+            Port::Inet(0)
+        )
+    );
+    assert_eq!(
+        parse("-D localhost:1080"),
+        fwd(
+            ForwardingType::Dynamic,
+            "localhost",
+            Port::Inet(1080),
+            "",
+            Port::Inet(0)
+        )
+    );
+    assert_eq!(
+        parse(r"-L 8080:we\:ird:80"),
+        fwd(
+            ForwardingType::Local,
+            "",
+            Port::Inet(8080),
+            "we:ird",
+            Port::Inet(80)
+        )
+    );
+
+    for invalid in [
+        "8080:localhost:80",
+        "-L 8080",
+        "-L 0:localhost:80",
+        "-L 8080:localhost:0",
+        "-L 8080:localhost:http",
+        "-L 70000:localhost:80",
+        "-L a:1:b:2:c",
+        "-L [::1:8080:db:80",
+        "-D 1080:db:80",
+    ] {
+        assert!(invalid.parse::<client::OpenFwd>().is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn forward_specs_round_trip() {
+    for spec in [
+        "-L 8080:localhost:80",
+        "-L *:8080:[2001:db8::1]:80",
+        "-L [::1]:8080:db:5432",
+        "-L /tmp/local.sock:/var/run/docker.sock",
+        "-L 2375:/var/run/docker.sock",
+        "-L localhost:2375:/var/run/docker.sock",
+        "-R /tmp/remote.sock:localhost:22",
+        "-R 0:localhost:22",
+        "-R 1080",
+        "-D localhost:1080",
+        "-D /tmp/socks.sock",
+        r"-L 8080:we\:ird\/host:80",
+        r"-L /tmp/odd\:name.sock:db:80",
+    ] {
+        assert_eq!(spec.parse::<client::OpenFwd>().unwrap().to_string(), spec);
+    }
+
+    let odd = client::OpenFwd {
+        request_id: 0,
+        forwarding_type: ForwardingType::Local,
+        listen_host: "a[b".into(),
+        listen_port: Port::Inet(1),
+        connect_host: "c\\d/e".into(),
+        connect_port: Port::Inet(2),
+    };
+    assert_eq!(odd.to_string().parse::<client::OpenFwd>().unwrap(), odd);
+}
@@ -0,0 +1,322 @@
+use std::{
+    borrow::Cow,
+    os::unix::net::{UnixListener, UnixStream},
+    thread,
+};
+
+use ssh_control::{
+    client::{self, ForwardingType, Port},
+    gateway::Policy,
+    proxy::{Proxy, Verdict},
+    record::Replayer,
+    server, Error, MuxMessage, MuxResponse, Wire,
+};
+
+mod common;
+use common::{handshake, hello_records, socket_path, write_body};
+
+const POLICY: &str = r#"
+[session]
+allow = ['^uptime$', '^ls( .*)?$']
+deny = ['\.\.']
+
+[forward]
+types = ["Local", "Dynamic"]
+destinations = ['^localhost:(80|443)$', '^/var/run/docker\.sock$']
+"#;
+
+fn session(command: &'static str) -> MuxMessage<'static> {
+    client::NewSession {
+        request_id: 1,
+        want_tty: false,
+        want_x11_forwarding: false,
+        want_agent: false,
+        subsystem: false,
+        escape_char: b'~' as u32,
+        terminal_type: Cow::Borrowed("xterm"),
+        command: Cow::Borrowed(command),
+        environment: Vec::new(),
+    }
+    .into()
+}
+
+fn forward(
+    forwarding_type: ForwardingType,
+    connect_host: &'static str,
+    connect_port: Port,
+) -> MuxMessage<'static> {
+    client::OpenFwd {
+        request_id: 1,
+        forwarding_type,
+        listen_host: Cow::Borrowed(""),
+        listen_port: Port::Inet(8080),
+        connect_host: Cow::Borrowed(connect_host),
+        connect_port,
+    }
+    .into()
+}
+
+fn is_denied(verdict: Verdict) -> bool {
+    matches!(verdict, Verdict::Deny(_))
+}
+
+#[test]
+fn sessions() {
+    let policy: Policy = POLICY.parse().unwrap();
+    assert_eq!(policy.check(&session("uptime")), Verdict::Forward);
+    assert_eq!(policy.check(&session("ls -l /tmp")), Verdict::Forward);
+    assert!(is_denied(policy.check(&session("ls ../secret"))));
+    assert!(is_denied(policy.check(&session("rm -rf /"))));
+}
+
+#[test]
+fn empty_policy_denies() {
+    let stdio: MuxMessage = client::NewStdioFwd {
+        request_id: 1,
+        connect_host: Cow::Borrowed("localhost"),
+        connect_port: Port::Inet(22),
+    }
+    .into();
+    for policy in [Policy::default(), "".parse().unwrap()] {
+        assert!(is_denied(policy.check(&session("uptime"))));
+        assert!(is_denied(policy.check(&forward(
+            ForwardingType::Local,
+            "localhost",
+            Port::Inet(80)
+        ))));
+        assert!(is_denied(policy.check(&forward(
+            ForwardingType::Dynamic,
+            "",
+            Port::Inet(0)
+        ))));
+        assert!(is_denied(policy.check(&stdio)));
+    }
+
+    // Forwarding types and destinations are both needed
+    let policy: Policy = "[forward]\ndestinations = ['^localhost:80$']"
+        .parse()
+        .unwrap();
+    assert!(is_denied(policy.check(&forward(
+        ForwardingType::Local,
+        "localhost",
+        Port::Inet(80)
+    ))));
+    let policy: Policy = "[forward]\ntypes = ['Local']".parse().unwrap();
+    assert!(is_denied(policy.check(&forward(
+        ForwardingType::Local,
+        "localhost",
+        Port::Inet(80)
+    ))));
+}
+
+#[test]
+fn session_forwarding() {
+    let with = |want_agent, want_x11_forwarding| {
+        let mut request = session("uptime");
+        if let MuxMessage::NewSession(ref mut ns) = request {
+            ns.want_agent = want_agent;
+            ns.want_x11_forwarding = want_x11_forwarding;
+        }
+        request
+    };
+    let policy: Policy = POLICY.parse().unwrap();
+    assert_eq!(policy.check(&with(false, false)), Verdict::Forward);
+    assert!(is_denied(policy.check(&with(true, false))));
+    assert!(is_denied(policy.check(&with(false, true))));
+
+    let policy: Policy =
+        "[session]\nallow = ['^uptime$']\nallow_agent = true\nallow_x11_forwarding = true"
+            .parse()
+            .unwrap();
+    assert_eq!(policy.check(&with(true, true)), Verdict::Forward);
+}
+
+#[test]
+fn forwards() {
+    let policy: Policy = POLICY.parse().unwrap();
+    let local = |host, port| policy.check(&forward(ForwardingType::Local, host, port));
+    assert_eq!(local("localhost", Port::Inet(443)), Verdict::Forward);
+    assert_eq!(local("/var/run/docker.sock", Port::Unix), Verdict::Forward);
+    assert!(is_denied(local("localhost", Port::Inet(22))));
+    assert!(is_denied(local("10.0.0.1", Port::Inet(80))));
+    assert!(is_denied(policy.check(&forward(
+        ForwardingType::Remote,
+        "localhost",
+        Port::Inet(80)
+    ))));
+    // A SOCKS proxy would reach destinations outside of the list
+    assert!(is_denied(policy.check(&forward(
+        ForwardingType::Dynamic,
+        "",
+        Port::Inet(0)
+    ))));
+    let socks_only: Policy = "[forward]\ntypes = ['Dynamic', 'Remote']".parse().unwrap();
+    assert_eq!(
+        socks_only.check(&forward(ForwardingType::Dynamic, "", Port::Inet(0))),
+        Verdict::Forward
+    );
+    // Remote forwards without a destination act as a SOCKS proxy on the server
+    assert_eq!(
+        socks_only.check(&forward(ForwardingType::Remote, "", Port::Inet(0))),
+        Verdict::Forward
+    );
+    assert!(is_denied(socks_only.check(&forward(
+        ForwardingType::Remote,
+        "localhost",
+        Port::Inet(80)
+    ))));
+
+    let stdio: MuxMessage = client::NewStdioFwd {
+        request_id: 1,
+        connect_host: Cow::Borrowed("example.com"),
+        connect_port: Port::Inet(22),
+    }
+    .into();
+    assert!(is_denied(policy.check(&stdio)));
+}
+
+#[test]
+fn master_control() {
+    let terminate: MuxMessage = client::Terminate { request_id: 1 }.into();
+    let stop: MuxMessage = client::StopListening { request_id: 1 }.into();
+    let check: MuxMessage = client::AliveCheck { request_id: 1 }.into();
+
+    let policy: Policy = POLICY.parse().unwrap();
+    assert!(is_denied(policy.check(&terminate)));
+    assert!(is_denied(policy.check(&stop)));
+    assert_eq!(policy.check(&check), Verdict::Forward);
+
+    let policy: Policy = "allow_terminate = true\nallow_stop_listening = true"
+        .parse()
+        .unwrap();
+    assert_eq!(policy.check(&terminate), Verdict::Forward);
+    assert_eq!(policy.check(&stop), Verdict::Forward);
+}
+
+#[test]
+fn closing_forwards() {
+    let close: MuxMessage = client::CloseFwd {
+        request_id: 1,
+        forwarding_type: ForwardingType::Local,
+        listen_host: Cow::Borrowed(""),
+        listen_port: Port::Inet(8080),
+        connect_host: Cow::Borrowed("localhost"),
+        connect_port: Port::Inet(80),
+    }
+    .into();
+    let policy: Policy = POLICY.parse().unwrap();
+    assert!(is_denied(policy.check(&close)));
+    let policy: Policy = "allow_close_forward = true".parse().unwrap();
+    assert_eq!(policy.check(&close), Verdict::Forward);
+}
+
+#[test]
+fn session_environment() {
+    let with_env = |environment: &[&'static str]| {
+        let mut request = session("uptime");
+        if let MuxMessage::NewSession(ref mut ns) = request {
+            ns.environment = environment.iter().map(|&v| Cow::Borrowed(v)).collect();
+        }
+        request
+    };
+    let policy: Policy = POLICY.parse().unwrap();
+    assert!(is_denied(policy.check(&with_env(&["LANG=C"]))));
+
+    let policy: Policy = "[session]\nallow = ['^uptime$']\nenv = ['^LANG$', '^LC_[A-Z]+$']"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        policy.check(&with_env(&["LANG=C", "LC_TIME=C"])),
+        Verdict::Forward
+    );
+    assert!(is_denied(
+        policy.check(&with_env(&["LANG=C", "LD_PRELOAD=/tmp/x.so"]))
+    ));
+}
+
+/// Runs a gateway enforcing `POLICY` for a single connection to a master that expects nothing
+/// but the handshake, returning the client end
+fn spawn_gateway(name: &str) -> (UnixStream, impl FnOnce()) {
+    let master_path = socket_path(&format!("{name}-master"));
+    let proxy_path = socket_path(&format!("{name}-listen"));
+    let master = Replayer::new(hello_records()).spawn(&master_path).unwrap();
+    let listener = UnixListener::bind(&proxy_path).unwrap();
+    let mut proxy = Proxy::new(&master_path);
+    proxy.filter(POLICY.parse::<Policy>().unwrap());
+    let gateway = thread::spawn(move || {
+        let (client, _) = listener.accept().unwrap();
+        proxy.handle(client).unwrap();
+    });
+    let client = UnixStream::connect(&proxy_path).unwrap();
+    let join = move || {
+        gateway.join().unwrap();
+        // The master fails if anything past the handshake reached it
+        master.join().unwrap().unwrap();
+        let _ = std::fs::remove_file(&master_path);
+        let _ = std::fs::remove_file(&proxy_path);
+    };
+    (client, join)
+}
+
+fn denied_reason(response: MuxResponse<'_>, request_id: u32) -> String {
+    match response {
+        MuxResponse::PermissionDenied(server::PermissionDenied {
+            client_request_id,
+            reason,
+        }) if client_request_id == request_id => reason.into_owned(),
+        response => panic!("unexpected {response:?}"),
+    }
+}
+
+#[test]
+fn refuses_undecodable_request() {
+    let (mut client, join) = spawn_gateway("gateway-undecodable");
+    let mut packet = handshake(&mut client);
+
+    // A command the policy would check, were it valid UTF-8 as OpenSSH does not require
+    let mut body = Vec::new();
+    session("rm -rf /").serialize(&mut body).unwrap();
+    let at = body.windows(2).position(|w| w == b"rm").unwrap();
+    body[at] = 0xff;
+    write_body(&mut client, &body);
+    for _ in 0..3 {
+        ssh_control::ControlSocket::send_fd(&mut client, 0).unwrap();
+    }
+    let reason = denied_reason(packet.recv_next(&mut client).unwrap(), 1);
+    assert!(reason.starts_with("Malformed NewSession"), "{reason}");
+
+    drop(client);
+    join();
+}
+
+#[test]
+fn refuses_unknown_message_type() {
+    let (mut client, join) = spawn_gateway("gateway-unknown");
+    let mut packet = handshake(&mut client);
+
+    // MUX_C_PROXY, giving raw channel access
+    let mut body = 0x1000000fu32.to_be_bytes().to_vec();
+    body.extend_from_slice(&5u32.to_be_bytes());
+    write_body(&mut client, &body);
+    assert_eq!(
+        denied_reason(packet.recv_next(&mut client).unwrap(), 5),
+        "Unsupported request type 0x1000000f"
+    );
+
+    drop(client);
+    join();
+}
+
+#[test]
+fn invalid_policies() {
+    for policy in [
+        "[session]\nallow = ['(']",
+        "unknown = true",
+        "[forward]\ntypes = ['Sideways']",
+    ] {
+        assert!(matches!(
+            policy.parse::<Policy>(),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    os::unix::{
+        io::{FromRawFd, OwnedFd},
+        net::UnixStream,
+    },
+    sync::Barrier,
+    thread,
+};
+
+use passfd::FdPassingExt;
+use ssh_control::{
+    client::ForwardingType,
+    command::{Pipe, SessionEvent, SshCommand},
+    forward::Endpoint,
+    handle::SshControlHandle,
+    server, Error, Hello, MuxMessage, MuxResponse, Packet, SshControl, Wire,
+};
+
+mod common;
+use common::{socket_path, spawn_shell_master};
+
+/// Master end of a connection, after the handshake
+fn accept(mut socket: UnixStream) -> (UnixStream, Packet) {
+    let mut packet: Packet = Vec::new().into();
+    let hello = Hello {
+        version: 4,
+        extensions: Vec::new(),
+    };
+    packet.set(&hello).unwrap();
+    packet.serialize(&mut socket).unwrap();
+    let _: Hello = packet.recv_next(&mut socket).unwrap();
+    (socket, packet)
+}
+
+fn send(socket: &mut UnixStream, packet: &mut Packet, response: MuxResponse<'_>) {
+    packet.set(&response).unwrap();
+    packet.serialize(socket).unwrap();
+}
+
+#[test]
+fn handle_is_shareable() {
+    fn shareable<T: Clone + Send + Sync>() {}
+    shareable::<SshControlHandle>();
+}
+
+#[test]
+fn concurrent_requests() {
+    const THREADS: u32 = 4;
+    let (client, server) = UnixStream::pair().unwrap();
+
+    // Master answering once every request is in flight, in reverse order, with the request id
+    // as pid
+    let master = thread::spawn(move || {
+        let (mut socket, mut packet) = accept(server);
+        let mut ids = Vec::new();
+        for _ in 0..THREADS {
+            let request: MuxMessage = packet.recv_next(&mut socket).unwrap();
+            let MuxMessage::AliveCheck(check) = request else {
+                panic!("unexpected {request:?}");
+            };
+            ids.push(check.request_id);
+        }
+        for &id in ids.iter().rev() {
+            let alive = server::Alive {
+                client_request_id: id,
+                server_pid: id,
+            };
+            send(&mut socket, &mut packet, alive.into());
+        }
+        ids
+    });
+
+    let handle = SshControl::with_socket(client)
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    let barrier = Barrier::new(THREADS as usize);
+    let pids: BTreeSet<u32> = thread::scope(|s| {
+        let workers: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let handle = handle.clone();
+                let barrier = &barrier;
+                s.spawn(move || {
+                    barrier.wait();
+                    handle.check_alive().unwrap()
+                })
+            })
+            .collect();
+        workers.into_iter().map(|w| w.join().unwrap()).collect()
+    });
+    let ids: BTreeSet<u32> = master.join().unwrap().into_iter().collect();
+    assert_eq!(pids, ids);
+    assert_eq!(ids, (1..=THREADS).collect());
+
+    // The master is gone
+    assert!(matches!(handle.check_alive(), Err(Error::IO(_))));
+}
+
+#[test]
+fn session_output() {
+    let path = socket_path("handle-session");
+    let master = spawn_shell_master(&path, 1);
+    let handle = SshControlHandle::new(&path).unwrap();
+
+    let mut cmd = SshCommand::new("echo from handle; exit 3");
+    cmd.stdout(Pipe::new().unwrap());
+    let child = thread::scope(|s| s.spawn(|| handle.new_session(cmd).unwrap()).join().unwrap());
+    let output = handle.wait_with_output(child).unwrap();
+    assert_eq!(output.status, 3);
+    assert_eq!(output.stdout, b"from handle\n");
+
+    master.join().unwrap();
+    let _ = fs::remove_file(&path);
+    // Like OpenSSH, the master closed the connection after the session
+    assert!(handle.check_alive().is_err());
+}
+
+#[test]
+fn session_end_closes_every_clone() {
+    let path = socket_path("handle-clones");
+    let master = spawn_shell_master(&path, 1);
+    let handle = SshControlHandle::new(&path).unwrap();
+    let other = handle.clone();
+
+    let mut cmd = SshCommand::new("exit 0");
+    cmd.stdin(Pipe::dev_null().unwrap())
+        .stdout(Pipe::dev_null().unwrap())
+        .stderr(Pipe::dev_null().unwrap());
+    let child = handle.new_session(cmd).unwrap();
+    assert_eq!(handle.wait(&child).unwrap(), 0);
+    master.join().unwrap();
+    let _ = fs::remove_file(&path);
+
+    // The connection is shared, so the other clone cannot open a session of its own
+    assert!(matches!(other.check_alive(), Err(Error::IO(_))));
+    let mut cmd = SshCommand::new("true");
+    cmd.stdin(Pipe::dev_null().unwrap())
+        .stdout(Pipe::dev_null().unwrap())
+        .stderr(Pipe::dev_null().unwrap());
+    assert!(matches!(other.new_session(cmd), Err(Error::IO(_))));
+}
+
+#[test]
+fn session_events_until_connection_lost() {
+    let (client, server) = UnixStream::pair().unwrap();
+    let master = thread::spawn(move || {
+        let (mut socket, mut packet) = accept(server);
+        let request: MuxMessage = packet.recv_next(&mut socket).unwrap();
+        let MuxMessage::NewSession(session) = request.into_owned() else {
+            panic!("unexpected request");
+        };
+        for _ in 0..3 {
+            drop(unsafe { OwnedFd::from_raw_fd(socket.recv_fd().unwrap()) });
+        }
+        let opened = server::SessionOpened {
+            client_request_id: session.request_id,
+            session_id: 7,
+        };
+        send(&mut socket, &mut packet, opened.into());
+        send(
+            &mut socket,
+            &mut packet,
+            server::TtyAllocFail { session_id: 7 }.into(),
+        );
+    });
+
+    let handle = SshControl::with_socket(client)
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    let mut cmd = SshCommand::new("top");
+    cmd.want_tty(true)
+        .stdin(Pipe::dev_null().unwrap())
+        .stdout(Pipe::dev_null().unwrap())
+        .stderr(Pipe::dev_null().unwrap());
+    let child = handle.new_session(cmd).unwrap();
+    assert_eq!(child.session_id(), 7);
+    master.join().unwrap();
+
+    let events: Vec<_> = handle.events(&child).unwrap().collect();
+    assert!(matches!(
+        events[..],
+        [
+            SessionEvent::Opened { session_id: 7 },
+            SessionEvent::TtyAllocFailed,
+            SessionEvent::ConnectionLost(Error::IO(_))
+        ]
+    ));
+    // Events are only handed out once
+    assert!(handle.events(&child).is_err());
+    assert!(handle.check_alive().is_err());
+}
+
+#[test]
+fn failed_close_is_retried() {
+    let (client, server) = UnixStream::pair().unwrap();
+    // Master opening a forward, then gone
+    let master = thread::spawn(move || {
+        let (mut socket, mut packet) = accept(server);
+        let request: MuxMessage = packet.recv_next(&mut socket).unwrap();
+        let MuxMessage::OpenFwd(open) = request else {
+            panic!("unexpected {request:?}");
+        };
+        let ok = server::Ok {
+            client_request_id: open.request_id,
+        };
+        send(&mut socket, &mut packet, ok.into());
+    });
+
+    let handle = SshControl::with_socket(client)
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    let forward = handle
+        .open_forward(
+            ForwardingType::Local,
+            ("localhost", 15432),
+            Some(Endpoint::tcp("db", 5432)),
+        )
+        .unwrap();
+    master.join().unwrap();
+
+    assert!(matches!(handle.close_forward(forward), Err(Error::IO(_))));
+    assert!(handle.forwards().is_empty());
+    // The close is still queued, and fails again
+    assert!(matches!(handle.close_dropped_forwards(), Err(Error::IO(_))));
+}
@@ -0,0 +1,91 @@
+use std::io::{self, Cursor, Write};
+
+use ssh_control::{client, Error, MuxMessage, NomError, Packet, Wire};
+
+/// Message whose serialization always fails
+struct Unserializable;
+
+impl<'a> Wire<'a> for Unserializable {
+    fn parse<E>(input: &'a [u8]) -> nom::IResult<&'a [u8], Self, E>
+    where
+        E: NomError<'a>,
+    {
+        Ok((input, Self))
+    }
+
+    fn serialize<W>(&self, _writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "unserializable",
+        ))
+    }
+}
+
+#[test]
+fn oversized_packet() {
+    let mut packet: Packet = Vec::new().into();
+    packet.set_max_size(16);
+    let mut reader = Cursor::new([0u8, 0, 0, 17]);
+    assert!(matches!(
+        packet.recv_next::<MuxMessage, _>(&mut reader),
+        Err(Error::PacketTooLarge { size: 17, max: 16 })
+    ));
+}
+
+#[test]
+fn trailing_data() {
+    let msg: MuxMessage = client::Terminate { request_id: 1 }.into();
+    let mut raw = Vec::new();
+    msg.serialize(&mut raw).unwrap();
+    let mut input = (raw.len() as u32 + 1).to_be_bytes().to_vec();
+    input.extend_from_slice(&raw);
+    input.push(b'x');
+
+    let mut packet: Packet = Vec::new().into();
+    assert!(matches!(
+        packet.recv_next::<MuxMessage, _>(&mut Cursor::new(input)),
+        Err(Error::TrailingData { len: 1 })
+    ));
+}
+
+#[test]
+fn truncated_packet() {
+    let msg: MuxMessage = client::Terminate { request_id: 1 }.into();
+    let mut raw = Vec::new();
+    msg.serialize(&mut raw).unwrap();
+    let mut input = (raw.len() as u32).to_be_bytes().to_vec();
+    input.extend_from_slice(&raw);
+
+    let mut packet: Packet = Vec::new().into();
+    for len in [2, input.len() - 1] {
+        match packet.recv_next::<MuxMessage, _>(&mut Cursor::new(&input[..len])) {
+            Err(Error::IO(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            other => panic!("unexpected {other:?} for {len} bytes"),
+        }
+    }
+
+    // The buffer is still usable for a whole packet
+    assert_eq!(
+        packet
+            .recv_next::<MuxMessage, _>(&mut Cursor::new(&input[..]))
+            .unwrap(),
+        msg
+    );
+}
+
+#[test]
+fn serialization_error() {
+    let mut packet: Packet = Vec::new().into();
+    match packet.set(&Unserializable) {
+        Err(Error::IO(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+        other => panic!("unexpected {other:?}"),
+    }
+
+    // The failed message left nothing behind to be sent
+    let mut sent = Vec::new();
+    packet.serialize(&mut sent).unwrap();
+    assert!(sent.is_empty());
+}
@@ -0,0 +1,218 @@
+use std::{
+    os::unix::net::UnixStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use ssh_control::{
+    client::{self, ForwardingType, Port},
+    profile::{Opened, Profile, Reconciler},
+    record::{Record, Replayer},
+    server, Error, SshControl,
+};
+
+mod common;
+use common::{hello_records, request, response, socket_path, FailingWrite};
+
+fn spec(s: &str) -> client::OpenFwd<'static> {
+    s.parse().unwrap()
+}
+
+fn with_id(mut msg: client::OpenFwd<'static>, request_id: u32) -> client::OpenFwd<'static> {
+    msg.request_id = request_id;
+    msg
+}
+
+fn open(s: &str, request_id: u32) -> Record {
+    request(with_id(spec(s), request_id).into(), 0)
+}
+
+fn close(s: &str, request_id: u32) -> Record {
+    let fwd = spec(s);
+    request(
+        client::CloseFwd {
+            request_id,
+            forwarding_type: fwd.forwarding_type,
+            listen_host: fwd.listen_host,
+            listen_port: fwd.listen_port,
+            connect_host: fwd.connect_host,
+            connect_port: fwd.connect_port,
+        }
+        .into(),
+        0,
+    )
+}
+
+fn ok(client_request_id: u32) -> Record {
+    response(server::Ok { client_request_id }.into())
+}
+
+#[test]
+fn parse_profile() {
+    let profile: Profile = r#"
+        forwards = [
+            "-L 8080:localhost:80",
+            "-L /tmp/docker.sock:/var/run/docker.sock",
+            "-D 1080",
+        ]
+    "#
+    .parse()
+    .unwrap();
+    assert_eq!(
+        profile.forwards,
+        [
+            spec("-L 8080:localhost:80"),
+            spec("-L /tmp/docker.sock:/var/run/docker.sock"),
+            spec("-D 1080"),
+        ]
+    );
+    assert_eq!(profile.forwards[1].listen_port, Port::Unix);
+
+    for invalid in [r#"forwards = ["-L 8080"]"#, r#"forward = []"#] {
+        assert!(
+            matches!(invalid.parse::<Profile>(), Err(Error::InvalidConfig(_))),
+            "{invalid}"
+        );
+    }
+}
+
+#[test]
+fn reconcile_forwards() {
+    let mut expected = hello_records();
+    expected.push(open("-L 8080:localhost:80", 1));
+    expected.push(ok(1));
+    expected.push(open("-R 0:localhost:22", 2));
+    expected.push(response(
+        server::RemotePort {
+            client_request_id: 2,
+            allocated_remote_listen_port: 40022,
+        }
+        .into(),
+    ));
+    expected.push(close("-L 8080:localhost:80", 3));
+    expected.push(ok(3));
+    expected.push(open("-D 1080", 4));
+    expected.push(ok(4));
+
+    let path = socket_path("profile");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let mut reconciler = Reconciler::new();
+
+    let first: Profile = r#"forwards = ["-L 8080:localhost:80", "-R 0:localhost:22"]"#
+        .parse()
+        .unwrap();
+    let report = reconciler.apply(&mut ctrl, &first).unwrap();
+    assert_eq!(
+        report.opened,
+        [
+            Opened {
+                forward: spec("-L 8080:localhost:80"),
+                allocated_port: None,
+            },
+            Opened {
+                forward: spec("-R 0:localhost:22"),
+                allocated_port: Some(40022),
+            },
+        ]
+    );
+    assert!(report.closed.is_empty());
+    assert_eq!(report.kept, 0);
+
+    let report = reconciler.apply(&mut ctrl, &first).unwrap();
+    assert!(report.opened.is_empty() && report.closed.is_empty());
+    assert_eq!(report.kept, 2);
+
+    let second: Profile = r#"forwards = ["-R 0:localhost:22", "-D 1080"]"#.parse().unwrap();
+    let report = reconciler.apply(&mut ctrl, &second).unwrap();
+    assert_eq!(report.closed, [spec("-L 8080:localhost:80")]);
+    assert_eq!(report.opened.len(), 1);
+    assert_eq!(report.kept, 1);
+    assert_eq!(reconciler.forwards().len(), 2);
+    assert_eq!(reconciler.forwards()[0].allocated_port(), Some(40022));
+    assert_eq!(
+        reconciler.forwards()[1].forwarding_type(),
+        ForwardingType::Dynamic
+    );
+
+    drop(ctrl);
+    drop(reconciler);
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn close_forwards() {
+    let mut expected = hello_records();
+    expected.push(open("-L 8080:localhost:80", 1));
+    expected.push(ok(1));
+    expected.push(open("-D 1080", 2));
+    expected.push(ok(2));
+    expected.push(close("-L 8080:localhost:80", 3));
+    expected.push(ok(3));
+    expected.push(close("-D 1080", 4));
+    expected.push(ok(4));
+    expected.push(open("-D 1080", 5));
+    expected.push(ok(5));
+    // Closed when the connection is dropped, after the reconciler
+    expected.push(close("-D 1080", 6));
+    expected.push(ok(6));
+
+    let path = socket_path("profile-close");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let mut reconciler = Reconciler::new();
+
+    let profile: Profile = r#"forwards = ["-L 8080:localhost:80", "-D 1080"]"#.parse().unwrap();
+    reconciler.apply(&mut ctrl, &profile).unwrap();
+    let closed = reconciler.close(&mut ctrl).unwrap();
+    assert_eq!(closed, profile.forwards);
+    assert!(reconciler.forwards().is_empty());
+
+    let profile: Profile = r#"forwards = ["-D 1080"]"#.parse().unwrap();
+    let report = reconciler.apply(&mut ctrl, &profile).unwrap();
+    assert_eq!(report.opened.len(), 1);
+
+    drop(reconciler);
+    drop(ctrl);
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn unsent_close_is_queued() {
+    let mut expected = hello_records();
+    expected.push(open("-L 8080:localhost:80", 1));
+    expected.push(ok(1));
+    expected.push(open("-D 1080", 2));
+    expected.push(ok(2));
+    // Request 3 could not be sent
+    expected.push(close("-L 8080:localhost:80", 4));
+    expected.push(ok(4));
+    expected.push(close("-D 1080", 5));
+    expected.push(ok(5));
+
+    let path = socket_path("profile-unsent-close");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+    let fail = Arc::new(AtomicBool::new(false));
+    let socket = FailingWrite {
+        socket: UnixStream::connect(&path).unwrap(),
+        fail: Arc::clone(&fail),
+    };
+    let mut ctrl = SshControl::with_socket(socket).unwrap();
+    let mut reconciler = Reconciler::new();
+
+    let profile: Profile = r#"forwards = ["-L 8080:localhost:80", "-D 1080"]"#.parse().unwrap();
+    reconciler.apply(&mut ctrl, &profile).unwrap();
+    fail.store(true, Ordering::Relaxed);
+    assert!(reconciler.close(&mut ctrl).is_err());
+    // The forward whose close failed is closed with the dropped ones, the other one is kept
+    assert_eq!(reconciler.forwards().len(), 1);
+    assert_eq!(reconciler.close(&mut ctrl).unwrap(), [spec("-D 1080")]);
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
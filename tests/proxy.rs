@@ -0,0 +1,222 @@
+use std::{
+    env,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use ssh_control::{
+    client,
+    command::{Pipe, SshCommand},
+    proxy::{Proxy, Verdict},
+    record::Replayer,
+    server, ControlSocket, Error, MuxMessage, MuxResponse, SshControl, Wire,
+};
+
+mod common;
+use common::{handshake, hello_records, request, response, socket_path, write_body};
+
+/// Proxies a single connection from `listen` to `master`, returning the requests it saw
+fn spawn_proxy(
+    listen: &Path,
+    master: &Path,
+    deny_terminate: bool,
+) -> (thread::JoinHandle<()>, Arc<Mutex<Vec<String>>>) {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let listener = UnixListener::bind(listen).unwrap();
+    let mut proxy = Proxy::new(master);
+    {
+        let seen = Arc::clone(&seen);
+        proxy.filter(move |_: Option<&_>, request: &MuxMessage<'_>| {
+            seen.lock().unwrap().push(format!("{request:?}"));
+            match request {
+                MuxMessage::Terminate(_) if deny_terminate => {
+                    Verdict::Deny("terminate is forbidden".into())
+                }
+                _ => Verdict::Forward,
+            }
+        });
+    }
+    let handle = thread::spawn(move || {
+        let (client, _) = listener.accept().unwrap();
+        proxy.handle(client).unwrap();
+    });
+    (handle, seen)
+}
+
+#[test]
+fn forwards_requests() {
+    let mut records = hello_records();
+    records.push(request(client::AliveCheck { request_id: 1 }.into(), 0));
+    records.push(response(
+        server::Alive {
+            client_request_id: 1,
+            server_pid: 99,
+        }
+        .into(),
+    ));
+
+    let master_path = socket_path("proxy-master");
+    let proxy_path = socket_path("proxy-listen");
+    let master = Replayer::new(records).spawn(&master_path).unwrap();
+    let (proxy, seen) = spawn_proxy(&proxy_path, &master_path, false);
+
+    let mut ctrl = SshControl::new(&proxy_path).unwrap();
+    assert_eq!(ctrl.check_alive().unwrap(), 99);
+    drop(ctrl);
+
+    proxy.join().unwrap();
+    master.join().unwrap().unwrap();
+    assert_eq!(seen.lock().unwrap().len(), 1);
+    let _ = std::fs::remove_file(&master_path);
+    let _ = std::fs::remove_file(&proxy_path);
+}
+
+#[test]
+fn relays_file_descriptors() {
+    let mut records = hello_records();
+    records.push(request(
+        client::NewSession {
+            request_id: 1,
+            want_tty: false,
+            want_x11_forwarding: false,
+            want_agent: false,
+            subsystem: false,
+            escape_char: b'~' as u32,
+            terminal_type: env::var("TERM").unwrap_or_else(|_| "xterm".into()).into(),
+            command: "true".into(),
+            environment: Vec::new(),
+        }
+        .into(),
+        3,
+    ));
+    records.push(response(
+        server::SessionOpened {
+            client_request_id: 1,
+            session_id: 2,
+        }
+        .into(),
+    ));
+
+    let master_path = socket_path("proxy-fds-master");
+    let proxy_path = socket_path("proxy-fds-listen");
+    let master = Replayer::new(records).spawn(&master_path).unwrap();
+    let (proxy, _) = spawn_proxy(&proxy_path, &master_path, false);
+
+    let mut ctrl = SshControl::new(&proxy_path).unwrap();
+    let mut cmd = SshCommand::new("true");
+    cmd.stdin(Pipe::dev_null().unwrap())
+        .stdout(Pipe::dev_null().unwrap())
+        .stderr(Pipe::dev_null().unwrap());
+    ctrl.new_session(cmd).unwrap();
+    drop(ctrl);
+
+    proxy.join().unwrap();
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&master_path);
+    let _ = std::fs::remove_file(&proxy_path);
+}
+
+#[test]
+fn denies_filtered_requests() {
+    let master_path = socket_path("proxy-deny-master");
+    let proxy_path = socket_path("proxy-deny-listen");
+    let master = Replayer::new(hello_records()).spawn(&master_path).unwrap();
+    let (proxy, seen) = spawn_proxy(&proxy_path, &master_path, true);
+
+    let mut ctrl = SshControl::new(&proxy_path).unwrap();
+    match ctrl.terminate() {
+        Err(Error::PermissionDenied(reason)) => assert_eq!(reason, "terminate is forbidden"),
+        r => panic!("Unexpected result {r:?}"),
+    }
+    drop(ctrl);
+
+    proxy.join().unwrap();
+    master.join().unwrap().unwrap();
+    assert_eq!(seen.lock().unwrap().len(), 1);
+    let _ = std::fs::remove_file(&master_path);
+    let _ = std::fs::remove_file(&proxy_path);
+}
+
+#[test]
+fn denies_undecodable_requests() {
+    let mut records = hello_records();
+    records.push(request(client::AliveCheck { request_id: 3 }.into(), 0));
+    records.push(response(
+        server::Alive {
+            client_request_id: 3,
+            server_pid: 7,
+        }
+        .into(),
+    ));
+
+    let master_path = socket_path("proxy-undecodable-master");
+    let proxy_path = socket_path("proxy-undecodable-listen");
+    let master = Replayer::new(records).spawn(&master_path).unwrap();
+    let (proxy, seen) = spawn_proxy(&proxy_path, &master_path, false);
+
+    let mut socket = UnixStream::connect(&proxy_path).unwrap();
+    let mut packet = handshake(&mut socket);
+
+    let mut denied = |socket: &mut UnixStream, request_id| match packet
+        .recv_next::<MuxResponse, _>(socket)
+        .unwrap()
+    {
+        MuxResponse::PermissionDenied(denied) => {
+            assert_eq!(denied.client_request_id, request_id);
+            denied.reason.into_owned()
+        }
+        response => panic!("unexpected {response:?}"),
+    };
+
+    // Session whose command is not UTF-8, with its descriptors
+    let session: MuxMessage = client::NewSession {
+        request_id: 1,
+        want_tty: false,
+        want_x11_forwarding: false,
+        want_agent: false,
+        subsystem: false,
+        escape_char: b'~' as u32,
+        terminal_type: "xterm".into(),
+        command: "CMD!".into(),
+        environment: Vec::new(),
+    }
+    .into();
+    let mut body = Vec::new();
+    session.serialize(&mut body).unwrap();
+    let at = body.windows(4).position(|w| w == b"CMD!").unwrap();
+    body[at..at + 4].copy_from_slice(&[0xff, 0xfe, 0xfd, 0xfc]);
+    write_body(&mut socket, &body);
+    for _ in 0..3 {
+        socket.send_fd(0).unwrap();
+    }
+    assert!(denied(&mut socket, 1).starts_with("Malformed NewSession"));
+
+    // MUX_C_PROXY, which this crate does not know
+    let mut body = 0x1000000fu32.to_be_bytes().to_vec();
+    body.extend_from_slice(&2u32.to_be_bytes());
+    write_body(&mut socket, &body);
+    assert_eq!(
+        denied(&mut socket, 2),
+        "Unsupported request type 0x1000000f"
+    );
+
+    // The stream is still in sync
+    let check: MuxMessage = client::AliveCheck { request_id: 3 }.into();
+    packet.set(&check).unwrap();
+    packet.serialize(&mut socket).unwrap();
+    let alive: MuxResponse = packet.recv_next(&mut socket).unwrap();
+    assert!(matches!(
+        alive,
+        MuxResponse::Alive(server::Alive { server_pid: 7, .. })
+    ));
+    drop(socket);
+
+    proxy.join().unwrap();
+    master.join().unwrap().unwrap();
+    // Only the decoded request reached the filter
+    assert_eq!(seen.lock().unwrap().len(), 1);
+    let _ = std::fs::remove_file(&master_path);
+    let _ = std::fs::remove_file(&proxy_path);
+}
@@ -0,0 +1,157 @@
+use std::{
+    env,
+    io::{self, Cursor, Read},
+    os::unix::net::UnixStream,
+};
+
+use ssh_control::{
+    client,
+    command::{Pipe, SshCommand},
+    record::{read_records, Record, Recorder, Replayer},
+    server, Error, SshControl,
+};
+
+mod common;
+use common::{hello_records, request, response, socket_path};
+
+fn assert_same(recorded: &[Record], expected: &[Record]) {
+    assert_eq!(recorded.len(), expected.len());
+    for (r, e) in recorded.iter().zip(expected) {
+        assert_eq!(r.direction, e.direction);
+        assert_eq!(r.packet, e.packet);
+        assert_eq!(r.message, e.message);
+        assert_eq!(r.fds, e.fds);
+    }
+}
+
+#[test]
+fn replay_alive_check() {
+    let mut expected = hello_records();
+    expected.push(request(client::AliveCheck { request_id: 1 }.into(), 0));
+    expected.push(response(
+        server::Alive {
+            client_request_id: 1,
+            server_pid: 1234,
+        }
+        .into(),
+    ));
+
+    let path = socket_path("alive");
+    let master = Replayer::new(expected.clone()).spawn(&path).unwrap();
+
+    let mut output = Vec::new();
+    let socket = Recorder::new(UnixStream::connect(&path).unwrap(), &mut output);
+    let mut ctrl = SshControl::with_socket(socket).unwrap();
+    assert_eq!(ctrl.check_alive().unwrap(), 1234);
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_same(&read_records(&output[..]).unwrap(), &expected);
+}
+
+#[test]
+fn replay_session_with_fds() {
+    let mut expected = hello_records();
+    expected.push(request(
+        client::NewSession {
+            request_id: 1,
+            want_tty: false,
+            want_x11_forwarding: false,
+            want_agent: false,
+            subsystem: false,
+            escape_char: b'~' as u32,
+            terminal_type: env::var("TERM").unwrap_or_else(|_| "xterm".into()).into(),
+            command: "true".into(),
+            environment: Vec::new(),
+        }
+        .into(),
+        3,
+    ));
+    expected.push(response(
+        server::SessionOpened {
+            client_request_id: 1,
+            session_id: 5,
+        }
+        .into(),
+    ));
+    expected.push(response(
+        server::ExitMessage {
+            session_id: 5,
+            exit_value: 0,
+        }
+        .into(),
+    ));
+
+    let path = socket_path("session");
+    let master = Replayer::new(expected.clone()).spawn(&path).unwrap();
+
+    let mut output = Vec::new();
+    let socket = Recorder::new(UnixStream::connect(&path).unwrap(), &mut output);
+    let mut ctrl = SshControl::with_socket(socket).unwrap();
+    let mut cmd = SshCommand::new("true");
+    cmd.stdin(Pipe::dev_null().unwrap())
+        .stdout(Pipe::dev_null().unwrap())
+        .stderr(Pipe::dev_null().unwrap());
+    let child = ctrl.new_session(cmd).unwrap();
+    assert!(ctrl.wait(&child).unwrap());
+    drop(ctrl);
+
+    master.join().unwrap().unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_same(&read_records(&output[..]).unwrap(), &expected);
+}
+
+#[test]
+fn replay_detects_divergence() {
+    let mut expected = hello_records();
+    expected.push(request(client::Terminate { request_id: 1 }.into(), 0));
+
+    let path = socket_path("divergence");
+    let master = Replayer::new(expected).spawn(&path).unwrap();
+
+    let mut ctrl = SshControl::new(&path).unwrap();
+    let _ = ctrl.check_alive();
+    drop(ctrl);
+
+    assert!(master.join().unwrap().is_err());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn oversized_packet_is_not_buffered() {
+    let too_large = |recorder: &mut Recorder<Cursor<Vec<u8>>, Vec<u8>>, max| {
+        let mut buf = [0u8; 64];
+        let e = recorder.read(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        match e.into_inner().unwrap().downcast::<Error>().map(|e| *e) {
+            Ok(Error::PacketTooLarge { max: m, .. }) => assert_eq!(m, max),
+            other => panic!("unexpected {other:?}"),
+        }
+    };
+
+    // A length close to 4 GiB is refused under the default limit
+    let mut recorder = Recorder::new(Cursor::new(vec![0xff; 8]), Vec::new());
+    too_large(&mut recorder, ssh_control::DEFAULT_MAX_PACKET_SIZE);
+
+    let mut recorder = Recorder::new(Cursor::new(vec![0, 0, 0, 17]), Vec::new());
+    recorder.set_max_packet_size(16);
+    too_large(&mut recorder, 16);
+}
+
+#[test]
+fn invalid_hex_packet() {
+    let record = &hello_records()[0];
+    let line = serde_json::to_string(record).unwrap();
+    let packet = serde_json::to_value(record).unwrap()["packet"].clone();
+    let packet = packet.as_str().unwrap();
+    assert_same(
+        &read_records(line.as_bytes()).unwrap(),
+        &hello_records()[..1],
+    );
+
+    for invalid in ["0xaé1", "0x+1", "0xg0", "0x0", "00"] {
+        let line = line.replace(packet, invalid);
+        assert!(read_records(line.as_bytes()).is_err(), "{invalid}");
+    }
+}
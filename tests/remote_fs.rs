@@ -0,0 +1,67 @@
+use std::{env, fs, io, os::unix::fs::PermissionsExt, process};
+
+use ssh_control::{remote_fs::RemoteFs, Error};
+
+mod common;
+use common::{socket_path, spawn_shell_master};
+
+fn kind<T: std::fmt::Debug>(result: ssh_control::Result<T>) -> io::ErrorKind {
+    match result {
+        Err(Error::RemoteFs { kind, .. }) => kind,
+        other => panic!("unexpected {other:?}"),
+    }
+}
+
+#[test]
+fn file_operations() {
+    let dir = env::temp_dir().join(format!("ssh-control-{}-remote-fs.d", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    let root = dir.to_str().unwrap();
+
+    let path = socket_path("remote-fs");
+    let master = spawn_shell_master(&path, 15);
+    let remote = RemoteFs::new(&path);
+
+    let nested = format!("{root}/a b/c'd");
+    assert!(!remote.exists(&nested).unwrap());
+    remote.mkdir_p(&nested).unwrap();
+    assert!(remote.exists(&nested).unwrap());
+    assert!(remote.stat(&nested).unwrap().is_dir());
+
+    let file = format!("{nested}/$(touch pwned) config");
+    remote.write_atomic(&file, b"key = 1\n", 0o640).unwrap();
+    assert_eq!(fs::read(&file).unwrap(), b"key = 1\n");
+    remote.write_atomic(&file, b"key = 2\n", 0o600).unwrap();
+    assert_eq!(remote.read_to_string(&file).unwrap(), "key = 2\n");
+    assert_eq!(fs::read_dir(&nested).unwrap().count(), 1);
+    assert!(!dir.join("pwned").exists());
+
+    let metadata = remote.stat(&file).unwrap();
+    assert!(metadata.is_file());
+    assert_eq!(metadata.size, 8);
+    assert_eq!(metadata.permissions(), 0o600);
+    remote.chmod(&file, 0o755).unwrap();
+    assert_eq!(
+        fs::metadata(&file).unwrap().permissions().mode() & 0o7777,
+        0o755
+    );
+
+    let missing = format!("{root}/missing");
+    assert_eq!(kind(remote.read(&missing)), io::ErrorKind::NotFound);
+    assert_eq!(kind(remote.stat(&missing)), io::ErrorKind::NotFound);
+    assert_eq!(kind(remote.chmod(&missing, 0o644)), io::ErrorKind::NotFound);
+    assert_eq!(kind(remote.read(&nested)), io::ErrorKind::IsADirectory);
+    assert_eq!(
+        kind(remote.write_atomic(&format!("{missing}/file"), b"", 0o644)),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(
+        kind(remote.mkdir_p(&format!("{file}/sub"))),
+        io::ErrorKind::NotADirectory
+    );
+
+    master.join().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_file(&path);
+}
@@ -0,0 +1,63 @@
+use std::io::Cursor;
+
+use nom::error::VerboseError;
+use proptest::prelude::*;
+use ssh_control::{Hello, MuxMessage, MuxResponse, Packet, Wire};
+
+macro_rules! roundtrip {
+    ($type:ident, $value:expr) => {{
+        let value = $value;
+        let mut raw = Vec::new();
+        value.serialize(&mut raw).unwrap();
+        let (rest, parsed) = $type::parse::<VerboseError<&[u8]>>(&raw[..]).unwrap();
+        prop_assert!(rest.is_empty(), "{} trailing bytes", rest.len());
+        prop_assert_eq!(&parsed, value);
+
+        let mut packet: Packet = Vec::new().into();
+        packet.set(value).unwrap();
+        raw.clear();
+        packet.serialize(&mut raw).unwrap();
+        let parsed: $type = packet.recv_next(&mut Cursor::new(raw)).unwrap();
+        prop_assert_eq!(&parsed, value);
+    }};
+}
+
+proptest! {
+    #[test]
+    fn mux_message(msg in any::<MuxMessage<'static>>()) {
+        roundtrip!(MuxMessage, &msg);
+    }
+
+    #[test]
+    fn mux_response(msg in any::<MuxResponse<'static>>()) {
+        roundtrip!(MuxResponse, &msg);
+    }
+
+    #[test]
+    fn hello(msg in any::<Hello<'static>>()) {
+        roundtrip!(Hello, &msg);
+    }
+}
+
+/// `ssh -S ctl host 'echo hi'` as sent by OpenSSH: the environment list is not terminated.
+#[test]
+fn openssh_new_session() {
+    const RAW: &[u8] = b"\x10\x00\x00\x02\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\
+        \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x7e\
+        \x00\x00\x00\x05xterm\x00\x00\x00\x07echo hi";
+
+    let (rest, msg) = MuxMessage::parse::<VerboseError<&[u8]>>(RAW).unwrap();
+    assert!(rest.is_empty());
+    let MuxMessage::NewSession(ref session) = msg else {
+        panic!("Unexpected message {msg:?}");
+    };
+    assert_eq!(session.request_id, 1);
+    assert_eq!(session.escape_char, u32::from(b'~'));
+    assert_eq!(session.terminal_type, "xterm");
+    assert_eq!(session.command, "echo hi");
+    assert!(session.environment.is_empty());
+
+    let mut raw = Vec::new();
+    msg.serialize(&mut raw).unwrap();
+    assert_eq!(&raw[..], RAW);
+}
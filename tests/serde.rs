@@ -0,0 +1,43 @@
+use std::borrow::Cow;
+
+use proptest::prelude::*;
+use ssh_control::{client, Hello, MuxMessage, MuxResponse};
+
+proptest! {
+    #[test]
+    fn mux_message(msg in any::<MuxMessage<'static>>()) {
+        let json = serde_json::to_string(&msg).unwrap();
+        let parsed: MuxMessage = serde_json::from_str(&json).unwrap();
+        prop_assert_eq!(parsed, msg);
+    }
+
+    #[test]
+    fn mux_response(msg in any::<MuxResponse<'static>>()) {
+        let json = serde_json::to_string(&msg).unwrap();
+        let parsed: MuxResponse = serde_json::from_str(&json).unwrap();
+        prop_assert_eq!(parsed, msg);
+    }
+
+    #[test]
+    fn hello(msg in any::<Hello<'static>>()) {
+        let json = serde_json::to_string(&msg).unwrap();
+        let parsed: Hello = serde_json::from_str(&json).unwrap();
+        prop_assert_eq!(parsed, msg);
+    }
+}
+
+#[test]
+fn borrows_strings() {
+    let json =
+        r#"{"NewStdioFwd":{"request_id":1,"connect_host":"localhost","connect_port":{"Inet":22}}}"#;
+    let msg: MuxMessage = serde_json::from_str(json).unwrap();
+    let MuxMessage::NewStdioFwd(client::NewStdioFwd {
+        connect_host: Cow::Borrowed(host),
+        connect_port: client::Port::Inet(22),
+        ..
+    }) = msg
+    else {
+        panic!("Unexpected message {msg:?}");
+    };
+    assert_eq!(host, "localhost");
+}
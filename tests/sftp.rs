@@ -0,0 +1,225 @@
+use std::{
+    env, fs,
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    process,
+    thread::{self, JoinHandle},
+};
+
+use ssh_control::{
+    sftp::{Attributes, OpenFlags, Sftp, StatusCode},
+    Error, SshControl,
+};
+
+mod common;
+use common::{sftp_server, socket_path, spawn_shell_master};
+
+fn packet(body: &[u8]) -> Vec<u8> {
+    let mut packet = (body.len() as u32).to_be_bytes().to_vec();
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn string(value: &[u8]) -> Vec<u8> {
+    packet(value)
+}
+
+/// Server answering the version handshake, then every request with `answer`, which gets the
+/// request type, id and remaining fields
+fn fake_server(
+    mut answer: impl FnMut(u8, &[u8], &[u8]) -> Vec<u8> + Send + 'static,
+) -> (UnixStream, JoinHandle<Vec<u8>>) {
+    let (client, mut server) = UnixStream::pair().unwrap();
+    let handle = thread::spawn(move || {
+        let mut requests = Vec::new();
+        loop {
+            let mut size = [0u8; 4];
+            if server.read_exact(&mut size).is_err() {
+                return requests;
+            }
+            let mut body = vec![0u8; u32::from_be_bytes(size) as usize];
+            server.read_exact(&mut body).unwrap();
+            requests.push(body[0]);
+            let reply = if body[0] == 1 {
+                assert_eq!(body[1..], 3u32.to_be_bytes());
+                let mut reply = vec![2, 0, 0, 0, 3];
+                reply.extend(string(b"limits@openssh.com"));
+                reply.extend(string(b"1"));
+                reply
+            } else {
+                answer(body[0], &body[1..5], &body[5..])
+            };
+            server.write_all(&packet(&reply)).unwrap();
+        }
+    });
+    (client, handle)
+}
+
+fn status(id: &[u8], code: u32, message: &str) -> Vec<u8> {
+    let mut reply = vec![101];
+    reply.extend_from_slice(id);
+    reply.extend_from_slice(&code.to_be_bytes());
+    reply.extend(string(message.as_bytes()));
+    reply.extend(string(b""));
+    reply
+}
+
+#[test]
+fn handshake_and_read_file() {
+    let (stream, server) = fake_server(|r#type, id, fields| match r#type {
+        // OPEN of "/etc/motd" for reading, without attributes
+        3 => {
+            let mut expected = string(b"/etc/motd");
+            expected.extend_from_slice(&1u32.to_be_bytes());
+            expected.extend_from_slice(&0u32.to_be_bytes());
+            assert_eq!(fields, expected);
+            let mut reply = vec![102];
+            reply.extend_from_slice(id);
+            reply.extend(string(b"h1"));
+            reply
+        }
+        // READ, answered with data at offset 0 and end of file afterwards
+        5 => {
+            assert_eq!(&fields[..6], &string(b"h1")[..]);
+            if fields[6..14] == [0; 8] {
+                let mut reply = vec![103];
+                reply.extend_from_slice(id);
+                reply.extend(string(b"hello"));
+                reply
+            } else {
+                assert_eq!(fields[6..14], 5u64.to_be_bytes());
+                status(id, 1, "EOF")
+            }
+        }
+        4 => status(id, 0, "Success"),
+        _ => panic!("unexpected request type {type}"),
+    });
+
+    let mut sftp = Sftp::new(stream.try_clone().unwrap(), stream).unwrap();
+    assert_eq!(sftp.version(), 3);
+    assert_eq!(
+        sftp.extensions(),
+        &[("limits@openssh.com".to_owned(), b"1".to_vec())]
+    );
+    assert_eq!(sftp.read_file("/etc/motd").unwrap(), b"hello");
+    drop(sftp);
+    assert_eq!(server.join().unwrap(), [1, 3, 5, 5, 4]);
+}
+
+#[test]
+fn status_errors() {
+    let (stream, server) = fake_server(|r#type, id, fields| match r#type {
+        17 => {
+            assert_eq!(fields, string(b"/missing"));
+            status(id, 2, "No such file")
+        }
+        // SYMLINK takes the target first, as OpenSSH does
+        20 => {
+            let mut expected = string(b"target");
+            expected.extend(string(b"link"));
+            assert_eq!(fields, expected);
+            status(id, 4, "Failure")
+        }
+        _ => panic!("unexpected request type {type}"),
+    });
+
+    let mut sftp = Sftp::new(stream.try_clone().unwrap(), stream).unwrap();
+    match sftp.stat("/missing") {
+        Err(Error::Sftp { code, message }) => {
+            assert_eq!(code, StatusCode::NoSuchFile);
+            assert_eq!(message, "No such file");
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+    assert!(matches!(
+        sftp.symlink("target", "link"),
+        Err(Error::Sftp {
+            code: StatusCode::Failure,
+            ..
+        })
+    ));
+    drop(sftp);
+    assert_eq!(server.join().unwrap(), [1, 17, 20]);
+}
+
+#[test]
+fn mismatched_response_id() {
+    let (stream, _server) = fake_server(|_, _, _| status(&[0, 0, 0, 42], 0, ""));
+    let mut sftp = Sftp::new(stream.try_clone().unwrap(), stream).unwrap();
+    assert!(matches!(
+        sftp.remove("file"),
+        Err(Error::InvalidResponseID { .. })
+    ));
+}
+
+#[test]
+fn sftp_server_session() {
+    if sftp_server().is_none() {
+        eprintln!("sftp-server not found, skipping");
+        return;
+    }
+    let dir = env::temp_dir().join(format!("ssh-control-{}-sftp.d", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    let root = dir.to_str().unwrap();
+
+    let path = socket_path("sftp");
+    let master = spawn_shell_master(&path, 1);
+    let mut sftp = SshControl::new(&path).unwrap().sftp().unwrap();
+
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+    let file = format!("{root}/file");
+    sftp.write_file(&file, &data).unwrap();
+    assert_eq!(fs::read(&file).unwrap(), data);
+    assert_eq!(sftp.read_file(&file).unwrap(), data);
+    let attributes = sftp.stat(&file).unwrap();
+    assert!(attributes.is_file());
+    assert_eq!(attributes.size, Some(data.len() as u64));
+
+    let sub = format!("{root}/sub");
+    let mode = Attributes {
+        permissions: Some(0o700),
+        ..Attributes::default()
+    };
+    sftp.mkdir(&sub, &mode).unwrap();
+    assert!(sftp.stat(&sub).unwrap().is_dir());
+    sftp.rename(&file, &format!("{sub}/moved")).unwrap();
+    sftp.symlink("moved", &format!("{sub}/link")).unwrap();
+    assert_eq!(sftp.readlink(&format!("{sub}/link")).unwrap(), "moved");
+    assert!(sftp.lstat(&format!("{sub}/link")).unwrap().is_symlink());
+
+    let mut names: Vec<_> = sftp
+        .read_dir(&sub)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.filename)
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+    names.sort();
+    assert_eq!(names, ["link", "moved"]);
+
+    let handle = sftp
+        .open(
+            &format!("{sub}/moved"),
+            OpenFlags::READ,
+            &Attributes::default(),
+        )
+        .unwrap();
+    assert_eq!(sftp.fstat(&handle).unwrap().size, Some(data.len() as u64));
+    sftp.close(handle).unwrap();
+
+    sftp.remove(&format!("{sub}/link")).unwrap();
+    sftp.remove(&format!("{sub}/moved")).unwrap();
+    sftp.rmdir(&sub).unwrap();
+    assert!(matches!(
+        sftp.stat(&sub),
+        Err(Error::Sftp {
+            code: StatusCode::NoSuchFile,
+            ..
+        })
+    ));
+
+    drop(sftp);
+    master.join().unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,153 @@
+use std::{env, fs, io::Write, os::unix::fs::PermissionsExt, process};
+
+use ssh_control::{Error, SshControl};
+
+mod common;
+use common::{socket_path, spawn_shell_master};
+
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = env::temp_dir().join(format!("ssh-control-{}-{name}.d", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn upload_and_download() {
+    let dir = temp_dir("transfer");
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let local = dir.join("local file");
+    fs::write(&local, &data).unwrap();
+    fs::set_permissions(&local, fs::Permissions::from_mode(0o750)).unwrap();
+
+    let path = socket_path("transfer");
+    let master = spawn_shell_master(&path, 2);
+
+    let remote = dir.join("it's remote");
+    let mut reports = Vec::new();
+    let sent = SshControl::new(&path)
+        .unwrap()
+        .upload_with_progress(&local, remote.to_str().unwrap(), |done, total| {
+            reports.push((done, total))
+        })
+        .unwrap();
+    assert_eq!(sent, data.len() as u64);
+    assert_eq!(fs::read(&remote).unwrap(), data);
+    assert_eq!(
+        fs::metadata(&remote).unwrap().permissions().mode() & 0o777,
+        0o750
+    );
+    assert_eq!(reports.last(), Some(&(sent, sent)));
+    assert!(reports.windows(2).all(|w| w[0].0 < w[1].0));
+
+    // Only permission bits are copied
+    fs::set_permissions(&remote, fs::Permissions::from_mode(0o4604)).unwrap();
+    let back = dir.join("back");
+    let received = SshControl::new(&path)
+        .unwrap()
+        .download(remote.to_str().unwrap(), &back)
+        .unwrap();
+    assert_eq!(received, data.len() as u64);
+    assert_eq!(fs::read(&back).unwrap(), data);
+    assert_eq!(
+        fs::metadata(&back).unwrap().permissions().mode() & 0o7777,
+        0o604
+    );
+
+    master.join().unwrap();
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn transfer_errors() {
+    let dir = temp_dir("transfer-errors");
+    let local = dir.join("empty");
+    fs::write(&local, b"").unwrap();
+
+    let path = socket_path("transfer-errors");
+    let master = spawn_shell_master(&path, 3);
+
+    let missing_dir = dir.join("missing/file");
+    let err = SshControl::new(&path)
+        .unwrap()
+        .upload(&local, missing_dir.to_str().unwrap())
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::CommandFailed { status, ref stderr } if status != 0 && !stderr.is_empty()),
+        "{err:?}"
+    );
+
+    // A failed download leaves the destination as it was, without a partial file
+    let out = dir.join("out");
+    fs::write(&out, b"previous").unwrap();
+    let err = SshControl::new(&path)
+        .unwrap()
+        .download(dir.join("missing").to_str().unwrap(), &out)
+        .unwrap_err();
+    assert!(matches!(err, Error::CommandFailed { .. }), "{err:?}");
+    assert_eq!(fs::read(&out).unwrap(), b"previous");
+
+    // Same after the content was received: cksum and cat each read their own process status
+    let err = SshControl::new(&path)
+        .unwrap()
+        .download("/proc/self/stat", &out)
+        .unwrap_err();
+    assert!(matches!(err, Error::VerificationFailed(_)), "{err:?}");
+    assert_eq!(fs::read(&out).unwrap(), b"previous");
+    let mut files: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["empty", "out"]);
+
+    master.join().unwrap();
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn failed_upload_keeps_remote() {
+    let dir = temp_dir("transfer-keep");
+    let local = dir.join("local");
+    let data = vec![7u8; 100_000];
+    fs::write(&local, &data).unwrap();
+    let remote = dir.join("remote");
+    fs::write(&remote, b"previous").unwrap();
+
+    let path = socket_path("transfer-keep");
+    let master = spawn_shell_master(&path, 1);
+
+    // The file grows while it is sent, so the copy does not have the checksum it had before
+    let mut grown = false;
+    let err = SshControl::new(&path)
+        .unwrap()
+        .upload_with_progress(&local, remote.to_str().unwrap(), |_, _| {
+            if !grown {
+                fs::OpenOptions::new()
+                    .append(true)
+                    .open(&local)
+                    .unwrap()
+                    .write_all(b"more")
+                    .unwrap();
+                grown = true;
+            }
+        })
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::CommandFailed { ref stderr, .. } if stderr.contains("checksum mismatch")),
+        "{err:?}"
+    );
+    assert_eq!(fs::read(&remote).unwrap(), b"previous");
+    let mut files: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["local", "remote"]);
+
+    master.join().unwrap();
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,89 @@
+use std::{
+    collections::VecDeque,
+    io, process, thread,
+    time::{Duration, Instant},
+};
+
+use ssh_control::tty::{
+    install_sigwinch_handler, notify_master, take_sigwinch, SizeSource, WindowSize, WindowWatcher,
+};
+
+/// Sizes a terminal goes through, one per query
+struct Resizes(VecDeque<WindowSize>);
+
+impl SizeSource for Resizes {
+    fn window_size(&mut self) -> io::Result<WindowSize> {
+        self.0
+            .pop_front()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+    }
+}
+
+fn size(rows: u16, cols: u16) -> WindowSize {
+    WindowSize {
+        rows,
+        cols,
+        ..WindowSize::default()
+    }
+}
+
+#[test]
+fn notifies_changes_only() {
+    let source = Resizes(
+        [
+            size(24, 80),
+            size(24, 80),
+            size(50, 120),
+            size(50, 120),
+            size(24, 80),
+        ]
+        .into(),
+    );
+    let mut notified = Vec::new();
+    let mut watcher = WindowWatcher::new(source, |size| {
+        notified.push(size);
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(watcher.size(), size(24, 80));
+
+    assert_eq!(watcher.check().unwrap(), None);
+    assert_eq!(watcher.check().unwrap(), Some(size(50, 120)));
+    assert_eq!(watcher.check().unwrap(), None);
+    assert_eq!(watcher.check().unwrap(), Some(size(24, 80)));
+    assert!(watcher.check().is_err());
+    drop(watcher);
+    assert_eq!(notified, [size(50, 120), size(24, 80)]);
+}
+
+#[test]
+fn failed_notification_is_retried() {
+    let source = Resizes([size(24, 80), size(30, 90), size(30, 90)].into());
+    let mut attempts = 0;
+    let mut watcher = WindowWatcher::new(source, |_| {
+        attempts += 1;
+        match attempts {
+            1 => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+            _ => Ok(()),
+        }
+    })
+    .unwrap();
+    assert!(watcher.check().is_err());
+    assert_eq!(watcher.check().unwrap(), Some(size(30, 90)));
+}
+
+#[test]
+fn sigwinch_reaches_master() {
+    install_sigwinch_handler().unwrap();
+    take_sigwinch();
+    // This process stands in for the master
+    let mut notify = notify_master(process::id());
+    notify(size(1, 1)).unwrap();
+    // The signal may be handled by another thread of the test process, after `kill` returned
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !take_sigwinch() {
+        assert!(Instant::now() < deadline, "SIGWINCH not received");
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(!take_sigwinch());
+}